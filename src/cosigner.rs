@@ -0,0 +1,264 @@
+//! # Revault cosigning servers
+//!
+//! The cosigning servers are anti-replay oracles: they only ever sign one Spend transaction for
+//! a given Unvault output, so that the managers can't replace a Spend the watchtowers already
+//! agreed to with another one paying to different destinations.
+
+use crate::{
+    error::CosignerError,
+    transactions::{RevaultTransaction, SpendTransaction},
+};
+
+use miniscript::bitcoin::{secp256k1, util::bip32, OutPoint, SigHashType, Txid};
+
+use std::{collections::HashMap, convert::Infallible, fmt};
+
+/// The storage of the signing decisions of a cosigning server.
+///
+/// Implementations backed by a persistent storage must make sure to never forget about a
+/// recorded decision, or the server would be open to replays.
+pub trait CosignerStore {
+    /// An error from the underlying storage
+    type Error: fmt::Display;
+
+    /// Get the txid of the Spend transaction we signed for this Unvault outpoint, if any.
+    fn signed_spend(&self, unvault_outpoint: &OutPoint) -> Result<Option<Txid>, Self::Error>;
+
+    /// Record that we signed the Spend transaction `spend_txid` for all these Unvault outpoints.
+    fn record_signed_spend(
+        &mut self,
+        unvault_outpoints: &[OutPoint],
+        spend_txid: Txid,
+    ) -> Result<(), Self::Error>;
+}
+
+/// A [CosignerStore] keeping the decisions in memory.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MemoryCosignerStore {
+    signed_spends: HashMap<OutPoint, Txid>,
+}
+
+impl MemoryCosignerStore {
+    /// An empty store
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl CosignerStore for MemoryCosignerStore {
+    type Error = Infallible;
+
+    fn signed_spend(&self, unvault_outpoint: &OutPoint) -> Result<Option<Txid>, Self::Error> {
+        Ok(self.signed_spends.get(unvault_outpoint).copied())
+    }
+
+    fn record_signed_spend(
+        &mut self,
+        unvault_outpoints: &[OutPoint],
+        spend_txid: Txid,
+    ) -> Result<(), Self::Error> {
+        for outpoint in unvault_outpoints {
+            self.signed_spends.insert(*outpoint, spend_txid);
+        }
+        Ok(())
+    }
+}
+
+/// A cosigning server, signing at most one Spend transaction per Unvault output.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cosigner<S: CosignerStore> {
+    xpriv: bip32::ExtendedPrivKey,
+    store: S,
+}
+
+impl<S: CosignerStore> Cosigner<S> {
+    /// Create a cosigning server using this key and storing its decisions in `store`.
+    ///
+    /// The key is used as is if the cosigner's key in the Unvault descriptor is a static one,
+    /// and derived at the index of the Unvault output if it's an xpub.
+    pub fn new(xpriv: bip32::ExtendedPrivKey, store: S) -> Self {
+        Cosigner { xpriv, store }
+    }
+
+    /// Get a reference to the storage of the signing decisions
+    pub fn store(&self) -> &S {
+        &self.store
+    }
+
+    /// Sign all the inputs of this Spend transaction, unless we already signed another Spend
+    /// for one of the Unvault outputs it spends.
+    ///
+    /// The signatures are added to the PSBT, and the decision recorded in the store. Signing
+    /// the same Spend transaction again is fine.
+    pub fn cosign_spend<C: secp256k1::Signing + secp256k1::Verification>(
+        &mut self,
+        spend_tx: &mut SpendTransaction,
+        secp: &secp256k1::Secp256k1<C>,
+    ) -> Result<(), CosignerError> {
+        let spend_txid = spend_tx.txid();
+        let unvault_outpoints: Vec<OutPoint> = spend_tx
+            .tx()
+            .input
+            .iter()
+            .map(|txin| txin.previous_output)
+            .collect();
+
+        for outpoint in unvault_outpoints.iter() {
+            if let Some(txid) = self
+                .store
+                .signed_spend(outpoint)
+                .map_err(|e| CosignerError::Store(e.to_string()))?
+            {
+                if txid != spend_txid {
+                    return Err(CosignerError::AlreadySigned(*outpoint, txid));
+                }
+            }
+        }
+
+        // Only touch the PSBT once we know we can sign all the inputs
+        let mut signed_tx = spend_tx.clone();
+        for input_index in 0..unvault_outpoints.len() {
+            let privkey = self.input_privkey(&signed_tx, input_index, secp)?;
+            let pubkey = secp256k1::PublicKey::from_secret_key(secp, &privkey);
+            let sighash = signed_tx.signature_hash(input_index, SigHashType::All)?;
+            let sighash = secp256k1::Message::from_slice(&sighash).expect("Sighash is 32 bytes");
+            let signature = secp.sign(&sighash, &privkey);
+            signed_tx.add_signature(input_index, pubkey, signature, secp)?;
+        }
+
+        self.store
+            .record_signed_spend(&unvault_outpoints, spend_txid)
+            .map_err(|e| CosignerError::Store(e.to_string()))?;
+        *spend_tx = signed_tx;
+
+        Ok(())
+    }
+
+    // Get the key to sign this input with, from the BIP32 derivations of the PSBT input
+    fn input_privkey<C: secp256k1::Signing>(
+        &self,
+        spend_tx: &SpendTransaction,
+        input_index: usize,
+        secp: &secp256k1::Secp256k1<C>,
+    ) -> Result<secp256k1::SecretKey, CosignerError> {
+        let fingerprint = self.xpriv.fingerprint(secp);
+        let static_pubkey = bip32::ExtendedPubKey::from_private(secp, &self.xpriv).public_key;
+
+        for (pubkey, (key_fingerprint, path)) in
+            spend_tx.psbt().inputs[input_index].bip32_derivation.iter()
+        {
+            if *pubkey == static_pubkey {
+                return Ok(self.xpriv.private_key.key);
+            }
+            if *key_fingerprint != fingerprint {
+                continue;
+            }
+            if let Ok(derived) = self.xpriv.derive_priv(secp, path) {
+                if derived.private_key.public_key(secp) == *pubkey {
+                    return Ok(derived.private_key.key);
+                }
+            }
+        }
+
+        Err(CosignerError::UnknownInput(input_index))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        transactions::{
+            tests_helpers::{get_random_privkey, satisfy_transaction_input, TestDeployment},
+            CpfpableTransaction, FeePolicy,
+        },
+        txins::RevaultTxIn,
+        txouts::SpendTxOut,
+    };
+
+    use miniscript::bitcoin::TxOut;
+
+    #[test]
+    fn cosign_spend() {
+        let secp = secp256k1::Secp256k1::new();
+        let deployment = TestDeployment::new(3, 2, true, 360_000_000, &secp);
+        let fee_policy = FeePolicy::default();
+        let (unvault_tx, _, _, _) = deployment.signed_chain(&fee_policy, &secp);
+        let spend_tx = deployment.spend_tx(&unvault_tx, &fee_policy, &secp);
+        let spend_unvault_txin =
+            unvault_tx.spend_unvault_txin(&deployment.der_unvault_descriptor(&secp));
+
+        // The cosigning servers only ever sign a single Spend per Unvault output
+        let mut cosigned_tx = spend_tx.clone();
+        let cosigned_tx_max_weight = cosigned_tx.max_weight();
+        let mut cosigners: Vec<Cosigner<MemoryCosignerStore>> = deployment
+            .cosigners_priv
+            .iter()
+            .map(|xpriv| Cosigner::new(*xpriv, MemoryCosignerStore::new()))
+            .collect();
+        for cosigner in cosigners.iter_mut() {
+            cosigner.cosign_spend(&mut cosigned_tx, &secp).unwrap();
+            // Signing the same Spend twice is fine
+            cosigner.cosign_spend(&mut cosigned_tx, &secp).unwrap();
+        }
+        assert_eq!(
+            cosigned_tx.psbt().inputs[0].partial_sigs.len(),
+            deployment.cosigners_priv.len()
+        );
+        let cosigned_tx_sighash = cosigned_tx
+            .signature_hash(0, SigHashType::All)
+            .expect("Input exists");
+        satisfy_transaction_input(
+            &secp,
+            &mut cosigned_tx,
+            0,
+            &cosigned_tx_sighash,
+            &deployment.managers_priv,
+            Some(deployment.child_number),
+        )
+        .unwrap();
+        cosigned_tx.finalize(&secp).unwrap();
+        assert!(cosigned_tx.max_weight() <= cosigned_tx_max_weight);
+
+        // But not another one spending the same Unvault output
+        let spend_txo = spend_tx.tx().output[1].clone();
+        let mut replay_tx = SpendTransaction::new(
+            vec![spend_unvault_txin.clone()],
+            vec![SpendTxOut::new(TxOut {
+                value: spend_txo.value - 1,
+                ..spend_txo
+            })],
+            None,
+            &deployment.der_cpfp_descriptor(&secp),
+            0,
+            &fee_policy,
+            true,
+        )
+        .expect("Amounts ok");
+        let unsigned_replay_tx = replay_tx.clone();
+        assert_eq!(
+            cosigners[0].cosign_spend(&mut replay_tx, &secp),
+            Err(CosignerError::AlreadySigned(
+                spend_unvault_txin.outpoint(),
+                spend_tx.txid()
+            ))
+        );
+        assert_eq!(replay_tx, unsigned_replay_tx);
+
+        // Nor one it doesn't have any key for
+        let mut foreign_cosigner = Cosigner::new(
+            get_random_privkey(&mut fastrand::Rng::new()),
+            MemoryCosignerStore::new(),
+        );
+        assert_eq!(
+            foreign_cosigner.cosign_spend(&mut replay_tx, &secp),
+            Err(CosignerError::UnknownInput(0))
+        );
+        assert_eq!(
+            foreign_cosigner
+                .store()
+                .signed_spend(&spend_unvault_txin.outpoint()),
+            Ok(None)
+        );
+    }
+}
@@ -1,13 +1,12 @@
 //! # Errors related to Revault transactions and Scripts management
 
-use crate::transactions::INSANE_FEES;
-
 use bitcoinconsensus::Error as LibConsensusError;
 use miniscript::{
     bitcoin::{
         consensus::encode::Error as EncodeError,
         secp256k1,
         util::psbt::{Input as PsbtInput, Output as PsbtOutput},
+        OutPoint, Txid,
     },
     policy::compiler::CompilerError,
 };
@@ -80,7 +79,8 @@ impl error::Error for TxoutCreationError {}
 pub enum TransactionCreationError {
     /// Would send more than MAX_MONEY
     InsaneAmounts,
-    /// Fees would be higher than [INSANE_FEES] (not checked for revocation transactions)
+    /// Fees would be higher than the insane fees threshold of the fee policy (not checked for
+    /// revocation transactions)
     InsaneFees,
     /// Would spend or create a dust output
     Dust,
@@ -92,8 +92,28 @@ pub enum TransactionCreationError {
     DuplicatedInput,
     /// Attempting to CPFP a transaction with no CPFP output
     MissingCpfpTxOut,
-    /// Insufficient funds (used in Cpfp)
+    /// Insufficient funds to cover the outputs and the fees (used in Cpfp, FeeBump and Spend)
     InsufficientFunds,
+    /// A fee ladder must contain at least one entry, and its entries must spend the same output
+    /// at distinct feerates
+    InvalidFeeLadder,
+    /// Would not comply with the TRUC (version 3) topology or size restrictions
+    TrucViolation,
+    /// The transaction can't be replaced: it was already finalized, or lacks the information
+    /// necessary to create its replacement
+    InvalidReplacement,
+    /// The revocation transaction already has a fee-bumping input
+    AlreadyFeeBumped,
+    /// The revocation transaction has an anchor output, it's fee-bumped through CPFP instead
+    AnchoredRevocation,
+    /// The fee-bumping coins must pay to a P2WPKH Script
+    InvalidFeeBumpScript,
+    /// The fee-bumping transaction must create at least one fee-bumping coin
+    NoFeeBumpTarget,
+    /// The deposit change would pay to the same address as the deposit it spends
+    DepositAddressReuse,
+    /// Fewer change descriptors than Spend transactions to create
+    MissingChangeDescriptor,
 }
 
 impl fmt::Display for TransactionCreationError {
@@ -102,7 +122,7 @@ impl fmt::Display for TransactionCreationError {
             Self::InsaneAmounts => {
                 write!(f, "Sum of the outputs value amounts to more than MAX_MONEY")
             }
-            Self::InsaneFees => write!(f, "Fees larger than {} sats", INSANE_FEES),
+            Self::InsaneFees => write!(f, "Fees larger than the insane fees threshold"),
             Self::Dust => write!(f, "Spending or creating a dust output"),
             Self::NegativeFees => write!(
                 f,
@@ -121,6 +141,41 @@ impl fmt::Display for TransactionCreationError {
                 "Attempting to create a CPFP transaction for a tx without a CPFP output"
             ),
             Self::InsufficientFunds => write!(f, "Insufficient funds"),
+            Self::InvalidFeeLadder => write!(
+                f,
+                "Fee ladder entries must spend the same output at distinct feerates"
+            ),
+            Self::TrucViolation => write!(
+                f,
+                "Transaction would not comply with the TRUC topology or size restrictions"
+            ),
+            Self::InvalidReplacement => write!(
+                f,
+                "Transaction is finalized or lacks the information necessary to replace it"
+            ),
+            Self::AlreadyFeeBumped => write!(
+                f,
+                "The revocation transaction already has a fee-bumping input"
+            ),
+            Self::AnchoredRevocation => write!(
+                f,
+                "The revocation transaction has an anchor output, it's fee-bumped through CPFP"
+            ),
+            Self::InvalidFeeBumpScript => {
+                write!(f, "The fee-bumping coins must pay to a P2WPKH Script")
+            }
+            Self::NoFeeBumpTarget => write!(
+                f,
+                "The fee-bumping transaction must create at least one fee-bumping coin"
+            ),
+            Self::DepositAddressReuse => write!(
+                f,
+                "The deposit change would pay to the address of the deposit it spends"
+            ),
+            Self::MissingChangeDescriptor => write!(
+                f,
+                "Fewer change descriptors than Spend transactions to create"
+            ),
         }
     }
 }
@@ -138,10 +193,14 @@ pub enum InputSatisfactionError {
     MissingWitnessScript,
     /// Trying to add an invalid signature
     InvalidSignature(
-        secp256k1::Signature,
-        secp256k1::PublicKey,
-        secp256k1::Message,
+        Box<(
+            secp256k1::Signature,
+            secp256k1::PublicKey,
+            secp256k1::Message,
+        )>,
     ),
+    /// Trying to sign for a keyless anchor input
+    KeylessInput,
 }
 
 impl fmt::Display for InputSatisfactionError {
@@ -153,11 +212,12 @@ impl fmt::Display for InputSatisfactionError {
                 f,
                 "Missing witness_script field in PSBT input. Wrong sighash function used?"
             ),
-            Self::InvalidSignature(sig, pk, hash) => write!(
+            Self::InvalidSignature(e) => write!(
                 f,
                 "Invalid signature '{:x?}' for key '{:x?}' and sighash '{:x?}'",
-                &sig, &pk, &hash
+                &e.0, &e.1, &e.2
             ),
+            Self::KeylessInput => write!(f, "Keyless anchor inputs can't be signed"),
         }
     }
 }
@@ -175,19 +235,25 @@ pub enum PsbtValidationError {
     DuplicatedInput,
     MissingRevocationInput,
     MissingFeeBumpingInput,
-    MissingWitnessUtxo(PsbtInput),
-    MissingInWitnessScript(PsbtInput),
-    InvalidInWitnessScript(PsbtInput),
-    MissingOutWitnessScript(PsbtOutput),
-    InvalidOutWitnessScript(PsbtOutput),
-    InvalidSighashType(PsbtInput),
-    InvalidInputField(PsbtInput),
-    InvalidOutputField(PsbtOutput),
-    InvalidPrevoutType(PsbtInput),
+    MissingWitnessUtxo(Box<PsbtInput>),
+    MissingInWitnessScript(Box<PsbtInput>),
+    InvalidInWitnessScript(Box<PsbtInput>),
+    MissingOutWitnessScript(Box<PsbtOutput>),
+    InvalidOutWitnessScript(Box<PsbtOutput>),
+    InvalidSighashType(Box<PsbtInput>),
+    InvalidInputField(Box<PsbtInput>),
+    InvalidOutputField(Box<PsbtOutput>),
+    InvalidPrevoutType(Box<PsbtInput>),
     InvalidCountOuputWithDerivations(usize),
     PartiallyFinalized,
     InsaneAmounts,
     TransactionTooLarge,
+    InvalidFees(u64, u64),
+    InsaneFees(u64),
+    InvalidCpfpValue(u64),
+    DustOutput(u64),
+    TrucTooLarge(u64),
+    InvalidAnchorValue(u64),
 }
 
 impl fmt::Display for PsbtValidationError {
@@ -248,6 +314,28 @@ impl fmt::Display for PsbtValidationError {
                 f,
                 "Transaction too large: satisfied it could be >400k weight units"
             ),
+            Self::InvalidFees(fees, expected) => write!(
+                f,
+                "Transaction pays '{}' sats of fees but the fee policy requires '{}'",
+                fees, expected
+            ),
+            Self::InsaneFees(fees) => write!(
+                f,
+                "Transaction pays '{}' sats of fees, more than the fee policy allows",
+                fees
+            ),
+            Self::InvalidCpfpValue(v) => write!(f, "Invalid CPFP output value: '{}'", v),
+            Self::DustOutput(v) => write!(
+                f,
+                "Output value '{}' is below the dust limit of the fee policy",
+                v
+            ),
+            Self::TrucTooLarge(w) => write!(
+                f,
+                "TRUC transaction too large: satisfied it could be '{}' weight units",
+                w
+            ),
+            Self::InvalidAnchorValue(v) => write!(f, "Invalid anchor output value: '{}'", v),
         }
     }
 }
@@ -296,6 +384,68 @@ impl From<PsbtValidationError> for TransactionSerialisationError {
 
 impl error::Error for TransactionSerialisationError {}
 
+/// Error when cosigning a Spend transaction
+#[derive(PartialEq, Debug)]
+pub enum CosignerError {
+    /// We already signed another Spend transaction (with this txid) for this Unvault outpoint
+    AlreadySigned(OutPoint, Txid),
+    /// None of the keys of this input is ours
+    UnknownInput(usize),
+    /// Signing an input failed
+    InputSatisfaction(Box<InputSatisfactionError>),
+    /// An error from the storage of the signing decisions
+    Store(String),
+}
+
+impl fmt::Display for CosignerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::AlreadySigned(outpoint, txid) => write!(
+                f,
+                "Already signed Spend '{}' for Unvault outpoint '{}'",
+                txid, outpoint
+            ),
+            Self::UnknownInput(index) => write!(f, "No cosigner key for input '{}'", index),
+            Self::InputSatisfaction(e) => write!(f, "Signing error: '{}'", e),
+            Self::Store(e) => write!(f, "Storage error: '{}'", e),
+        }
+    }
+}
+
+impl From<InputSatisfactionError> for CosignerError {
+    fn from(e: InputSatisfactionError) -> Self {
+        Self::InputSatisfaction(Box::new(e))
+    }
+}
+
+impl error::Error for CosignerError {}
+
+/// Error when managing a vault
+#[derive(PartialEq, Eq, Debug)]
+pub enum VaultError {
+    /// This Spend transaction does not spend the Unvault output of the vault
+    UnrelatedSpend(Txid),
+    /// This transaction does not spend the same output as the revocation transaction it replaces
+    UnrelatedReplacement(Txid),
+}
+
+impl fmt::Display for VaultError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::UnrelatedSpend(txid) => {
+                write!(f, "Spend '{}' does not spend the vault's Unvault", txid)
+            }
+            Self::UnrelatedReplacement(txid) => write!(
+                f,
+                "Transaction '{}' does not replace a revocation transaction of the vault",
+                txid
+            ),
+        }
+    }
+}
+
+impl error::Error for VaultError {}
+
 /// An error specific to the management of Revault transactions and scripts.
 #[derive(Debug)]
 pub enum Error {
@@ -314,6 +464,10 @@ pub enum Error {
     TransactionVerification(LibConsensusError),
     /// Error when working with serialized Revault transactions
     TransactionSerialisation(TransactionSerialisationError),
+    /// Cosigning a Spend transaction failed.
+    Cosigner(CosignerError),
+    /// Managing a vault failed.
+    Vault(VaultError),
 }
 
 impl From<ScriptCreationError> for Error {
@@ -352,6 +506,18 @@ impl From<TransactionSerialisationError> for Error {
     }
 }
 
+impl From<CosignerError> for Error {
+    fn from(e: CosignerError) -> Self {
+        Self::Cosigner(e)
+    }
+}
+
+impl From<VaultError> for Error {
+    fn from(e: VaultError) -> Self {
+        Self::Vault(e)
+    }
+}
+
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
@@ -374,6 +540,8 @@ impl fmt::Display for Error {
             Error::TransactionSerialisation(ref e) => {
                 write!(f, "Revault transaction serialisation error: '{}'", e)
             }
+            Error::Cosigner(ref e) => write!(f, "Revault cosigner error: '{}'", e),
+            Error::Vault(ref e) => write!(f, "Revault vault error: '{}'", e),
         }
     }
 }
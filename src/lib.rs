@@ -17,3 +17,9 @@ pub mod txins;
 pub mod txouts;
 
 pub mod transactions;
+
+pub mod policy;
+
+pub mod cosigner;
+
+pub mod vault;
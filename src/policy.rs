@@ -0,0 +1,535 @@
+//! # Revault Spend policies
+//!
+//! Rules a watchtower checks a Spend transaction against before letting it go through. If the
+//! Spend violates any of them, the watchtower must revault the coins by broadcasting the Cancel
+//! transactions of the Unvault outputs it spends.
+
+use crate::{
+    scripts::{CpfpDescriptor, DepositDescriptor},
+    transactions::{CancelTransaction, CpfpableTransaction, RevaultTransaction, SpendTransaction},
+};
+
+use miniscript::{
+    bitcoin::{secp256k1, OutPoint, Script},
+    DescriptorTrait,
+};
+
+use std::{collections::BTreeSet, fmt};
+
+/// A rule a Spend transaction must comply with.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SpendRule {
+    /// The Spend may only pay to these Scripts, in addition to the vault and the CPFP outputs.
+    DestinationWhitelist(BTreeSet<Script>),
+    /// The Spend may not pay more than this amount, in sats, to destinations outside the vault.
+    MaxAmountPerSpend(u64),
+    /// The Spends allowed within `window` seconds, including this one, may not pay more than
+    /// `max_amount` sats to destinations outside the vault.
+    MaxAmountPerWindow {
+        /// The maximum amount, in sats
+        max_amount: u64,
+        /// The duration of the rolling window, in seconds
+        window: u64,
+    },
+    /// The Spend must send at least this percentage of the value it spends back to the vault,
+    /// through a change output paying to the deposit descriptor.
+    MinChangePercent(u64),
+    /// The feerate of the Spend transaction, without its CPFP, must be within these bounds
+    /// (in sat/kWU, as for the CPFP transactions).
+    FeerateBounds {
+        /// The minimum feerate
+        min: u64,
+        /// The maximum feerate
+        max: u64,
+    },
+    /// The Spend may only be seen between these hours (UTC), the start hour being included and
+    /// the end hour being excluded. The range may wrap around midnight.
+    AllowedHours {
+        /// The first allowed hour, between 0 and 23
+        start: u8,
+        /// The first forbidden hour after the start one, between 0 and 23
+        end: u8,
+    },
+}
+
+/// The rule a Spend transaction violated.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SpendPolicyViolation {
+    /// The Spend pays to a Script which isn't whitelisted
+    UnknownDestination(Script),
+    /// The Spend pays this amount, above the maximum
+    AmountTooLarge {
+        /// The amount paid to destinations outside the vault
+        amount: u64,
+        /// The maximum per Spend
+        max_amount: u64,
+    },
+    /// The Spends within the rolling window would pay this amount, above the maximum
+    WindowAmountTooLarge {
+        /// The amount paid by the Spends of the window, including this one
+        amount: u64,
+        /// The maximum per window
+        max_amount: u64,
+    },
+    /// The Spend sends this amount back to the vault, below the required one
+    InsufficientChange {
+        /// The value of the change output(s)
+        change: u64,
+        /// The minimum value of the change
+        required: u64,
+    },
+    /// The feerate of the Spend, in sat/kWU, is out of the bounds
+    FeerateOutOfBounds(u64),
+    /// The Spend was seen at this hour (UTC), outside of the allowed ones
+    ForbiddenHour(u8),
+}
+
+impl fmt::Display for SpendPolicyViolation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::UnknownDestination(spk) => write!(f, "Unknown destination: '{}'", spk),
+            Self::AmountTooLarge { amount, max_amount } => write!(
+                f,
+                "Spend amount '{}' is above the maximum of '{}'",
+                amount, max_amount
+            ),
+            Self::WindowAmountTooLarge { amount, max_amount } => write!(
+                f,
+                "Amount spent in the window '{}' is above the maximum of '{}'",
+                amount, max_amount
+            ),
+            Self::InsufficientChange { change, required } => write!(
+                f,
+                "Change '{}' is below the required '{}'",
+                change, required
+            ),
+            Self::FeerateOutOfBounds(feerate) => {
+                write!(f, "Feerate '{}' is out of the bounds", feerate)
+            }
+            Self::ForbiddenHour(hour) => write!(f, "Spend at forbidden hour '{}'", hour),
+        }
+    }
+}
+
+/// What to do about a Spend transaction.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SpendVerdict {
+    /// The Spend complies with all the rules
+    Allowed,
+    /// The Spend violated a rule, these Cancel transactions must be broadcast
+    Revoke {
+        /// The first rule the Spend violated
+        violation: SpendPolicyViolation,
+        /// The Cancel transactions of the Unvault outputs spent by the Spend
+        cancel_txs: Vec<CancelTransaction>,
+    },
+}
+
+/// A set of rules to check the Spend transactions against.
+///
+/// The vault and CPFP outputs of a Spend are recognized using the derivation paths of its PSBT
+/// outputs: all the other outputs are considered destinations outside the vault.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SpendPolicy {
+    deposit_descriptor: DepositDescriptor,
+    cpfp_descriptor: CpfpDescriptor,
+    rules: Vec<SpendRule>,
+    // The time at which we allowed a Spend and the amount it paid, for the rolling windows
+    history: Vec<(u64, u64)>,
+}
+
+impl SpendPolicy {
+    /// A policy without any rule, allowing all Spends. Rules are added using
+    /// [SpendPolicy::with_rule].
+    pub fn new(deposit_descriptor: DepositDescriptor, cpfp_descriptor: CpfpDescriptor) -> Self {
+        SpendPolicy {
+            deposit_descriptor,
+            cpfp_descriptor,
+            rules: Vec::new(),
+            history: Vec::new(),
+        }
+    }
+
+    /// Add a rule to the policy. The rules are checked in the order they were added.
+    pub fn with_rule(mut self, rule: SpendRule) -> Self {
+        self.rules.push(rule);
+        self
+    }
+
+    /// Check a Spend transaction seen at `seen_at` (a UNIX timestamp) against the rules.
+    ///
+    /// Allowed Spends are recorded for the rolling window rules. For a Spend violating a rule,
+    /// the Cancel transactions spending the same Unvault outputs are picked among the
+    /// `cancel_txs` for the watchtower to broadcast.
+    pub fn evaluate<C: secp256k1::Verification>(
+        &mut self,
+        spend_tx: &SpendTransaction,
+        cancel_txs: &[CancelTransaction],
+        seen_at: u64,
+        secp: &secp256k1::Secp256k1<C>,
+    ) -> SpendVerdict {
+        match self.check_rules(spend_tx, seen_at, secp) {
+            Ok(amount) => {
+                self.record(seen_at, amount);
+                SpendVerdict::Allowed
+            }
+            Err(violation) => {
+                let unvault_outpoints: BTreeSet<OutPoint> = spend_tx
+                    .tx()
+                    .input
+                    .iter()
+                    .map(|txin| txin.previous_output)
+                    .collect();
+                let cancel_txs = cancel_txs
+                    .iter()
+                    .filter(|cancel_tx| {
+                        cancel_tx
+                            .tx()
+                            .input
+                            .iter()
+                            .any(|txin| unvault_outpoints.contains(&txin.previous_output))
+                    })
+                    .cloned()
+                    .collect();
+                SpendVerdict::Revoke {
+                    violation,
+                    cancel_txs,
+                }
+            }
+        }
+    }
+
+    // Check all the rules, returning the amount paid outside the vault by the Spend
+    fn check_rules<C: secp256k1::Verification>(
+        &self,
+        spend_tx: &SpendTransaction,
+        seen_at: u64,
+        secp: &secp256k1::Secp256k1<C>,
+    ) -> Result<u64, SpendPolicyViolation> {
+        let (destinations, change) = self.classify_outputs(spend_tx, secp);
+        let amount: u64 = destinations.iter().map(|(_, value)| value).sum();
+        let inputs_value: u64 = spend_tx
+            .psbt()
+            .inputs
+            .iter()
+            .filter_map(|input| input.witness_utxo.as_ref())
+            .map(|txo| txo.value)
+            .sum();
+
+        for rule in self.rules.iter() {
+            match rule {
+                SpendRule::DestinationWhitelist(whitelist) => {
+                    if let Some((spk, _)) = destinations
+                        .iter()
+                        .find(|(spk, _)| !whitelist.contains(spk))
+                    {
+                        return Err(SpendPolicyViolation::UnknownDestination(spk.clone()));
+                    }
+                }
+                SpendRule::MaxAmountPerSpend(max_amount) => {
+                    if amount > *max_amount {
+                        return Err(SpendPolicyViolation::AmountTooLarge {
+                            amount,
+                            max_amount: *max_amount,
+                        });
+                    }
+                }
+                SpendRule::MaxAmountPerWindow { max_amount, window } => {
+                    let window_start = seen_at.saturating_sub(*window);
+                    let window_amount = self
+                        .history
+                        .iter()
+                        .filter(|(time, _)| *time > window_start)
+                        .map(|(_, amount)| amount)
+                        .sum::<u64>()
+                        + amount;
+                    if window_amount > *max_amount {
+                        return Err(SpendPolicyViolation::WindowAmountTooLarge {
+                            amount: window_amount,
+                            max_amount: *max_amount,
+                        });
+                    }
+                }
+                SpendRule::MinChangePercent(percent) => {
+                    let required = inputs_value.saturating_mul(*percent) / 100;
+                    if change < required {
+                        return Err(SpendPolicyViolation::InsufficientChange { change, required });
+                    }
+                }
+                SpendRule::FeerateBounds { min, max } => {
+                    let feerate = spend_tx.fees().saturating_mul(1000) / spend_tx.max_weight();
+                    if feerate < *min || feerate > *max {
+                        return Err(SpendPolicyViolation::FeerateOutOfBounds(feerate));
+                    }
+                }
+                SpendRule::AllowedHours { start, end } => {
+                    let hour = ((seen_at / 3600) % 24) as u8;
+                    let allowed = if start <= end {
+                        hour >= *start && hour < *end
+                    } else {
+                        hour >= *start || hour < *end
+                    };
+                    if !allowed {
+                        return Err(SpendPolicyViolation::ForbiddenHour(hour));
+                    }
+                }
+            }
+        }
+
+        Ok(amount)
+    }
+
+    // Get the destinations outside the vault paid by the Spend, and the value sent back to the
+    // vault. An output pays to the vault (or is the CPFP output) if it pays to the deposit (or
+    // CPFP) descriptor derived at the last index of its derivation paths.
+    fn classify_outputs<C: secp256k1::Verification>(
+        &self,
+        spend_tx: &SpendTransaction,
+        secp: &secp256k1::Secp256k1<C>,
+    ) -> (Vec<(Script, u64)>, u64) {
+        let mut destinations = Vec::new();
+        let mut change = 0;
+
+        let psbt = spend_tx.psbt();
+        for (txo, psbtout) in psbt
+            .global
+            .unsigned_tx
+            .output
+            .iter()
+            .zip(psbt.outputs.iter())
+        {
+            // Our descriptors can't be derived at a hardened index, such an output can't be ours
+            let indexes: BTreeSet<_> = psbtout
+                .bip32_derivation
+                .values()
+                .filter_map(|(_, path)| path.as_ref().last().copied())
+                .filter(|index| index.is_normal())
+                .collect();
+            let is_deposit = indexes.iter().any(|index| {
+                self.deposit_descriptor
+                    .derive(*index, secp)
+                    .inner()
+                    .script_pubkey()
+                    == txo.script_pubkey
+            });
+            let is_cpfp = indexes.iter().any(|index| {
+                self.cpfp_descriptor
+                    .derive(*index, secp)
+                    .inner()
+                    .script_pubkey()
+                    == txo.script_pubkey
+            });
+
+            if is_deposit {
+                change += txo.value;
+            } else if !is_cpfp {
+                destinations.push((txo.script_pubkey.clone(), txo.value));
+            }
+        }
+
+        (destinations, change)
+    }
+
+    // Record an allowed Spend, forgetting about the ones no window rule cares about anymore
+    fn record(&mut self, seen_at: u64, amount: u64) {
+        let max_window = self
+            .rules
+            .iter()
+            .filter_map(|rule| match rule {
+                SpendRule::MaxAmountPerWindow { window, .. } => Some(*window),
+                _ => None,
+            })
+            .max();
+
+        if let Some(window) = max_window {
+            let window_start = seen_at.saturating_sub(window);
+            self.history.retain(|(time, _)| *time > window_start);
+            self.history.push((seen_at, amount));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        transactions::{plan_spend_tx, tests_helpers::TestDeployment, FeePolicy},
+        txouts::SpendTxOut,
+    };
+
+    use miniscript::bitcoin::{consensus::encode, util::bip32, TxOut};
+
+    #[test]
+    fn spend_policy() {
+        let secp = secp256k1::Secp256k1::new();
+        let deployment = TestDeployment::new(3, 2, false, 360_000_000, &secp);
+        let fee_policy = FeePolicy::default();
+        let other_deployment = TestDeployment::new(3, 2, false, 360_000_000, &secp);
+        let (_, other_cancel, _, _) = other_deployment.chain(&fee_policy, &secp);
+
+        // A Spend of the deployment's vault paying 1000 sats, with a change output
+        let feerate = 2;
+        let change_index = bip32::ChildNumber::from(30);
+        let (spend_tx, unvault_txs) = plan_spend_tx(
+            vec![(
+                deployment.deposit_outpoint(),
+                deployment.deposit_amount(),
+                deployment.child_number,
+            )],
+            vec![SpendTxOut::new(TxOut {
+                value: 1_000,
+                ..TxOut::default()
+            })],
+            feerate,
+            change_index,
+            &deployment.deposit_descriptor,
+            &deployment.unvault_descriptor,
+            &deployment.cpfp_descriptor,
+            0,
+            &fee_policy,
+            &secp,
+        )
+        .unwrap();
+        assert_eq!(spend_tx.tx().output.len(), 3);
+        let cancel_tx = CancelTransaction::new(
+            unvault_txs[0].revault_unvault_txin(&deployment.der_unvault_descriptor(&secp)),
+            None,
+            &deployment.der_deposit_descriptor(&secp),
+            0,
+            &fee_policy,
+        )
+        .unwrap();
+        let all_cancels = vec![other_cancel, cancel_tx.clone()];
+        let feerate_kwu = feerate * 1_000;
+
+        // 12:00 UTC
+        let seen_at = 1_600_000_000;
+        let mut spend_policy = SpendPolicy::new(
+            deployment.deposit_descriptor.clone(),
+            deployment.cpfp_descriptor.clone(),
+        )
+        .with_rule(SpendRule::DestinationWhitelist(
+            std::iter::once(Script::new()).collect(),
+        ))
+        .with_rule(SpendRule::MaxAmountPerSpend(1_000))
+        .with_rule(SpendRule::MinChangePercent(50))
+        .with_rule(SpendRule::FeerateBounds {
+            min: feerate_kwu,
+            max: feerate_kwu,
+        })
+        .with_rule(SpendRule::AllowedHours { start: 8, end: 18 })
+        .with_rule(SpendRule::MaxAmountPerWindow {
+            max_amount: 1_500,
+            window: 3_600,
+        });
+        assert_eq!(
+            spend_policy.evaluate(&spend_tx, &all_cancels, seen_at, &secp),
+            SpendVerdict::Allowed
+        );
+        // Only the Cancel of the spent Unvault is to be broadcast
+        assert_eq!(
+            spend_policy.evaluate(&spend_tx, &all_cancels, seen_at + 60, &secp),
+            SpendVerdict::Revoke {
+                violation: SpendPolicyViolation::WindowAmountTooLarge {
+                    amount: 2_000,
+                    max_amount: 1_500
+                },
+                cancel_txs: vec![cancel_tx.clone()],
+            }
+        );
+        assert_eq!(
+            spend_policy.evaluate(&spend_tx, &all_cancels, seen_at + 3_600, &secp),
+            SpendVerdict::Allowed
+        );
+
+        let violation = |rule: SpendRule, seen_at: u64| match SpendPolicy::new(
+            deployment.deposit_descriptor.clone(),
+            deployment.cpfp_descriptor.clone(),
+        )
+        .with_rule(rule)
+        .evaluate(&spend_tx, &all_cancels, seen_at, &secp)
+        {
+            SpendVerdict::Allowed => None,
+            SpendVerdict::Revoke {
+                violation,
+                cancel_txs,
+            } => {
+                assert_eq!(cancel_txs, vec![cancel_tx.clone()]);
+                Some(violation)
+            }
+        };
+        assert_eq!(
+            violation(
+                SpendRule::DestinationWhitelist(
+                    std::iter::once(Script::new_op_return(&[])).collect()
+                ),
+                seen_at
+            ),
+            Some(SpendPolicyViolation::UnknownDestination(Script::new()))
+        );
+        assert_eq!(
+            violation(SpendRule::MaxAmountPerSpend(999), seen_at),
+            Some(SpendPolicyViolation::AmountTooLarge {
+                amount: 1_000,
+                max_amount: 999
+            })
+        );
+        let change_value = spend_tx.tx().output[2].value;
+        let inputs_value = spend_tx.psbt().inputs[0]
+            .witness_utxo
+            .as_ref()
+            .unwrap()
+            .value;
+        assert_eq!(
+            violation(SpendRule::MinChangePercent(100), seen_at),
+            Some(SpendPolicyViolation::InsufficientChange {
+                change: change_value,
+                required: inputs_value,
+            })
+        );
+        assert_eq!(
+            violation(
+                SpendRule::FeerateBounds {
+                    min: feerate_kwu + 1,
+                    max: feerate_kwu * 2,
+                },
+                seen_at
+            ),
+            Some(SpendPolicyViolation::FeerateOutOfBounds(feerate_kwu))
+        );
+        assert_eq!(
+            violation(SpendRule::AllowedHours { start: 22, end: 6 }, seen_at),
+            Some(SpendPolicyViolation::ForbiddenHour(12))
+        );
+        assert_eq!(
+            violation(
+                SpendRule::AllowedHours { start: 22, end: 6 },
+                seen_at + 11 * 3_600
+            ),
+            None
+        );
+
+        // An output derived at a hardened index is a destination, even if it has our keys
+        let mut psbt = spend_tx.psbt().clone();
+        for (_, path) in psbt.outputs[2].bip32_derivation.values_mut() {
+            *path = vec![bip32::ChildNumber::from_hardened_idx(30).unwrap()].into();
+        }
+        let hardened_spend_tx =
+            SpendTransaction::from_psbt_serialized(&encode::serialize(&psbt)).unwrap();
+        assert_eq!(
+            SpendPolicy::new(
+                deployment.deposit_descriptor.clone(),
+                deployment.cpfp_descriptor.clone(),
+            )
+            .with_rule(SpendRule::MaxAmountPerSpend(1_000))
+            .evaluate(&hardened_spend_tx, &all_cancels, seen_at, &secp),
+            SpendVerdict::Revoke {
+                violation: SpendPolicyViolation::AmountTooLarge {
+                    amount: 1_000 + change_value,
+                    max_amount: 1_000
+                },
+                cancel_txs: vec![cancel_tx],
+            }
+        );
+    }
+}
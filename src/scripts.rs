@@ -16,10 +16,11 @@ use crate::error::*;
 
 use miniscript::{
     bitcoin::{
+        blockdata::{opcodes, script},
         hashes::{hash160, Hash},
         secp256k1,
         util::bip32,
-        Address, PublicKey,
+        Address, PublicKey, Script,
     },
     descriptor::{DescriptorPublicKey, DescriptorTrait, Wildcard, WshInner},
     miniscript::{
@@ -293,7 +294,7 @@ macro_rules! deposit_desc {
 }
 
 macro_rules! unvault_desc_checks {
-    ($stakeholders:ident,$managers:ident, $managers_threshold:ident, $cosigners:ident, $csv_value:ident) => {
+    ($stakeholders:ident,$managers:ident, $managers_threshold:ident, $cosigners:ident, $cosigners_threshold:ident, $csv_value:ident) => {
         if $stakeholders.is_empty()
             || $managers.is_empty()
             || !$cosigners.is_empty() && $cosigners.len() != $stakeholders.len()
@@ -305,6 +306,14 @@ macro_rules! unvault_desc_checks {
             return Err(ScriptCreationError::BadParameters);
         }
 
+        // Without Cosigning Servers there is nothing to require, otherwise at least one of them
+        // must sign
+        if $cosigners_threshold > $cosigners.len()
+            || !$cosigners.is_empty() && $cosigners_threshold == 0
+        {
+            return Err(ScriptCreationError::BadParameters);
+        }
+
         // We require the locktime to:
         //  - not be disabled
         //  - be in number of blocks
@@ -319,7 +328,7 @@ macro_rules! unvault_desc_checks {
 }
 
 macro_rules! unvault_desc {
-    ($stakeholders:ident, $managers:ident, $managers_threshold:ident, $cosigners:ident, $csv_value:ident) => {{
+    ($stakeholders:ident, $managers:ident, $managers_threshold:ident, $cosigners:ident, $cosigners_threshold:ident, $csv_value:ident) => {{
         let mut pubkeys = $managers
             .into_iter()
             .map(Policy::Key)
@@ -339,7 +348,7 @@ macro_rules! unvault_desc {
                 .into_iter()
                 .map(Policy::Key)
                 .collect::<Vec<Policy<_>>>();
-            let cosigners_thres = Policy::Threshold(pubkeys.len(), pubkeys);
+            let cosigners_thres = Policy::Threshold($cosigners_threshold, pubkeys);
             let cosigners_and_csv = Policy::And(vec![cosigners_thres, Policy::Older($csv_value)]);
 
             Policy::And(vec![spenders_thres, cosigners_and_csv])
@@ -499,9 +508,45 @@ fn unvault_descriptor_csv<Pk: MiniscriptKey>(desc: &Descriptor<Pk>) -> u32 {
     }
 }
 
-fn unvault_descriptor_managers_threshold<Pk: MiniscriptKey>(
+// The Miniscript of our P2WSH descriptors
+type SegwitMs<Pk> = Miniscript<Pk, Segwitv0>;
+
+// Whether this Miniscript contains the CSV
+fn contains_csv<Pk: MiniscriptKey>(ms: &SegwitMs<Pk>) -> bool {
+    ms.iter().any(|ms| matches!(ms.node, Terminal::Older(_)))
+}
+
+// Get the Miniscript under the wrappers
+fn unwrapped<Pk: MiniscriptKey>(ms: &SegwitMs<Pk>) -> &SegwitMs<Pk> {
+    match ms.node {
+        Terminal::Alt(ref sub)
+        | Terminal::Swap(ref sub)
+        | Terminal::Check(ref sub)
+        | Terminal::DupIf(ref sub)
+        | Terminal::Verify(ref sub)
+        | Terminal::NonZero(ref sub)
+        | Terminal::ZeroNotEqual(ref sub) => unwrapped(sub),
+        _ => ms,
+    }
+}
+
+// Get the two sub-Miniscripts of an 'and_v', 'and_b' or 'and_n'
+fn and_subs<Pk: MiniscriptKey>(ms: &SegwitMs<Pk>) -> Option<(&SegwitMs<Pk>, &SegwitMs<Pk>)> {
+    match unwrapped(ms).node {
+        Terminal::AndV(ref left, ref right) | Terminal::AndB(ref left, ref right) => {
+            Some((left, right))
+        }
+        Terminal::AndOr(ref a, ref b, ref c) if c.node == Terminal::False => Some((a, b)),
+        _ => None,
+    }
+}
+
+// Split the managers' path of an Unvault descriptor into the Managers and the (optional)
+// Cosigning Servers policies. We can't lift the whole Miniscript here, as the normalization
+// would flatten the keys of both in a single threshold along with the CSV.
+fn unvault_descriptor_spenders<Pk: MiniscriptKey>(
     desc: &Descriptor<Pk>,
-) -> Option<usize> {
+) -> (SemanticPolicy<Pk>, Option<SemanticPolicy<Pk>>) {
     let ms = match &desc {
         Descriptor::Wsh(ref wsh) => match wsh.as_inner() {
             WshInner::Ms(ms) => ms,
@@ -510,44 +555,66 @@ fn unvault_descriptor_managers_threshold<Pk: MiniscriptKey>(
         _ => unreachable!("Unvault descriptor is always a P2WSH"),
     };
 
-    let policy = ms
-        .lift()
-        .expect("Lifting can't fail on a Miniscript")
-        .normalized();
-
-    // The Unvault descriptor is always of the form 'or(mans_branch, stks_branch)'
-    match policy {
-        SemanticPolicy::Threshold(1, ref subs) => {
-            assert_eq!(subs.len(), 2);
-
-            // The 'mans_branch' can be identified as the one containing the CSV. It is always an
-            // 'and()' of CSV + Managers (the latter being potentially a thresh) and optionally
-            // along with Cosigning Servers
-            for sub in subs {
-                match sub {
-                    SemanticPolicy::Threshold(k, ref subs)
-                        if k == &subs.len()
-                            && subs
-                                .iter()
-                                .any(|sub| matches!(sub, SemanticPolicy::Older(..))) =>
-                    {
-                        // Now, the mans are either a Threshold or directly pks (in the case the
-                        // thresh is an 'and()', pks are flattened in the upper 'and()').
-                        return subs.iter().find_map(|sub| match sub {
-                            SemanticPolicy::Threshold(k, _) => Some(*k),
-                            _ => None,
-                        });
-                    }
-                    _ => continue,
-                }
-            }
-
-            unreachable!(
-                "Given an Unvault descriptor which doesn't contain a second-to-top \
-                         branch with a CSV"
-            );
+    // The Unvault descriptor is always of the form 'or(stks_branch, mans_branch)', which may
+    // be compiled to an 'andor(mans_a, mans_b, stks_branch)'. The 'mans_branch' can be
+    // identified as the one containing the CSV, and is always of the form
+    // 'and(managers, csv)' or 'and(managers, and(cosigners, csv))'.
+    let mans_branch = match unwrapped(ms).node {
+        Terminal::AndOr(ref a, ref b, ref c) if !contains_csv(c) => Some((&**a, &**b)),
+        Terminal::AndOr(_, _, ref branch)
+        | Terminal::OrB(ref branch, _)
+        | Terminal::OrB(_, ref branch)
+        | Terminal::OrD(ref branch, _)
+        | Terminal::OrD(_, ref branch)
+        | Terminal::OrC(ref branch, _)
+        | Terminal::OrC(_, ref branch)
+        | Terminal::OrI(ref branch, _)
+        | Terminal::OrI(_, ref branch)
+            if contains_csv(branch) =>
+        {
+            and_subs(branch)
         }
         _ => unreachable!("Given an Unvault descriptor that doesn't contain a 'or()' at the root"),
+    };
+    let (managers, cosigners_and_csv) = match mans_branch {
+        Some((left, right)) if contains_csv(right) => (left, right),
+        Some((left, right)) => (right, left),
+        None => unreachable!("Given an Unvault descriptor whose CSV branch isn't an 'and()'"),
+    };
+    let cosigners = match unwrapped(cosigners_and_csv).node {
+        Terminal::Older(_) => None,
+        _ => match and_subs(cosigners_and_csv) {
+            Some((left, right)) if contains_csv(right) => Some(left),
+            Some((_, right)) => Some(right),
+            None => unreachable!("Given an Unvault descriptor with no 'and()' of the CSV"),
+        },
+    };
+
+    let lift = |ms: &SegwitMs<Pk>| {
+        ms.lift()
+            .expect("Lifting can't fail on a Miniscript")
+            .normalized()
+    };
+    (lift(managers), cosigners.map(lift))
+}
+
+fn unvault_descriptor_managers_threshold<Pk: MiniscriptKey>(
+    desc: &Descriptor<Pk>,
+) -> Option<usize> {
+    // The managers are either a Threshold, or directly pks if all of them are required
+    match unvault_descriptor_spenders(desc).0 {
+        SemanticPolicy::Threshold(k, ref subs) if k < subs.len() => Some(k),
+        _ => None,
+    }
+}
+
+fn unvault_descriptor_cosigners_threshold<Pk: MiniscriptKey>(
+    desc: &Descriptor<Pk>,
+) -> Option<usize> {
+    match unvault_descriptor_spenders(desc).1? {
+        SemanticPolicy::Threshold(k, _) => Some(k),
+        SemanticPolicy::KeyHash(_) => Some(1),
+        _ => unreachable!("Cosigning Servers are always either a thresh() or a single key"),
     }
 }
 
@@ -580,6 +647,7 @@ impl UnvaultDescriptor {
     ///     1,
     ///     // No cosigning server
     ///     vec![],
+    ///     0,
     ///     // CSV
     ///     42
     /// ).expect("Compiling descriptor");
@@ -613,8 +681,9 @@ impl UnvaultDescriptor {
     ///     vec![first_stakeholder, second_stakeholder, third_stakeholder],
     ///     vec![first_manager, second_manager],
     ///     1,
-    ///     // Cosigning servers
+    ///     // Cosigning servers, any 2 of them must sign
     ///     vec![first_cosig, second_cosig, third_cosig],
+    ///     2,
     ///     // CSV
     ///     42
     /// ).expect("Compiling descriptor");
@@ -631,12 +700,15 @@ impl UnvaultDescriptor {
     /// - If the stakeholders and managers `DescriptorPublickKey`s are not wildcards (can be derived from).
     /// - If the cosigning servers public keys vector is both not empty and not of the same length
     ///   as the stakeholders public keys vec
+    /// - If the cosigning servers threshold is 0 while there are cosigning servers, or larger
+    ///   than their number
     /// - If the policy compilation to miniscript failed (eg if a key is used twice)
     pub fn new(
         stakeholders: Vec<DescriptorPublicKey>,
         managers: Vec<DescriptorPublicKey>,
         managers_threshold: usize,
         cosigners: Vec<DescriptorPublicKey>,
+        cosigners_threshold: usize,
         csv_value: u32,
     ) -> Result<UnvaultDescriptor, ScriptCreationError> {
         unvault_desc_checks!(
@@ -644,6 +716,7 @@ impl UnvaultDescriptor {
             managers,
             managers_threshold,
             cosigners,
+            cosigners_threshold,
             csv_value
         );
 
@@ -658,6 +731,7 @@ impl UnvaultDescriptor {
             managers,
             managers_threshold,
             cosigners,
+            cosigners_threshold,
             csv_value
         )))
     }
@@ -672,6 +746,12 @@ impl UnvaultDescriptor {
     pub fn managers_threshold(&self) -> Option<usize> {
         unvault_descriptor_managers_threshold(&self.0)
     }
+
+    /// Get the minimum number of Cosigning Servers required to sign along with the managers,
+    /// or None if there are no Cosigning Servers
+    pub fn cosigners_threshold(&self) -> Option<usize> {
+        unvault_descriptor_cosigners_threshold(&self.0)
+    }
 }
 
 impl Display for UnvaultDescriptor {
@@ -728,6 +808,7 @@ impl DerivedUnvaultDescriptor {
     ///     1,
     ///     // No cosigning server
     ///     vec![],
+    ///     0,
     ///     // CSV
     ///     42
     /// ).expect("Compiling descriptor");
@@ -755,8 +836,9 @@ impl DerivedUnvaultDescriptor {
     ///     vec![first_stakeholder, second_stakeholder, third_stakeholder],
     ///     vec![first_manager, second_manager],
     ///     1,
-    ///     // Cosigners
+    ///     // Cosigners, any 2 of them must sign
     ///     vec![first_cosig, second_cosig, third_cosig],
+    ///     2,
     ///     // CSV
     ///     42
     /// ).expect("Compiling descriptor");
@@ -768,12 +850,15 @@ impl DerivedUnvaultDescriptor {
     /// # Errors
     /// - If the cosigning servers public keys vector is both not empty and not of the same length
     ///   as the stakeholders public keys vec
+    /// - If the cosigning servers threshold is 0 while there are cosigning servers, or larger
+    ///   than their number
     /// - If the policy compilation to miniscript failed (eg if a key is used twice)
     pub fn new(
         stakeholders: Vec<DerivedPublicKey>,
         managers: Vec<DerivedPublicKey>,
         managers_threshold: usize,
         cosigners: Vec<DerivedPublicKey>,
+        cosigners_threshold: usize,
         csv_value: u32,
     ) -> Result<DerivedUnvaultDescriptor, ScriptCreationError> {
         unvault_desc_checks!(
@@ -781,6 +866,7 @@ impl DerivedUnvaultDescriptor {
             managers,
             managers_threshold,
             cosigners,
+            cosigners_threshold,
             csv_value
         );
 
@@ -789,6 +875,7 @@ impl DerivedUnvaultDescriptor {
             managers,
             managers_threshold,
             cosigners,
+            cosigners_threshold,
             csv_value
         )))
     }
@@ -803,6 +890,12 @@ impl DerivedUnvaultDescriptor {
     pub fn managers_threshold(&self) -> Option<usize> {
         unvault_descriptor_managers_threshold(&self.0)
     }
+
+    /// Get the minimum number of Cosigning Servers required to sign along with the managers,
+    /// or None if there are no Cosigning Servers
+    pub fn cosigners_threshold(&self) -> Option<usize> {
+        unvault_descriptor_cosigners_threshold(&self.0)
+    }
 }
 
 impl Display for DerivedUnvaultDescriptor {
@@ -988,6 +1081,18 @@ impl<'de> de::Deserialize<'de> for EmergencyAddress {
     }
 }
 
+/// The witness program of a keyless "Pay To Anchor" output.
+const ANCHOR_WITNESS_PROGRAM: [u8; 2] = [0x4e, 0x73];
+
+/// The scriptPubKey of a keyless "Pay To Anchor" output: `OP_1 <0x4e73>`. Anyone can spend it
+/// with an empty witness, it's therefore used to fee-bump transactions through CPFP.
+pub fn anchor_script_pubkey() -> Script {
+    script::Builder::new()
+        .push_opcode(opcodes::all::OP_PUSHNUM_1)
+        .push_slice(&ANCHOR_WITNESS_PROGRAM)
+        .into_script()
+}
+
 #[cfg(test)]
 mod tests {
 
@@ -1159,6 +1264,7 @@ mod tests {
                 second_cosig.clone(),
                 third_cosig.clone(),
             ],
+            3,
             128,
         )
         .expect_err("Accepting a non wildcard stakeholder xpub");
@@ -1171,6 +1277,7 @@ mod tests {
             vec![first_manager.clone(), invalid_man],
             1,
             vec![first_cosig.clone(), second_cosig.clone(), third_cosig],
+            3,
             128,
         )
         .expect_err("Accepting a non wildcard manager xpub");
@@ -1189,6 +1296,7 @@ mod tests {
             vec![first_manager.clone(), second_manager.clone()],
             1,
             vec![xpub_first_cosig, xpub_second_cosig],
+            2,
             128,
         )
         .expect("Refusing a non wildcard cosigning server xpub");
@@ -1206,6 +1314,7 @@ mod tests {
             vec![first_manager.clone(), second_manager.clone()],
             1,
             vec![xpub_first_cosig, xpub_second_cosig],
+            2,
             128,
         )
         .expect("Refusing a wildcard cosigning server xpub");
@@ -1215,6 +1324,7 @@ mod tests {
             vec![first_manager.clone(), second_manager.clone()],
             1,
             vec![],
+            0,
             6789,
         )
         .expect("Refusing an Unvault descriptor without Cosigning Server");
@@ -1265,6 +1375,7 @@ mod tests {
             vec![first_manager.clone(), second_manager.clone()],
             1,
             vec![first_cosig, second_cosig],
+            2,
             128,
         )
         .expect("Valid, with xpubs");
@@ -1275,6 +1386,7 @@ mod tests {
             vec![raw_pk_c.clone(), raw_pk_d.clone()],
             2,
             vec![raw_pk_e, raw_pk_f],
+            2,
             1024,
         )
         .expect("Derived pubkeys");
@@ -1287,6 +1399,7 @@ mod tests {
             vec![first_manager.clone(), second_manager.clone()],
             2,
             vec![],
+            0,
             128,
         )
         .expect("Valid, with xpubs");
@@ -1297,6 +1410,7 @@ mod tests {
             vec![raw_pk_c, raw_pk_d],
             2,
             vec![],
+            0,
             1024,
         )
         .expect("Derived pubkeys");
@@ -1374,6 +1488,7 @@ mod tests {
                 second_cosig.clone(),
                 third_cosig.clone(),
             ],
+            3,
             2018,
         )
         .expect("Valid, with xpubs");
@@ -1497,6 +1612,7 @@ mod tests {
                 managers.clone(),
                 *thresh,
                 cosigners.clone(),
+                cosigners.len(),
                 18,
             )
             .expect(&format!(
@@ -1543,6 +1659,7 @@ mod tests {
                     get_random_pubkey(&mut rng, &secp),
                     get_random_pubkey(&mut rng, &secp)
                 ],
+                2,
                 6
             )
             .unwrap_err()
@@ -1556,6 +1673,7 @@ mod tests {
                 vec![get_random_pubkey(&mut rng, &secp)],
                 1,
                 vec![get_random_pubkey(&mut rng, &secp)],
+                1,
                 4194305
             )
             .unwrap_err()
@@ -1569,6 +1687,7 @@ mod tests {
                 vec![get_random_pubkey(&mut rng, &secp)],
                 2,
                 vec![get_random_pubkey(&mut rng, &secp)],
+                1,
                 4194305
             )
             .unwrap_err()
@@ -1616,7 +1735,7 @@ mod tests {
         let cosigners = (0..38)
             .map(|_| get_random_pubkey(&mut rng, &secp))
             .collect::<Vec<DescriptorPublicKey>>();
-        UnvaultDescriptor::new(stakeholders, managers, 2, cosigners, 145).unwrap();
+        UnvaultDescriptor::new(stakeholders, managers, 2, cosigners, 38, 145).unwrap();
 
         // Now hit the limit
         let stakeholders = (0..39)
@@ -1629,7 +1748,7 @@ mod tests {
             .map(|_| get_random_pubkey(&mut rng, &secp))
             .collect::<Vec<DescriptorPublicKey>>();
         assert_eq!(
-            UnvaultDescriptor::new(stakeholders, managers, 2, cosigners, 32)
+            UnvaultDescriptor::new(stakeholders, managers, 2, cosigners, 39, 32)
                 .unwrap_err()
                 .to_string(),
             ScriptCreationError::PolicyCompilation(CompilerError::LimitsExceeded).to_string()
@@ -1683,11 +1802,18 @@ mod tests {
 
             for t in (1..n_mans - 1).step_by(2) {
                 assert_eq!(
-                    UnvaultDescriptor::new(stakes.clone(), mans.clone(), t, cosigs.clone(), 6)
-                        .unwrap()
-                        .derive(bip32::ChildNumber::from(345678), &secp)
-                        .managers_threshold()
-                        .unwrap(),
+                    UnvaultDescriptor::new(
+                        stakes.clone(),
+                        mans.clone(),
+                        t,
+                        cosigs.clone(),
+                        cosigs.len(),
+                        6
+                    )
+                    .unwrap()
+                    .derive(bip32::ChildNumber::from(345678), &secp)
+                    .managers_threshold()
+                    .unwrap(),
                     t
                 );
             }
@@ -1696,6 +1822,7 @@ mod tests {
                 mans.clone(),
                 n_mans,
                 cosigs.clone(),
+                cosigs.len(),
                 6
             )
             .unwrap()
@@ -1720,10 +1847,17 @@ mod tests {
 
             for t in (1..n_mans - 1).step_by(4) {
                 assert_eq!(
-                    UnvaultDescriptor::new(stakes.clone(), mans.clone(), t, cosigs.clone(), 6)
-                        .unwrap()
-                        .managers_threshold()
-                        .unwrap(),
+                    UnvaultDescriptor::new(
+                        stakes.clone(),
+                        mans.clone(),
+                        t,
+                        cosigs.clone(),
+                        cosigs.len(),
+                        6
+                    )
+                    .unwrap()
+                    .managers_threshold()
+                    .unwrap(),
                     t
                 );
             }
@@ -1732,6 +1866,7 @@ mod tests {
                 mans.clone(),
                 n_mans,
                 cosigs.clone(),
+                cosigs.len(),
                 6
             )
             .unwrap()
@@ -1755,10 +1890,17 @@ mod tests {
 
             for t in (1..n_mans - 1).step_by(3) {
                 assert_eq!(
-                    UnvaultDescriptor::new(stakes.clone(), mans.clone(), t, cosigs.clone(), 6)
-                        .unwrap()
-                        .managers_threshold()
-                        .unwrap(),
+                    UnvaultDescriptor::new(
+                        stakes.clone(),
+                        mans.clone(),
+                        t,
+                        cosigs.clone(),
+                        cosigs.len(),
+                        6
+                    )
+                    .unwrap()
+                    .managers_threshold()
+                    .unwrap(),
                     t
                 );
             }
@@ -1767,6 +1909,7 @@ mod tests {
                 mans.clone(),
                 n_mans,
                 cosigs.clone(),
+                cosigs.len(),
                 6
             )
             .unwrap()
@@ -1774,4 +1917,82 @@ mod tests {
             .is_none(),);
         }
     }
+
+    #[test]
+    fn unvault_desc_cosigners_threshold() {
+        let secp = secp256k1::Secp256k1::new();
+        let mut rng = fastrand::Rng::new();
+
+        for n_stks in &[1, 3, 10, 20, 22] {
+            let stakes: Vec<DescriptorPublicKey> = (0..*n_stks)
+                .map(|_| get_random_pubkey(&mut rng, &secp))
+                .collect();
+            let cosigs: Vec<DescriptorPublicKey> = (0..*n_stks)
+                .map(|_| get_random_pubkey(&mut rng, &secp))
+                .collect();
+            // Same number of managers as of cosigners, so they can't be told apart by their
+            // number of keys
+            let mans: Vec<DescriptorPublicKey> = (0..*n_stks)
+                .map(|_| get_random_pubkey(&mut rng, &secp))
+                .collect();
+
+            // Above 20 keys a threshold can't be a 'multi()', and a 'thresh()' exceeds the
+            // script limits
+            let thresholds: Vec<usize> = if *n_stks > 20 {
+                vec![*n_stks]
+            } else {
+                (1..n_stks + 1).collect()
+            };
+
+            for t in thresholds.iter().copied() {
+                for mans_t in &[thresholds[0], *n_stks] {
+                    let unvault_desc = UnvaultDescriptor::new(
+                        stakes.clone(),
+                        mans.clone(),
+                        *mans_t,
+                        cosigs.clone(),
+                        t,
+                        6,
+                    )
+                    .unwrap();
+                    assert_eq!(unvault_desc.cosigners_threshold(), Some(t));
+                    assert_eq!(
+                        unvault_desc
+                            .derive(bip32::ChildNumber::from(345678), &secp)
+                            .cosigners_threshold(),
+                        Some(t)
+                    );
+                    let mans_threshold = if *mans_t < *n_stks {
+                        Some(*mans_t)
+                    } else {
+                        None
+                    };
+                    assert_eq!(unvault_desc.managers_threshold(), mans_threshold);
+                }
+            }
+
+            // Without Cosigning Servers
+            assert!(
+                UnvaultDescriptor::new(stakes.clone(), mans.clone(), *n_stks, vec![], 0, 6)
+                    .unwrap()
+                    .cosigners_threshold()
+                    .is_none()
+            );
+
+            // Insane thresholds
+            UnvaultDescriptor::new(stakes.clone(), mans.clone(), 1, cosigs.clone(), 0, 6)
+                .expect_err("No Cosigning Server required");
+            UnvaultDescriptor::new(
+                stakes.clone(),
+                mans.clone(),
+                1,
+                cosigs.clone(),
+                n_stks + 1,
+                6,
+            )
+            .expect_err("Threshold larger than the number of Cosigning Servers");
+            UnvaultDescriptor::new(stakes.clone(), mans.clone(), 1, vec![], 1, 6)
+                .expect_err("Threshold without Cosigning Servers");
+        }
+    }
 }
@@ -2,8 +2,8 @@ use crate::{
     error::*,
     scripts::*,
     transactions::{
-        utils, RevaultTransaction, CANCEL_TX_FEERATE, INSANE_FEES, MAX_STANDARD_TX_WEIGHT,
-        TX_VERSION,
+        utils, CpfpableTransaction, FeePolicy, RevaultTransaction, RevocationTransaction,
+        MAX_STANDARD_TX_WEIGHT, TRUC_MAX_WEIGHT,
     },
     txins::*,
     txouts::*,
@@ -30,6 +30,7 @@ use std::{collections::BTreeMap, convert::TryInto};
 
 impl_revault_transaction!(
     CancelTransaction,
+    parse_psbt,
     doc = "The transaction \"revaulting\" a spend attempt, i.e. spending the unvaulting transaction back to a deposit txo."
 );
 impl CancelTransaction {
@@ -38,7 +39,9 @@ impl CancelTransaction {
         unvault_txin: UnvaultTxIn,
         feebump_txin: Option<FeeBumpTxIn>,
         deposit_txo: DepositTxOut,
+        anchor_txo: Option<AnchorTxOut>,
         lock_time: u32,
+        version: i32,
     ) -> Psbt {
         let mut txins = vec![unvault_txin.unsigned_txin()];
         let mut psbtins = vec![PsbtIn {
@@ -57,19 +60,26 @@ impl CancelTransaction {
             });
         }
 
+        // Deposit txout, and the optional anchor txout
+        let mut psbtouts = vec![PsbtOut {
+            bip32_derivation: deposit_txo.bip32_derivation().clone(),
+            ..PsbtOut::default()
+        }];
+        let mut txouts = vec![deposit_txo.into_txout()];
+        if let Some(anchor_txo) = anchor_txo {
+            psbtouts.push(PsbtOut::default());
+            txouts.push(anchor_txo.into_txout());
+        }
+
         Psbt {
             inputs: psbtins,
-            // Deposit txout
-            outputs: vec![PsbtOut {
-                bip32_derivation: deposit_txo.bip32_derivation().clone(),
-                ..PsbtOut::default()
-            }],
+            outputs: psbtouts,
             global: PsbtGlobal {
                 unsigned_tx: Transaction {
-                    version: TX_VERSION,
+                    version,
                     lock_time,
                     input: txins,
-                    output: vec![deposit_txo.into_txout()],
+                    output: txouts,
                 },
                 version: 0,
                 xpub: BTreeMap::new(),
@@ -88,6 +98,51 @@ impl CancelTransaction {
         feebump_input: Option<FeeBumpTxIn>,
         deposit_descriptor: &DerivedDepositDescriptor,
         lock_time: u32,
+        fee_policy: &FeePolicy,
+    ) -> Result<CancelTransaction, TransactionCreationError> {
+        CancelTransaction::with_feerate(
+            unvault_input,
+            feebump_input,
+            None,
+            deposit_descriptor,
+            lock_time,
+            fee_policy.cancel_feerate,
+            fee_policy,
+        )
+    }
+
+    /// Create a Cancel transaction with a keyless anchor output in addition to its main output,
+    /// so that it can be fee-bumped through CPFP instead of by adding a fee-bumping input. The
+    /// anchor output value is paid for by the revocation input, on top of the fees.
+    ///
+    /// BIP174 Creator and Updater roles.
+    pub fn new_with_anchor(
+        unvault_input: UnvaultTxIn,
+        deposit_descriptor: &DerivedDepositDescriptor,
+        lock_time: u32,
+        fee_policy: &FeePolicy,
+    ) -> Result<CancelTransaction, TransactionCreationError> {
+        CancelTransaction::with_feerate(
+            unvault_input,
+            None,
+            Some(AnchorTxOut::default()),
+            deposit_descriptor,
+            lock_time,
+            fee_policy.cancel_feerate,
+            fee_policy,
+        )
+    }
+
+    // Create a Cancel transaction at this feerate (in sat/WU), used for the fee ladder
+    // and the anchored variant.
+    pub(super) fn with_feerate(
+        unvault_input: UnvaultTxIn,
+        feebump_input: Option<FeeBumpTxIn>,
+        anchor_txo: Option<AnchorTxOut>,
+        deposit_descriptor: &DerivedDepositDescriptor,
+        lock_time: u32,
+        feerate: u64,
+        fee_policy: &FeePolicy,
     ) -> Result<CancelTransaction, TransactionCreationError> {
         if let Some(ref txin) = feebump_input {
             if txin.txout().txout().value > max_money(Network::Bitcoin) {
@@ -102,7 +157,9 @@ impl CancelTransaction {
             unvault_input.clone(),
             None,
             dummy_deposit_txo,
+            anchor_txo.clone(),
             lock_time,
+            fee_policy.tx_version(),
         )
         .global
         .unsigned_tx;
@@ -114,57 +171,67 @@ impl CancelTransaction {
             .checked_add(unvault_input.txout().max_sat_weight())
             .expect("Properly computed weight won't overflow");
         let total_weight: u64 = total_weight.try_into().expect("usize in u64");
-        let fees = CANCEL_TX_FEERATE
+        let fees = feerate
             .checked_mul(total_weight)
-            .expect("Properly computed weight won't overflow");
-        // Without the feebump input, it should not be reachable.
-        debug_assert!(fees < INSANE_FEES);
+            .ok_or(TransactionCreationError::InsaneFees)?;
 
         assert!(
             total_weight <= MAX_STANDARD_TX_WEIGHT as u64,
-            "At most 2 inputs and single output"
+            "At most 2 inputs and 2 outputs"
         );
 
         // Now, get the revaulting output value out of it.
         let unvault_value = unvault_input.txout().txout().value;
+        // The anchor output, if any, is paid for by the revocation input too.
+        let anchor_value = anchor_txo
+            .as_ref()
+            .map(|txo| txo.txout().value)
+            .unwrap_or(0);
         let revault_value = unvault_value
             .checked_sub(fees)
-            .expect("We would not create a dust unvault txo");
+            .and_then(|v| v.checked_sub(anchor_value))
+            .ok_or(TransactionCreationError::Dust)?;
+        if revault_value < fee_policy.dust_limit {
+            return Err(TransactionCreationError::Dust);
+        }
         assert!(
             revault_value < max_money(Network::Bitcoin),
             "Checked in UnvaultTransaction constructor already"
         );
         let deposit_txo = DepositTxOut::new(Amount::from_sat(revault_value), deposit_descriptor);
 
-        Ok(CancelTransaction(CancelTransaction::create_psbt(
+        let psbt = CancelTransaction::create_psbt(
             unvault_input,
             feebump_input,
             deposit_txo,
+            anchor_txo,
             lock_time,
-        )))
+            fee_policy.tx_version(),
+        );
+        if fee_policy.truc && utils::psbt_max_weight(&psbt) > TRUC_MAX_WEIGHT {
+            return Err(TransactionCreationError::TrucViolation);
+        }
+
+        Ok(CancelTransaction(psbt))
     }
 
-    /// Parse a Cancel transaction from a PSBT
-    pub fn from_raw_psbt(raw_psbt: &[u8]) -> Result<Self, TransactionSerialisationError> {
+    // Parse a Cancel transaction from a PSBT, only checking its structure. This is what the
+    // deserialization routines use, as they don't know about the fee policy.
+    fn parse_psbt(raw_psbt: &[u8]) -> Result<Self, TransactionSerialisationError> {
         let psbt = Decodable::consensus_decode(raw_psbt)?;
-        let psbt = utils::psbt_common_sanity_checks(psbt)?;
+        let psbt = utils::psbt_common_sanity_checks(psbt, false, Some(TRUC_MAX_WEIGHT))?;
 
-        // Deposit txo
+        // Deposit txo, and an optional anchor txo
+        let has_anchor = utils::check_revocationtx_anchor(&psbt)?;
         let output_count = psbt.global.unsigned_tx.output.len();
-        if output_count != 1 {
+        if output_count != 1 + has_anchor as usize {
             return Err(PsbtValidationError::InvalidOutputCount(output_count).into());
         }
 
-        for output in psbt.outputs.iter() {
-            if output.bip32_derivation.is_empty() {
-                return Err(PsbtValidationError::InvalidOutputField(output.clone()).into());
-            }
-        }
-
         // Deposit txo is P2WSH
         let output = &psbt.outputs[0];
-        if output.redeem_script.is_some() {
-            return Err(PsbtValidationError::InvalidOutputField(output.clone()).into());
+        if output.bip32_derivation.is_empty() || output.redeem_script.is_some() {
+            return Err(PsbtValidationError::InvalidOutputField(Box::new(output.clone())).into());
         }
 
         let input_count = psbt.global.unsigned_tx.input.len();
@@ -183,6 +250,23 @@ impl CancelTransaction {
         Ok(CancelTransaction(psbt))
     }
 
+    /// Parse a Cancel transaction from a PSBT, checking it was created under this fee policy.
+    pub fn from_raw_psbt(
+        raw_psbt: &[u8],
+        fee_policy: &FeePolicy,
+    ) -> Result<Self, TransactionSerialisationError> {
+        let tx = CancelTransaction::parse_psbt(raw_psbt)?;
+        if tx.tx().version != fee_policy.tx_version() {
+            return Err(PsbtValidationError::InvalidTransactionVersion(tx.tx().version).into());
+        }
+        let value = tx.tx().output[0].value;
+        if value < fee_policy.dust_limit {
+            return Err(PsbtValidationError::DustOutput(value).into());
+        }
+        utils::check_revocationtx_fees(tx.psbt(), fee_policy.cancel_feerate)?;
+        Ok(tx)
+    }
+
     /// Add a signature for the input spending the Unvault transaction
     pub fn add_cancel_sig<C: secp256k1::Verification>(
         &mut self,
@@ -210,3 +294,194 @@ impl CancelTransaction {
         )
     }
 }
+
+impl CpfpableTransaction for CancelTransaction {
+    fn max_weight(&self) -> u64 {
+        utils::psbt_max_weight(self.psbt())
+    }
+}
+
+impl RevocationTransaction for CancelTransaction {
+    fn add_feebump_input(
+        &mut self,
+        feebump_input: FeeBumpTxIn,
+    ) -> Result<u64, TransactionCreationError> {
+        utils::add_feebump_input(&mut self.0, feebump_input)?;
+        Ok(self.max_feerate())
+    }
+
+    fn remove_feebump_input(&mut self) -> Option<FeeBumpTxIn> {
+        utils::remove_feebump_input(&mut self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transactions::{tests_helpers::TestDeployment, ANCHOR_VALUE, TRUC_TX_VERSION};
+
+    use miniscript::bitcoin::{consensus::encode, hashes::Hash, Script, TxOut, WPubkeyHash};
+    use std::str::FromStr;
+
+    #[test]
+    fn anchored_cancel() {
+        let secp = secp256k1::Secp256k1::new();
+        let deployment = TestDeployment::new(3, 2, false, 360_000_000, &secp);
+        let fee_policy = FeePolicy::default();
+        let (unvault_tx, cancel_tx, _, _) = deployment.chain(&fee_policy, &secp);
+        let unvault_txin =
+            unvault_tx.revault_unvault_txin(&deployment.der_unvault_descriptor(&secp));
+        let der_deposit_descriptor = deployment.der_deposit_descriptor(&secp);
+
+        // It can be created with a keyless anchor output, to be fee-bumped through CPFP
+        let anchored_cancel = CancelTransaction::new_with_anchor(
+            unvault_txin.clone(),
+            &der_deposit_descriptor,
+            0,
+            &fee_policy,
+        )
+        .unwrap();
+        assert_eq!(
+            CancelTransaction::from_raw_psbt(&anchored_cancel.as_psbt_serialized(), &fee_policy),
+            Ok(anchored_cancel.clone())
+        );
+        assert_eq!(anchored_cancel.tx().output.len(), 2);
+        assert_eq!(anchored_cancel.tx().output[1].value, ANCHOR_VALUE);
+        assert_eq!(
+            anchored_cancel.tx().output[0].value + ANCHOR_VALUE,
+            cancel_tx.tx().output[0].value - (anchored_cancel.fees() - cancel_tx.fees())
+        );
+        assert!(cancel_tx.anchor_txin().is_none());
+        assert_eq!(
+            anchored_cancel.anchor_txin().map(|txin| txin.outpoint()),
+            Some(OutPoint {
+                txid: anchored_cancel.txid(),
+                vout: 1
+            })
+        );
+
+        // It's fee-bumped through CPFP, not by adding an input
+        let feebump_txin = FeeBumpTxIn::new(
+            OutPoint::from_str(
+                "4bb4545bb4bc8853cb03e42984d677fbe880c81e7d95609360eed0d8f45b52f8:0",
+            )
+            .unwrap(),
+            FeeBumpTxOut::new(TxOut {
+                value: 1_000_000,
+                script_pubkey: Script::new_v0_wpkh(&WPubkeyHash::hash(&[0; 33])),
+            })
+            .unwrap(),
+        );
+        assert_eq!(
+            anchored_cancel
+                .clone()
+                .add_feebump_input(feebump_txin.clone()),
+            Err(TransactionCreationError::AnchoredRevocation)
+        );
+        // And only a CPFP transaction may spend an anchor
+        let mut anchor_spending_psbt = CancelTransaction::new(
+            unvault_txin,
+            Some(feebump_txin),
+            &der_deposit_descriptor,
+            0,
+            &fee_policy,
+        )
+        .unwrap()
+        .into_psbt();
+        anchor_spending_psbt.inputs[1].witness_utxo = Some(TxOut {
+            value: ANCHOR_VALUE,
+            script_pubkey: anchor_script_pubkey(),
+        });
+        assert!(matches!(
+            CancelTransaction::from_raw_psbt(
+                &encode::serialize(&anchor_spending_psbt),
+                &fee_policy
+            ),
+            Err(TransactionSerialisationError::Validation(
+                PsbtValidationError::InvalidPrevoutType(..)
+            ))
+        ));
+
+        // Anyone may spend the anchor output, it can't be given more than its value
+        let mut psbt = anchored_cancel.psbt().clone();
+        psbt.global.unsigned_tx.output[0].value -= 10_000;
+        psbt.global.unsigned_tx.output[1].value += 10_000;
+        assert!(matches!(
+            CancelTransaction::from_raw_psbt(&encode::serialize(&psbt), &fee_policy),
+            Err(TransactionSerialisationError::Validation(
+                PsbtValidationError::InvalidAnchorValue(value)
+            )) if value == ANCHOR_VALUE + 10_000
+        ));
+    }
+
+    #[test]
+    fn cancel_fee_policy() {
+        let secp = secp256k1::Secp256k1::new();
+        let deployment = TestDeployment::new(3, 2, false, 360_000_000, &secp);
+        let fee_policy = FeePolicy::default();
+        let (unvault_tx, cancel_tx, _, _) = deployment.signed_chain(&fee_policy, &secp);
+
+        // A finalized transaction can still be checked against the fee policy
+        CancelTransaction::from_raw_psbt(&cancel_tx.as_psbt_serialized(), &fee_policy)
+            .expect("Created with this fee policy");
+        let other_policy = FeePolicy {
+            cancel_feerate: fee_policy.cancel_feerate * 2,
+            ..fee_policy.clone()
+        };
+        assert!(matches!(
+            CancelTransaction::from_raw_psbt(&cancel_tx.as_psbt_serialized(), &other_policy),
+            Err(TransactionSerialisationError::Validation(
+                PsbtValidationError::InvalidFees(..)
+            ))
+        ));
+
+        // Nor one with a higher dust limit than its output value
+        let cancel_value = cancel_tx.tx().output[0].value;
+        let other_policy = FeePolicy {
+            dust_limit: cancel_value + 1,
+            ..fee_policy.clone()
+        };
+        assert_eq!(
+            CancelTransaction::from_raw_psbt(&cancel_tx.as_psbt_serialized(), &other_policy),
+            Err(TransactionSerialisationError::Validation(
+                PsbtValidationError::DustOutput(cancel_value)
+            ))
+        );
+        // Which we'd refuse to create in the first place
+        assert_eq!(
+            CancelTransaction::new(
+                unvault_tx.revault_unvault_txin(&deployment.der_unvault_descriptor(&secp)),
+                None,
+                &deployment.der_deposit_descriptor(&secp),
+                0,
+                &other_policy,
+            ),
+            Err(TransactionCreationError::Dust)
+        );
+    }
+
+    #[test]
+    fn truc_cancel() {
+        let secp = secp256k1::Secp256k1::new();
+        let deployment = TestDeployment::new(3, 2, false, 360_000_000, &secp);
+        let truc_policy = FeePolicy {
+            truc: true,
+            ..FeePolicy::default()
+        };
+        let (truc_unvault, _, _, _) = deployment.chain(&truc_policy, &secp);
+
+        // A TRUC Cancel is fee-bumped through its anchor output
+        let truc_cancel = CancelTransaction::new_with_anchor(
+            truc_unvault.revault_unvault_txin(&deployment.der_unvault_descriptor(&secp)),
+            &deployment.der_deposit_descriptor(&secp),
+            0,
+            &truc_policy,
+        )
+        .unwrap();
+        assert_eq!(truc_cancel.tx().version, TRUC_TX_VERSION);
+        assert_eq!(
+            CancelTransaction::from_raw_psbt(&truc_cancel.as_psbt_serialized(), &truc_policy),
+            Ok(truc_cancel)
+        );
+    }
+}
@@ -0,0 +1,350 @@
+//! Identification of the Revault transactions among the transactions of the network.
+
+use crate::scripts::{CpfpDescriptor, DepositDescriptor, EmergencyAddress, UnvaultDescriptor};
+
+use miniscript::{
+    bitcoin::{
+        hashes::Hash, secp256k1, util::bip32::ChildNumber, Script, Transaction, WScriptHash,
+    },
+    DescriptorTrait,
+};
+
+use std::{cmp, collections::HashMap, ops::Range};
+
+// BIP68: if this bit is set, the nSequence of the input does not encode a relative locktime
+const SEQUENCE_LOCKTIME_DISABLE_FLAG: u32 = 1 << 31;
+
+// BIP32: the derivation indexes from this one onward are hardened
+const HARDENED_INDEX: u32 = 1 << 31;
+
+/// The role of an input or an output of a transaction in a Revault deployment.
+///
+/// The role of an input is the kind of Revault transaction spending the coin: an Unvault or an
+/// Emergency for a deposit, a Cancel, an UnvaultEmergency or a Spend for an Unvault output,
+/// and a Cpfp for a CPFP output. The `index` is the derivation index of the coin spent.
+///
+/// The role of an output is the kind of coin it creates: a Deposit, the Unvault or CPFP
+/// output of an Unvault transaction, or the output of an (Unvault)Emergency transaction. The
+/// `index` is the derivation index of the descriptor it pays to, or of the coins revaulted for
+/// an Emergency output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RevaultRole {
+    /// An output paying to the deposit descriptor
+    Deposit {
+        /// The position of the output in the transaction
+        vout: u32,
+        /// The derivation index of the deposit descriptor
+        index: ChildNumber,
+    },
+    /// An input of an Unvault transaction, or its Unvault output
+    Unvault {
+        /// The derivation index
+        index: ChildNumber,
+    },
+    /// An input of a Cancel transaction
+    Cancel {
+        /// The derivation index
+        index: ChildNumber,
+    },
+    /// An input of a Spend transaction
+    Spend {
+        /// The derivation index
+        index: ChildNumber,
+    },
+    /// An input of an Emergency transaction, or its output
+    Emergency {
+        /// The derivation index
+        index: ChildNumber,
+    },
+    /// An input of an UnvaultEmergency transaction, or its output
+    UnvaultEmergency {
+        /// The derivation index
+        index: ChildNumber,
+    },
+    /// An input of a CPFP transaction, or the CPFP output of an Unvault transaction
+    Cpfp {
+        /// The derivation index
+        index: ChildNumber,
+    },
+}
+
+/// The roles of the inputs and outputs of a transaction, `None` for those unrelated to the
+/// deployment.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TransactionRoles {
+    /// The roles of the inputs, in the order of the transaction inputs
+    pub inputs: Vec<Option<RevaultRole>>,
+    /// The roles of the outputs, in the order of the transaction outputs
+    pub outputs: Vec<Option<RevaultRole>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CoinKind {
+    Deposit,
+    Unvault,
+    Cpfp,
+}
+
+/// Identifies the Revault transactions of a deployment among raw transactions.
+///
+/// The Scripts of the deployment are derived once and for all for a range of derivation
+/// indexes, coins derived at other indexes aren't recognized.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TransactionClassifier {
+    scripts: HashMap<Script, (CoinKind, ChildNumber)>,
+    emer_script: Script,
+}
+
+impl TransactionClassifier {
+    /// Derive the Scripts of the deployment for all the derivation indexes in the range. Our
+    /// descriptors can't be derived at hardened indexes, the range is therefore truncated to the
+    /// indexes below 2^31.
+    pub fn new<C: secp256k1::Verification>(
+        deposit_descriptor: &DepositDescriptor,
+        unvault_descriptor: &UnvaultDescriptor,
+        cpfp_descriptor: &CpfpDescriptor,
+        emer_address: &EmergencyAddress,
+        indexes: Range<u32>,
+        secp: &secp256k1::Secp256k1<C>,
+    ) -> TransactionClassifier {
+        let mut scripts = HashMap::new();
+        for index in indexes.start..cmp::min(indexes.end, HARDENED_INDEX) {
+            let index = ChildNumber::from(index);
+            scripts.insert(
+                deposit_descriptor
+                    .derive(index, secp)
+                    .inner()
+                    .script_pubkey(),
+                (CoinKind::Deposit, index),
+            );
+            scripts.insert(
+                unvault_descriptor
+                    .derive(index, secp)
+                    .inner()
+                    .script_pubkey(),
+                (CoinKind::Unvault, index),
+            );
+            scripts.insert(
+                cpfp_descriptor.derive(index, secp).inner().script_pubkey(),
+                (CoinKind::Cpfp, index),
+            );
+        }
+
+        TransactionClassifier {
+            scripts,
+            emer_script: emer_address.address().script_pubkey(),
+        }
+    }
+
+    /// Identify the role of each input and output of this transaction.
+    ///
+    /// The coins spent are recognized by the witness script of the inputs, which therefore
+    /// need to be signed.
+    pub fn classify(&self, tx: &Transaction) -> TransactionRoles {
+        let pays_emergency = tx
+            .output
+            .iter()
+            .any(|txo| txo.script_pubkey == self.emer_script);
+        let inputs: Vec<Option<RevaultRole>> = tx
+            .input
+            .iter()
+            .map(|txin| {
+                // The witness script of a P2WSH input is the last element of its witness
+                let witness_script = Script::from(txin.witness.last()?.clone());
+                let spent_script =
+                    Script::new_v0_wsh(&WScriptHash::hash(witness_script.as_bytes()));
+                let (kind, index) = *self.scripts.get(&spent_script)?;
+                Some(match kind {
+                    CoinKind::Deposit if pays_emergency => RevaultRole::Emergency { index },
+                    CoinKind::Deposit => RevaultRole::Unvault { index },
+                    // Only the managers' path is timelocked, the revocation transactions
+                    // don't set a relative locktime
+                    CoinKind::Unvault if txin.sequence & SEQUENCE_LOCKTIME_DISABLE_FLAG == 0 => {
+                        RevaultRole::Spend { index }
+                    }
+                    CoinKind::Unvault if pays_emergency => RevaultRole::UnvaultEmergency { index },
+                    CoinKind::Unvault => RevaultRole::Cancel { index },
+                    CoinKind::Cpfp => RevaultRole::Cpfp { index },
+                })
+            })
+            .collect();
+
+        // The Emergency outputs take the role of the inputs they revault
+        let emergency_role = inputs.iter().find_map(|role| match role {
+            Some(RevaultRole::Emergency { .. }) | Some(RevaultRole::UnvaultEmergency { .. }) => {
+                *role
+            }
+            _ => None,
+        });
+        let outputs = tx
+            .output
+            .iter()
+            .enumerate()
+            .map(|(vout, txo)| {
+                if txo.script_pubkey == self.emer_script {
+                    return emergency_role;
+                }
+                let (kind, index) = *self.scripts.get(&txo.script_pubkey)?;
+                Some(match kind {
+                    CoinKind::Deposit => RevaultRole::Deposit {
+                        vout: vout as u32,
+                        index,
+                    },
+                    CoinKind::Unvault => RevaultRole::Unvault { index },
+                    CoinKind::Cpfp => RevaultRole::Cpfp { index },
+                })
+            })
+            .collect();
+
+        TransactionRoles { inputs, outputs }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        transactions::{
+            tests_helpers::{satisfy_transaction_input, TestDeployment},
+            CpfpTransaction, CpfpableTransaction, FeePolicy, LargestFirst, RevaultTransaction,
+        },
+        txins::CpfpTxIn,
+        txouts::CpfpTxOut,
+    };
+
+    use miniscript::bitcoin::{Amount, OutPoint, SigHashType};
+    use std::str::FromStr;
+
+    #[test]
+    fn classify_transactions() {
+        let secp = secp256k1::Secp256k1::new();
+        let deployment = TestDeployment::new(3, 2, true, 360_000_000, &secp);
+        let fee_policy = FeePolicy::default();
+        let (unvault_tx, cancel_tx, emer_tx, unemer_tx) =
+            deployment.signed_chain(&fee_policy, &secp);
+        let mut spend_tx = deployment.spend_tx(&unvault_tx, &fee_policy, &secp);
+        let unsigned_spend_tx = spend_tx.clone().into_tx();
+        deployment.sign_spend(&mut spend_tx, &secp);
+        let index = deployment.child_number;
+        let cpfp_txin = unvault_tx
+            .cpfp_txin(&deployment.cpfp_descriptor, &secp)
+            .unwrap();
+        let (mut cpfp_tx, _) = CpfpTransaction::from_txins(
+            vec![cpfp_txin],
+            unvault_tx.max_weight(),
+            Amount::from_sat(unvault_tx.fees()),
+            1_000,
+            vec![CpfpTxIn::new(
+                OutPoint::from_str(
+                    "f21596dd9df36b86bcf65f0884f1f20675c1fc185bc78a37a9cddb4ae5e3dd9f:0",
+                )
+                .unwrap(),
+                CpfpTxOut::new(
+                    Amount::from_sat(500_000),
+                    &deployment.der_cpfp_descriptor(&secp),
+                ),
+            )],
+            &deployment.cpfp_descriptor.derive(11.into(), &secp),
+            false,
+            &LargestFirst,
+        )
+        .unwrap();
+        for i in 0..cpfp_tx.psbt().inputs.len() {
+            let sighash = cpfp_tx.signature_hash(i, SigHashType::All).unwrap();
+            satisfy_transaction_input(
+                &secp,
+                &mut cpfp_tx,
+                i,
+                &sighash,
+                &deployment.mancpfp_priv,
+                Some(index),
+            )
+            .unwrap();
+        }
+        cpfp_tx.finalize(&secp).unwrap();
+
+        // The (signed) transactions of the chain can be identified on the network
+        let classifier = TransactionClassifier::new(
+            &deployment.deposit_descriptor,
+            &deployment.unvault_descriptor,
+            &deployment.cpfp_descriptor,
+            &deployment.emergency_address,
+            0..20,
+            &secp,
+        );
+        assert_eq!(
+            classifier.classify(&deployment.deposit_tx.0),
+            TransactionRoles {
+                inputs: vec![None],
+                outputs: vec![Some(RevaultRole::Deposit { vout: 0, index })],
+            }
+        );
+        assert_eq!(
+            classifier.classify(&unvault_tx.clone().into_tx()),
+            TransactionRoles {
+                inputs: vec![Some(RevaultRole::Unvault { index })],
+                outputs: vec![
+                    Some(RevaultRole::Unvault { index }),
+                    Some(RevaultRole::Cpfp { index })
+                ],
+            }
+        );
+        assert_eq!(
+            classifier.classify(&cancel_tx.into_tx()),
+            TransactionRoles {
+                inputs: vec![Some(RevaultRole::Cancel { index })],
+                outputs: vec![Some(RevaultRole::Deposit { vout: 0, index })],
+            }
+        );
+        let emer_roles = classifier.classify(&emer_tx.into_tx());
+        assert_eq!(
+            emer_roles.inputs,
+            vec![Some(RevaultRole::Emergency { index })]
+        );
+        assert_eq!(emer_roles.outputs, emer_roles.inputs);
+        let unemer_roles = classifier.classify(&unemer_tx.into_tx());
+        assert_eq!(
+            unemer_roles.inputs,
+            vec![Some(RevaultRole::UnvaultEmergency { index })]
+        );
+        assert_eq!(unemer_roles.outputs, unemer_roles.inputs);
+        let spend_roles = classifier.classify(&spend_tx.into_tx());
+        assert_eq!(spend_roles.inputs, vec![Some(RevaultRole::Spend { index })]);
+        // The payment output is not part of the deployment
+        assert_eq!(
+            spend_roles.outputs,
+            vec![Some(RevaultRole::Cpfp { index }), None]
+        );
+        // As are the inputs of a CPFP spending the CPFP output
+        assert!(classifier
+            .classify(&cpfp_tx.into_tx())
+            .inputs
+            .contains(&Some(RevaultRole::Cpfp { index })));
+
+        // Unsigned inputs can't be identified
+        assert_eq!(classifier.classify(&unsigned_spend_tx).inputs, vec![None]);
+        // Nor can coins derived out of the range
+        let narrow_classifier = TransactionClassifier::new(
+            &deployment.deposit_descriptor,
+            &deployment.unvault_descriptor,
+            &deployment.cpfp_descriptor,
+            &deployment.emergency_address,
+            0..10,
+            &secp,
+        );
+        assert_eq!(
+            narrow_classifier.classify(&deployment.deposit_tx.0).outputs,
+            vec![None]
+        );
+        // Hardened indexes are ignored
+        let hardened_classifier = TransactionClassifier::new(
+            &deployment.deposit_descriptor,
+            &deployment.unvault_descriptor,
+            &deployment.cpfp_descriptor,
+            &deployment.emergency_address,
+            HARDENED_INDEX - 1..HARDENED_INDEX + 10,
+            &secp,
+        );
+        assert_eq!(hardened_classifier.scripts.len(), 3);
+    }
+}
@@ -0,0 +1,319 @@
+//! Coin selection strategies for picking the coins paying for the fees of a
+//! [CpfpTransaction](crate::transactions::CpfpTransaction).
+
+use crate::{
+    txins::{CpfpTxIn, RevaultTxIn},
+    txouts::RevaultTxOut,
+};
+
+use miniscript::bitcoin::OutPoint;
+
+use std::collections::BTreeMap;
+
+/// A coin that may be spent by the CPFP transaction.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CoinCandidate<'a> {
+    /// The coin
+    pub txin: &'a CpfpTxIn,
+    /// The value of the coin minus the fees needed to spend it at the target feerate. Coins that
+    /// cost more than they are worth are never candidates.
+    pub effective_value: u64,
+}
+
+/// What the selected coins must pay for, in sats of effective value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SelectionTarget {
+    /// The minimum sum of effective values for the transaction to reach the target feerate, its
+    /// excess value being given to miners.
+    pub changeless: u64,
+    /// The minimum sum of effective values for the transaction to reach the target feerate with
+    /// a change output.
+    pub with_change: u64,
+}
+
+/// A coin selection algorithm.
+pub trait CoinSelector {
+    /// Select the coins to spend among these candidates, returning their indexes. The sum of
+    /// their effective values must be at least the changeless target.
+    ///
+    /// Returns None if there is no acceptable selection.
+    fn select(&self, candidates: &[CoinCandidate], target: &SelectionTarget) -> Option<Vec<usize>>;
+}
+
+/// The outcome of the coin selection of a CPFP transaction.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CoinSelection {
+    /// The coins selected in addition to the ones spending the transactions to be CPFPed
+    pub selected: Vec<OutPoint>,
+    /// Whether the transaction has a change output
+    pub change: bool,
+    /// The fees paid on top of what's needed to reach the target feerate: the cost of the change
+    /// output if there is one, or the excess value given to miners otherwise.
+    pub waste: u64,
+}
+
+// Pick coins in this order until the changeless target is reached
+fn accumulate(
+    candidates: &[CoinCandidate],
+    target: &SelectionTarget,
+    order: impl Iterator<Item = usize>,
+) -> Option<Vec<usize>> {
+    let mut selected = vec![];
+    let mut sum = 0;
+
+    if target.changeless == 0 {
+        return Some(selected);
+    }
+    for i in order {
+        selected.push(i);
+        sum += candidates[i].effective_value;
+        if sum >= target.changeless {
+            return Some(selected);
+        }
+    }
+
+    None
+}
+
+/// Spend the largest coins first.
+///
+/// This minimizes the number of inputs, but fragments the wallet over time.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LargestFirst;
+
+impl CoinSelector for LargestFirst {
+    fn select(&self, candidates: &[CoinCandidate], target: &SelectionTarget) -> Option<Vec<usize>> {
+        let mut order: Vec<usize> = (0..candidates.len()).collect();
+        order.sort_by_key(|i| std::cmp::Reverse(candidates[*i].txin.txout().txout().value));
+        accumulate(candidates, target, order.into_iter())
+    }
+}
+
+/// Spend the oldest coins first, given their confirmation height. Coins of unknown confirmation
+/// height are spent last.
+///
+/// This consolidates the wallet over time.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct OldestFirst {
+    confirmation_heights: BTreeMap<OutPoint, u32>,
+}
+
+impl OldestFirst {
+    /// Select the coins by increasing confirmation height.
+    pub fn new(confirmation_heights: BTreeMap<OutPoint, u32>) -> OldestFirst {
+        OldestFirst {
+            confirmation_heights,
+        }
+    }
+}
+
+impl CoinSelector for OldestFirst {
+    fn select(&self, candidates: &[CoinCandidate], target: &SelectionTarget) -> Option<Vec<usize>> {
+        let mut order: Vec<usize> = (0..candidates.len()).collect();
+        order.sort_by_key(|i| {
+            self.confirmation_heights
+                .get(&candidates[*i].txin.outpoint())
+                .copied()
+                .unwrap_or(u32::MAX)
+        });
+        accumulate(candidates, target, order.into_iter())
+    }
+}
+
+/// Search for a selection that doesn't need a change output, wasting as little as possible.
+///
+/// This is a depth-first search of the combinations of coins, largest first, giving up after a
+/// maximum number of tries. If no selection avoids a change output, falls back to [LargestFirst].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BranchAndBound {
+    max_tries: usize,
+}
+
+impl BranchAndBound {
+    /// Search for a changeless selection for at most this number of tries.
+    pub fn new(max_tries: usize) -> BranchAndBound {
+        BranchAndBound { max_tries }
+    }
+}
+
+impl Default for BranchAndBound {
+    fn default() -> Self {
+        BranchAndBound::new(100_000)
+    }
+}
+
+impl CoinSelector for BranchAndBound {
+    fn select(&self, candidates: &[CoinCandidate], target: &SelectionTarget) -> Option<Vec<usize>> {
+        if target.changeless == 0 {
+            return Some(vec![]);
+        }
+        // Any selection below this doesn't get a change output
+        let upper_bound = target.with_change;
+
+        let mut order: Vec<usize> = (0..candidates.len()).collect();
+        order.sort_by_key(|i| std::cmp::Reverse(candidates[*i].effective_value));
+        // The sum of the effective values of the coins we haven't decided upon yet
+        let mut remaining: u64 = candidates.iter().map(|c| c.effective_value).sum();
+        if remaining < target.changeless {
+            return None;
+        }
+
+        let mut best: Option<(u64, Vec<bool>)> = None;
+        let mut selection = vec![false; order.len()];
+        let mut sum = 0;
+        let mut depth = 0;
+        let mut tries = 0;
+        loop {
+            tries += 1;
+            if tries > self.max_tries {
+                break;
+            }
+
+            // Should we go back up the tree?
+            let backtrack = if sum + remaining < target.changeless || sum >= upper_bound {
+                true
+            } else if sum >= target.changeless {
+                // A changeless solution, record it if it wastes less
+                if best.as_ref().map(|(w, _)| sum < *w) != Some(false) {
+                    best = Some((sum, selection.clone()));
+                }
+                true
+            } else {
+                depth == order.len()
+            };
+
+            if backtrack {
+                // Walk back to the last included coin, and exclude it instead
+                while depth > 0 && !selection[depth - 1] {
+                    depth -= 1;
+                    remaining += candidates[order[depth]].effective_value;
+                }
+                if depth == 0 {
+                    break;
+                }
+                selection[depth - 1] = false;
+                sum -= candidates[order[depth - 1]].effective_value;
+            } else {
+                // Try including the next coin
+                remaining -= candidates[order[depth]].effective_value;
+                selection[depth] = true;
+                sum += candidates[order[depth]].effective_value;
+                depth += 1;
+            }
+        }
+
+        match best {
+            Some((_, selection)) => Some(
+                selection
+                    .into_iter()
+                    .enumerate()
+                    .filter(|(_, included)| *included)
+                    .map(|(i, _)| order[i])
+                    .collect(),
+            ),
+            None => LargestFirst.select(candidates, target),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{scripts::CpfpDescriptor, txouts::CpfpTxOut};
+
+    use miniscript::bitcoin::{secp256k1, Amount};
+    use std::str::FromStr;
+
+    fn candidates_txins(values: &[u64]) -> Vec<CpfpTxIn> {
+        let secp = secp256k1::Secp256k1::verification_only();
+        let cpfp_descriptor = CpfpDescriptor::from_str("wsh(multi(1,xpub6BaZSKgpaVvibu2k78QsqeDWXp92xLHZxiu1WoqLB9hKhsBf3miBUDX7PJLgSPvkj66ThVHTqdnbXpeu8crXFmDUd4HeM4s4miQS2xsv3Qb/*))#6u5vnvw9").unwrap();
+        let der_cpfp_descriptor = cpfp_descriptor.derive(0.into(), &secp);
+        values
+            .iter()
+            .enumerate()
+            .map(|(i, value)| {
+                CpfpTxIn::new(
+                    OutPoint::from_str(&format!(
+                        "f21596dd9df36b86bcf65f0884f1f20675c1fc185bc78a37a9cddb4ae5e3dd9f:{}",
+                        i
+                    ))
+                    .unwrap(),
+                    CpfpTxOut::new(Amount::from_sat(*value), &der_cpfp_descriptor),
+                )
+            })
+            .collect()
+    }
+
+    fn candidates(txins: &[CpfpTxIn]) -> Vec<CoinCandidate<'_>> {
+        txins
+            .iter()
+            .map(|txin| CoinCandidate {
+                txin,
+                effective_value: txin.txout().txout().value - 1_000,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn coin_selection() {
+        let txins = candidates_txins(&[11_000, 4_000, 6_000, 31_000, 8_000]);
+        let candidates = candidates(&txins);
+        let target = SelectionTarget {
+            changeless: 12_000,
+            with_change: 13_000,
+        };
+
+        assert_eq!(LargestFirst.select(&candidates, &target), Some(vec![3]));
+
+        let heights = vec![
+            (txins[4].outpoint(), 10),
+            (txins[2].outpoint(), 12),
+            (txins[0].outpoint(), 11),
+        ]
+        .into_iter()
+        .collect();
+        assert_eq!(
+            OldestFirst::new(heights).select(&candidates, &target),
+            Some(vec![4, 0])
+        );
+
+        // 10_000 + 3_000 is too much, but 5_000 + 7_000 is right within the window
+        let selection = BranchAndBound::default()
+            .select(&candidates, &target)
+            .unwrap();
+        let sum: u64 = selection
+            .iter()
+            .map(|i| candidates[*i].effective_value)
+            .sum();
+        assert!(sum >= target.changeless && sum < target.with_change);
+        assert!(BranchAndBound::default()
+            .select(
+                &candidates,
+                &SelectionTarget {
+                    changeless: 100_000,
+                    with_change: 110_000,
+                }
+            )
+            .is_none());
+        // No combination fits in 31_000..31_100, fall back to a selection with change
+        let target = SelectionTarget {
+            changeless: 31_000,
+            with_change: 31_100,
+        };
+        assert_eq!(
+            BranchAndBound::default().select(&candidates, &target),
+            Some(vec![3, 0])
+        );
+        // Nothing to select
+        assert_eq!(
+            BranchAndBound::default().select(
+                &candidates,
+                &SelectionTarget {
+                    changeless: 0,
+                    with_change: 10_000,
+                }
+            ),
+            Some(vec![])
+        );
+    }
+}
@@ -1,17 +1,30 @@
 use crate::{
     error::*,
-    transactions::{utils, CPFP_MIN_CHANGE},
+    scripts::{anchor_script_pubkey, DerivedCpfpDescriptor},
+    transactions::{
+        utils, CoinCandidate, CoinSelection, CoinSelector, LargestFirst, RevaultTransaction,
+        SelectionTarget, CPFP_MIN_CHANGE, TRUC_CHILD_MAX_WEIGHT, TRUC_TX_VERSION,
+    },
     txins::*,
     txouts::*,
 };
 
 use miniscript::bitcoin::{
-    consensus::encode,
-    util::psbt::{Global as PsbtGlobal, Input as PsbtIn, PartiallySignedTransaction as Psbt},
-    Amount, Script, SigHashType, Transaction, TxIn,
+    consensus::encode::Decodable,
+    util::psbt::{
+        Global as PsbtGlobal, Input as PsbtIn, Output as PsbtOut,
+        PartiallySignedTransaction as Psbt,
+    },
+    Amount, Script, SigHashType, Transaction, TxIn, TxOut,
 };
 
-use std::{convert::TryInto, fmt};
+#[cfg(feature = "use-serde")]
+use {
+    serde::de::{self, Deserialize, Deserializer},
+    serde::ser::{Serialize, Serializer},
+};
+
+use std::{cmp, collections::BTreeMap, convert::TryInto};
 
 // If single-input single-output we need this many dummy vbytes to keep our transaction above the
 // minimum standard size.
@@ -33,82 +46,285 @@ fn op_return_script(cpfp_psbt: &Psbt) -> Script {
     }
 }
 
-/// The transaction spending the Unvault and Spend transactions' CPFP output in order
-/// to bump their fees.
-#[derive(Clone, PartialEq)]
-pub struct CpfpTransaction(Psbt);
+// The weight of a txin without its witness: outpoint, empty scriptSig and nSequence
+const TXIN_BASE_WEIGHT: u64 = 4 * (32 + 4 + 1 + 4);
+
+// The maximum satisfaction weight of a CPFP txin
+fn cpfp_txin_sat_weight(cpfp_txin: &CpfpTxIn) -> u64 {
+    cpfp_txin
+        .txout()
+        .max_sat_weight()
+        .try_into()
+        .expect("Weight doesn't fit in u64?")
+}
+
+// The unsigned txin spending a CPFP txo, signaling for RBF
+fn cpfp_unsigned_txin(cpfp_txin: &CpfpTxIn) -> TxIn {
+    TxIn {
+        previous_output: cpfp_txin.outpoint(),
+        sequence: RBF_SEQUENCE,
+        script_sig: Script::new(),
+        witness: vec![],
+    }
+}
+
+// The dummy change output paying to this descriptor, and the PSBT output with its derivation info
+fn cpfp_change(change_descriptor: &DerivedCpfpDescriptor) -> (TxOut, PsbtOut) {
+    let change_txo = CpfpTxOut::new(Amount::from_sat(0), change_descriptor);
+    let psbtout = PsbtOut {
+        bip32_derivation: change_txo.bip32_derivation().clone(),
+        ..PsbtOut::default()
+    };
+    (change_txo.into_txout(), psbtout)
+}
 
-// A custom implementation of Debug to make it more compact in logs
-impl fmt::Debug for CpfpTransaction {
-    fn fmt(&self, f: &mut fmt::Formatter) -> std::fmt::Result {
-        write!(f, "{}", base64::encode(encode::serialize(&self.0)))
+// The PSBT input spending a CPFP txo
+fn cpfp_psbtin(cpfp_txin: CpfpTxIn) -> PsbtIn {
+    PsbtIn {
+        witness_script: Some(cpfp_txin.txout().witness_script().clone()),
+        bip32_derivation: cpfp_txin.txout().bip32_derivation().clone(),
+        sighash_type: Some(SigHashType::All),
+        witness_utxo: Some(cpfp_txin.into_txout().into_txout()),
+        ..PsbtIn::default()
     }
 }
 
-impl fmt::Display for CpfpTransaction {
-    fn fmt(&self, f: &mut fmt::Formatter) -> std::fmt::Result {
-        write!(f, "{:?}", &self)
+// The incremental relay feerate, in sat/kWU, a replacement must pay for its own weight on top
+// of the fees of the transaction it replaces (BIP125 rule 4).
+const INCREMENTAL_RELAY_FEERATE: u64 = 250;
+
+// The minimum fees a replacement of this weight must pay, if it's one (BIP125 rules 3 and 4).
+fn replacement_min_fees(replaced_fees: Option<u64>, weight: u64) -> u64 {
+    replaced_fees
+        .map(|fees| fees + (INCREMENTAL_RELAY_FEERATE * weight + 999) / 1_000)
+        .unwrap_or(0)
+}
+
+// A TRUC child may only have a single unconfirmed parent: the transaction it CPFPs. Therefore it
+// may only add confirmed coins.
+fn confirmed_if_truc(mut available_utxos: Vec<CpfpTxIn>, truc: bool) -> Vec<CpfpTxIn> {
+    if truc {
+        available_utxos.retain(|utxo| utxo.is_confirmed());
     }
+    available_utxos
+}
+
+// What a CPFP transaction is bumping, and by how much
+struct CpfpTarget {
+    tbc_weight: u64,
+    tbc_fees: Amount,
+    added_feerate: u64,
+    // The fees of the transaction we are replacing, if any
+    replaced_fees: Option<u64>,
+    truc: bool,
 }
 
+impl CpfpTarget {
+    // The feerate of the package we target, in sat/kWU
+    fn feerate(&self) -> u64 {
+        let tbc_feerate = 1_000 * (self.tbc_fees.as_sat() + self.tbc_weight) / self.tbc_weight; // * 1000 for kWU
+        tbc_feerate + self.added_feerate
+    }
+
+    // The fees the CPFP transaction must pay for the package to reach the target feerate, with
+    // its output as a change output and as an OP_RETURN.
+    fn fees_needed(&self, psbt: &Psbt, total_satisfation_weight: u64) -> (u64, u64) {
+        let fees_needed = |tx_weight: u64| {
+            let package_weight = tx_weight + total_satisfation_weight + self.tbc_weight;
+            // /1000 to get sats/WU (rounded down) from sats/kWU
+            let fees =
+                (self.feerate() * package_weight / 1000).saturating_sub(self.tbc_fees.as_sat());
+            cmp::max(
+                fees,
+                replacement_min_fees(self.replaced_fees, tx_weight + total_satisfation_weight),
+            )
+        };
+
+        let cpfp_weight: u64 = psbt
+            .global
+            .unsigned_tx
+            .get_weight()
+            .try_into()
+            .expect("Weight doesn't fit in u64?");
+        // Here we calculate the fees needed if we used OP_RETURN instead of p2wsh
+        // as output
+        let mut op_return_tx = psbt.global.unsigned_tx.clone();
+        op_return_tx.output[0].script_pubkey = op_return_script(psbt);
+        op_return_tx.output[0].value = 0;
+        let opr_tx_weight: u64 = op_return_tx
+            .get_weight()
+            .try_into()
+            .expect("Weight doesn't fit in u64?");
+
+        (fees_needed(cpfp_weight), fees_needed(opr_tx_weight))
+    }
+}
+
+impl_revault_transaction!(
+    CpfpTransaction,
+    doc = "The transaction spending the Unvault and Spend transactions' CPFP output, or the revocation transactions' anchor output, in order to bump their fees."
+);
 impl CpfpTransaction {
     /// Create a CPFP tx to bump a set of transactions by a specified feerate.
     /// The current implementation will return a CPFP tx that either pays to a 0-value OP_RETURN
-    /// output or to a change output paying to the same script. It will select UTxOs to consume
-    /// using a largest first CS and will error if not enough UTxOs are provided to cover the expected feerate.
+    /// output or to a change output paying to the `change_descriptor`, whose derivation info is
+    /// set in the PSBT output. It will select UTxOs to consume
+    /// using the given coin selection algorithm and will error if it can't find enough UTxOs to
+    /// cover the expected feerate. Along with the transaction, it returns the outcome of the coin
+    /// selection including its waste.
     /// NOTE: we assume all available UTxOs to be CPFP txouts from other Unvault transactions.
-    // FIXME: Avoid largest first CS and be smarter! Instead, go with:
-    // - calculate the fees we need to cover before being ok
-    // - if the biggest coin is less than the fees, take the biggest coin
-    // - otherwise, take the smallest coin big enough to cover fees
-    pub fn from_txins(
+    ///
+    /// If `truc` is set, the transactions to be CPFPed must be TRUC transactions and the CPFP tx
+    /// is created as their TRUC child. As such a child may only have a single unconfirmed parent,
+    /// all the txins to be CPFPed must spend the same transaction and only the confirmed
+    /// available UTxOs are considered. It will also error if the CPFP tx would exceed the TRUC
+    /// child size limit.
+    #[allow(clippy::too_many_arguments)]
+    pub fn from_txins<S: CoinSelector>(
         to_be_cpfped: Vec<CpfpTxIn>,
         tbc_weight: u64,
         tbc_fees: Amount,
         added_feerate: u64,
-        mut available_utxos: Vec<CpfpTxIn>,
-    ) -> Result<CpfpTransaction, TransactionCreationError> {
+        available_utxos: Vec<CpfpTxIn>,
+        change_descriptor: &DerivedCpfpDescriptor,
+        truc: bool,
+        coin_selector: &S,
+    ) -> Result<(CpfpTransaction, CoinSelection), TransactionCreationError> {
         assert!(!to_be_cpfped.is_empty());
-        // This will sort the vector in ascending order.
-        // Since we're going to pop() from it, we're using a largest first CS.
-        available_utxos.sort_unstable_by_key(|l| l.txout().txout().value);
+        if truc
+            && to_be_cpfped
+                .iter()
+                .any(|txin| txin.outpoint().txid != to_be_cpfped[0].outpoint().txid)
+        {
+            return Err(TransactionCreationError::TrucViolation);
+        }
+        let available_utxos = confirmed_if_truc(available_utxos, truc);
 
         let mut txins = vec![];
         let mut psbtins = vec![];
-        let mut dummy_change = None;
         let mut inputs_sum = Amount::from_sat(0);
         let mut total_satisfation_weight = 0;
 
         for cpfp_txin in to_be_cpfped {
-            dummy_change = Some(cpfp_txin.txout().txout().clone());
             inputs_sum += Amount::from_sat(cpfp_txin.txout().txout().value);
-            // I can't collapse this in one call (total_satisfation_weight += ...)
-            // as I have a "cannot infer type"
-            let w: u64 = cpfp_txin
+            total_satisfation_weight += cpfp_txin_sat_weight(&cpfp_txin);
+            txins.push(cpfp_unsigned_txin(&cpfp_txin));
+            psbtins.push(cpfp_psbtin(cpfp_txin));
+        }
+
+        let (dummy_change, change_psbtout) = cpfp_change(change_descriptor);
+        CpfpTransaction::select_coins(
+            txins,
+            psbtins,
+            dummy_change,
+            change_psbtout,
+            inputs_sum,
+            total_satisfation_weight,
+            CpfpTarget {
+                tbc_weight,
+                tbc_fees,
+                added_feerate,
+                replaced_fees: None,
+                truc,
+            },
+            available_utxos,
+            coin_selector,
+        )
+    }
+
+    /// Create a CPFP tx to bump a set of transactions by a specified feerate, by spending their
+    /// keyless anchor outputs.
+    /// As the anchors' value is too small to pay for the fees, it will always consume at least
+    /// the largest of the available UTxOs. Otherwise it behaves as [CpfpTransaction::from_txins]
+    /// with a [LargestFirst] coin selection, including regarding the change output (never an
+    /// anchor) and the TRUC restrictions.
+    pub fn from_anchors(
+        to_be_cpfped: Vec<AnchorTxIn>,
+        tbc_weight: u64,
+        tbc_fees: Amount,
+        added_feerate: u64,
+        available_utxos: Vec<CpfpTxIn>,
+        change_descriptor: &DerivedCpfpDescriptor,
+        truc: bool,
+    ) -> Result<CpfpTransaction, TransactionCreationError> {
+        assert!(!to_be_cpfped.is_empty());
+        if truc
+            && to_be_cpfped
+                .iter()
+                .any(|txin| txin.outpoint().txid != to_be_cpfped[0].outpoint().txid)
+        {
+            return Err(TransactionCreationError::TrucViolation);
+        }
+        let mut available_utxos = confirmed_if_truc(available_utxos, truc);
+        // Sorted in ascending order, we pop() the largest one.
+        available_utxos.sort_unstable_by_key(|l| l.txout().txout().value);
+
+        let mut txins = vec![];
+        let mut psbtins = vec![];
+        let mut inputs_sum = Amount::from_sat(0);
+        let mut total_satisfation_weight = 0;
+
+        for anchor_txin in to_be_cpfped {
+            inputs_sum += Amount::from_sat(anchor_txin.txout().txout().value);
+            let w: u64 = anchor_txin
                 .txout()
                 .max_sat_weight()
                 .try_into()
                 .expect("Weight doesn't fit in u64?");
             total_satisfation_weight += w;
-            txins.push(TxIn {
-                previous_output: cpfp_txin.outpoint(),
-                sequence: RBF_SEQUENCE,
-                script_sig: Script::new(),
-                witness: vec![],
-            });
+            txins.push(anchor_txin.unsigned_txin());
+            // Keyless: there is nothing to sign
             psbtins.push(PsbtIn {
-                witness_script: Some(cpfp_txin.txout().witness_script().clone()),
-                bip32_derivation: cpfp_txin.txout().bip32_derivation().clone(),
-                sighash_type: Some(SigHashType::All),
-                witness_utxo: Some(cpfp_txin.into_txout().into_txout()),
+                witness_utxo: Some(anchor_txin.into_txout().into_txout()),
                 ..PsbtIn::default()
             });
         }
 
-        let dummy_change = dummy_change.expect("Must be initialized in the loop");
+        let largest_txin = available_utxos
+            .pop()
+            .ok_or(TransactionCreationError::InsufficientFunds)?;
+        inputs_sum += Amount::from_sat(largest_txin.txout().txout().value);
+        total_satisfation_weight += cpfp_txin_sat_weight(&largest_txin);
+        txins.push(cpfp_unsigned_txin(&largest_txin));
+        psbtins.push(cpfp_psbtin(largest_txin));
 
+        let (dummy_change, change_psbtout) = cpfp_change(change_descriptor);
+        CpfpTransaction::select_coins(
+            txins,
+            psbtins,
+            dummy_change,
+            change_psbtout,
+            inputs_sum,
+            total_satisfation_weight,
+            CpfpTarget {
+                tbc_weight,
+                tbc_fees,
+                added_feerate,
+                replaced_fees: None,
+                truc,
+            },
+            available_utxos,
+            &LargestFirst,
+        )
+        .map(|(tx, _)| tx)
+    }
+
+    // Internal routine adding coins from the available UTxOs to the initial inputs, as chosen by
+    // the coin selector, for the package to reach the target feerate.
+    #[allow(clippy::too_many_arguments)]
+    fn select_coins<S: CoinSelector>(
+        txins: Vec<TxIn>,
+        psbtins: Vec<PsbtIn>,
+        dummy_change: TxOut,
+        change_psbtout: PsbtOut,
+        mut inputs_sum: Amount,
+        mut total_satisfation_weight: u64,
+        target: CpfpTarget,
+        mut available_utxos: Vec<CpfpTxIn>,
+        coin_selector: &S,
+    ) -> Result<(CpfpTransaction, CoinSelection), TransactionCreationError> {
         let transaction = Transaction {
-            version: 2,
+            version: utils::tx_version(target.truc),
             lock_time: 0,
             input: txins,
             output: vec![dummy_change],
@@ -117,107 +333,912 @@ impl CpfpTransaction {
         let mut psbt = Psbt {
             global: PsbtGlobal::from_unsigned_tx(transaction).expect("unsigned"),
             inputs: psbtins,
-            outputs: vec![Default::default()],
+            outputs: vec![change_psbtout],
         };
 
-        // We discard the CPFP descriptors in to_be_cpfped as we don't need them anymore
-        let tbc_feerate = 1_000 * (tbc_fees.as_sat() + tbc_weight) / tbc_weight; // * 1000 for kWU
+        // The fees each additional coin needs to pay for its own weight. They are rounded up, and
+        // the most constraining of the feerates is used if we are a replacement, so that a
+        // selection reaching the target with these is enough to reach the exact target.
+        let coin_feerate = if target.replaced_fees.is_some() {
+            cmp::max(target.feerate(), INCREMENTAL_RELAY_FEERATE)
+        } else {
+            target.feerate()
+        };
+        let coin_fees = |utxo: &CpfpTxIn| {
+            (coin_feerate * (TXIN_BASE_WEIGHT + cpfp_txin_sat_weight(utxo)) + 999) / 1_000
+        };
+        // Don't bother with coins costing more than they are worth
+        available_utxos.retain(|utxo| utxo.txout().txout().value > coin_fees(utxo));
+        let candidates: Vec<CoinCandidate> = available_utxos
+            .iter()
+            .map(|txin| CoinCandidate {
+                txin,
+                effective_value: txin.txout().txout().value - coin_fees(txin),
+            })
+            .collect();
 
-        let target_feerate = tbc_feerate + added_feerate;
-        loop {
-            let cpfp_weight: u64 = psbt
-                .global
+        let (fees_needed, op_return_fees_needed) =
+            target.fees_needed(&psbt, total_satisfation_weight);
+        let selection_target = SelectionTarget {
+            changeless: (op_return_fees_needed + 1).saturating_sub(inputs_sum.as_sat()),
+            with_change: (fees_needed + CPFP_MIN_CHANGE + 1).saturating_sub(inputs_sum.as_sat()),
+        };
+        let selected = coin_selector
+            .select(&candidates, &selection_target)
+            .ok_or(TransactionCreationError::InsufficientFunds)?;
+
+        let mut selected_outpoints = Vec::with_capacity(selected.len());
+        for i in selected {
+            let new_input = &available_utxos[i];
+            selected_outpoints.push(new_input.outpoint());
+            inputs_sum += Amount::from_sat(new_input.txout().txout().value);
+            total_satisfation_weight += cpfp_txin_sat_weight(new_input);
+            psbt.global
                 .unsigned_tx
-                .get_weight()
-                .try_into()
-                .expect("Weight doesn't fit in u64?");
-            let package_weight = cpfp_weight + total_satisfation_weight + tbc_weight;
-            let fees_needed = Amount::from_sat(
-                // /1000 to get sats/WU (rounded down) from sats/kWU
-                target_feerate * package_weight / 1000,
-            ) - tbc_fees;
-
-            // Here we calculate the fees needed if we used OP_RETURN instead of p2wsh
-            // as output
-            let mut op_return_tx = psbt.global.unsigned_tx.clone();
-            op_return_tx.output[0].script_pubkey = op_return_script(&psbt);
-            op_return_tx.output[0].value = 0;
-            let opr_tx_weight: u64 = op_return_tx
-                .get_weight()
-                .try_into()
-                .expect("Weight doesn't fit in u64?");
-            let opr_package_weight = opr_tx_weight + total_satisfation_weight + tbc_weight;
-            let op_return_fees_needed = Amount::from_sat(
-                // /1000 to get sats/WU (rounded down) from sats/kWU
-                target_feerate * opr_package_weight / 1000,
-            ) - tbc_fees;
-
-            if inputs_sum > fees_needed || inputs_sum > op_return_fees_needed {
-                // Alright, we found it!
-                if inputs_sum > fees_needed && (inputs_sum - fees_needed).as_sat() > CPFP_MIN_CHANGE
-                {
-                    // If it makes sense to have a change, let's have a change :)
-                    let change = &mut psbt.global.unsigned_tx.output[0];
-                    change.value = (inputs_sum - fees_needed).as_sat();
-                } else {
-                    // Otherwise, stick with OP_RETURN
-                    let opr = op_return_script(&psbt);
-                    let change = &mut psbt.global.unsigned_tx.output[0];
-                    change.value = 0;
-                    change.script_pubkey = opr;
+                .input
+                .push(cpfp_unsigned_txin(new_input));
+            psbt.inputs.push(cpfp_psbtin(new_input.clone()));
+        }
+
+        // A TRUC child is restricted to a smaller size
+        let cpfp_weight: u64 = psbt
+            .global
+            .unsigned_tx
+            .get_weight()
+            .try_into()
+            .expect("Weight doesn't fit in u64?");
+        if target.truc && cpfp_weight + total_satisfation_weight > TRUC_CHILD_MAX_WEIGHT {
+            return Err(TransactionCreationError::TrucViolation);
+        }
+
+        // Now get the exact fees we need, and whether it makes sense to have a change
+        let inputs_sum = inputs_sum.as_sat();
+        let (fees_needed, op_return_fees_needed) =
+            target.fees_needed(&psbt, total_satisfation_weight);
+        if inputs_sum <= fees_needed && inputs_sum <= op_return_fees_needed {
+            return Err(TransactionCreationError::InsufficientFunds);
+        }
+        let change = inputs_sum > fees_needed && inputs_sum - fees_needed > CPFP_MIN_CHANGE;
+        let waste = if change {
+            // If it makes sense to have a change, let's have a change :)
+            let change = &mut psbt.global.unsigned_tx.output[0];
+            change.value = inputs_sum - fees_needed;
+            fees_needed.saturating_sub(op_return_fees_needed)
+        } else {
+            // Otherwise, stick with OP_RETURN
+            let opr = op_return_script(&psbt);
+            let change = &mut psbt.global.unsigned_tx.output[0];
+            change.value = 0;
+            change.script_pubkey = opr;
+            psbt.outputs[0] = PsbtOut::default();
+            inputs_sum - op_return_fees_needed
+        };
+
+        Ok((
+            CpfpTransaction(psbt),
+            CoinSelection {
+                selected: selected_outpoints,
+                change,
+                waste,
+            },
+        ))
+    }
+
+    /// Create a transaction replacing this one (as per BIP125) in order to bump the transactions it
+    /// CPFPs, of total `tbc_weight` and `tbc_fees`, by `new_added_feerate` (in sat/kWU) instead.
+    ///
+    /// The replacement spends all the inputs of this transaction, thereby CPFPing the same
+    /// transactions, plus as many of the `extra_utxos` as needed (largest first). As it may only
+    /// add confirmed inputs (BIP125 rule 2), the unconfirmed ones are not considered. It pays more
+    /// fees than this transaction, in addition to the incremental relay fee for its own weight.
+    /// Any change goes to the `change_descriptor`.
+    ///
+    /// A finalized transaction lost the witness Scripts and derivation paths of the CPFP txos it
+    /// spends, these txins must then be part of the `extra_utxos`. Will error with
+    /// [TransactionCreationError::InvalidReplacement] if they are not, and with
+    /// [TransactionCreationError::InsufficientFunds] if the rules can't be met with these coins.
+    pub fn bump(
+        &self,
+        tbc_weight: u64,
+        tbc_fees: Amount,
+        new_added_feerate: u64,
+        mut extra_utxos: Vec<CpfpTxIn>,
+        change_descriptor: &DerivedCpfpDescriptor,
+    ) -> Result<CpfpTransaction, TransactionCreationError> {
+        let psbt = self.psbt();
+
+        // Spend the very same inputs, without their signatures
+        let anchor_spk = anchor_script_pubkey();
+        let mut txins = Vec::with_capacity(psbt.inputs.len());
+        let mut psbtins = Vec::with_capacity(psbt.inputs.len());
+        let mut inputs_sum = Amount::from_sat(0);
+        let mut total_satisfation_weight = 0;
+        for (txin, psbtin) in self.tx().input.iter().zip(psbt.inputs.iter()) {
+            let prev_txo = psbtin
+                .witness_utxo
+                .as_ref()
+                .expect("Checked at creation or parsing");
+            inputs_sum += Amount::from_sat(prev_txo.value);
+            let psbtin = if prev_txo.script_pubkey == anchor_spk {
+                total_satisfation_weight += 1;
+                PsbtIn {
+                    witness_utxo: Some(prev_txo.clone()),
+                    ..PsbtIn::default()
                 }
-                return Ok(CpfpTransaction(psbt));
             } else {
-                // Let's add another input and start again
-                match available_utxos.pop() {
-                    Some(new_input) => {
-                        psbt.global.unsigned_tx.input.push(TxIn {
-                            previous_output: new_input.outpoint(),
-                            script_sig: Script::new(),
-                            sequence: RBF_SEQUENCE,
-                            witness: vec![],
-                        });
-
-                        let bip32_derivation = new_input.txout().clone().into_bip32_derivation();
-
-                        psbt.inputs.push(PsbtIn {
-                            witness_utxo: Some(new_input.txout().txout().clone()),
-                            witness_script: Some(new_input.txout().clone().into_witness_script()),
-                            sighash_type: Some(SigHashType::All),
-                            bip32_derivation,
-                            ..Default::default()
-                        });
-                        inputs_sum += Amount::from_sat(new_input.txout().txout().value);
-
-                        let input_sat_weight: u64 = new_input
-                            .txout()
-                            .max_sat_weight()
-                            .try_into()
-                            .expect("Weight doesn't fit in u64?");
-                        total_satisfation_weight += input_sat_weight;
-                    }
-                    None => {
-                        return Err(TransactionCreationError::InsufficientFunds);
+                let psbtin = if psbtin.final_script_witness.is_some() {
+                    let position = extra_utxos
+                        .iter()
+                        .position(|utxo| utxo.outpoint() == txin.previous_output)
+                        .ok_or(TransactionCreationError::InvalidReplacement)?;
+                    cpfp_psbtin(extra_utxos.swap_remove(position))
+                } else {
+                    PsbtIn {
+                        partial_sigs: BTreeMap::new(),
+                        ..psbtin.clone()
                     }
+                };
+                total_satisfation_weight += utils::input_max_sat_weight(&psbtin)
+                    .map_err(|_| TransactionCreationError::InvalidReplacement)?;
+                psbtin
+            };
+            txins.push(TxIn {
+                previous_output: txin.previous_output,
+                sequence: RBF_SEQUENCE,
+                script_sig: Script::new(),
+                witness: vec![],
+            });
+            psbtins.push(psbtin);
+        }
+        let (dummy_change, change_psbtout) = cpfp_change(change_descriptor);
+
+        // We already spend some of them, maybe. And we can't add unconfirmed ones.
+        extra_utxos.retain(|utxo| {
+            utxo.is_confirmed() && !txins.iter().any(|t| t.previous_output == utxo.outpoint())
+        });
+
+        CpfpTransaction::select_coins(
+            txins,
+            psbtins,
+            dummy_change,
+            change_psbtout,
+            inputs_sum,
+            total_satisfation_weight,
+            CpfpTarget {
+                tbc_weight,
+                tbc_fees,
+                added_feerate: new_added_feerate,
+                replaced_fees: Some(self.fees()),
+                truc: self.tx().version == TRUC_TX_VERSION,
+            },
+            extra_utxos,
+            &LargestFirst,
+        )
+        .map(|(tx, _)| tx)
+    }
+
+    /// Parse a CPFP transaction from a PSBT
+    pub fn from_raw_psbt(raw_psbt: &[u8]) -> Result<Self, TransactionSerialisationError> {
+        let psbt = Decodable::consensus_decode(raw_psbt)?;
+        let psbt = utils::psbt_common_sanity_checks(psbt, true, Some(TRUC_CHILD_MAX_WEIGHT))?;
+
+        // Either a change output or an OP_RETURN
+        let output_count = psbt.global.unsigned_tx.output.len();
+        if output_count != 1 {
+            return Err(PsbtValidationError::InvalidOutputCount(output_count).into());
+        }
+
+        // Every input must be spending a CPFP txo, except the keyless anchors of the revocation
+        // transactions.
+        let anchor_spk = anchor_script_pubkey();
+        let mut cpfp_txo_count = 0;
+        for input in psbt.inputs.iter() {
+            let spk = &input
+                .witness_utxo
+                .as_ref()
+                .expect("Checked in sanity checks")
+                .script_pubkey;
+
+            if spk == &anchor_spk {
+                // There is nothing to sign
+                if input.sighash_type.is_some()
+                    || !input.bip32_derivation.is_empty()
+                    || !input.partial_sigs.is_empty()
+                {
+                    return Err(
+                        PsbtValidationError::InvalidInputField(Box::new(input.clone())).into(),
+                    );
+                }
+                continue;
+            }
+
+            if !spk.is_v0_p2wsh() {
+                return Err(
+                    PsbtValidationError::InvalidPrevoutType(Box::new(input.clone())).into(),
+                );
+            }
+            cpfp_txo_count += 1;
+
+            // The witness Script was checked against the scriptPubKey in the sanity checks
+            if input.final_script_witness.is_none() {
+                if input.sighash_type != Some(SigHashType::All) {
+                    return Err(
+                        PsbtValidationError::InvalidSighashType(Box::new(input.clone())).into(),
+                    );
+                }
+
+                if input.bip32_derivation.is_empty() {
+                    return Err(
+                        PsbtValidationError::InvalidInputField(Box::new(input.clone())).into(),
+                    );
                 }
             }
         }
+        // We always spend at least one CPFP txo
+        if cpfp_txo_count == 0 {
+            let input_count = psbt.global.unsigned_tx.input.len();
+            return Err(PsbtValidationError::InvalidInputCount(input_count).into());
+        }
+
+        // A TRUC child has a single unconfirmed parent, hence all the anchors it spends must be
+        // from the same transaction. Its size was checked in the sanity checks.
+        if psbt.global.unsigned_tx.version == TRUC_TX_VERSION {
+            let mut anchor_inputs = psbt
+                .global
+                .unsigned_tx
+                .input
+                .iter()
+                .zip(psbt.inputs.iter())
+                .filter(|(_, psbtin)| {
+                    psbtin.witness_utxo.as_ref().map(|txo| &txo.script_pubkey) == Some(&anchor_spk)
+                });
+            if let Some((first_txin, _)) = anchor_inputs.next() {
+                let parent_txid = first_txin.previous_output.txid;
+                if let Some((_, psbtin)) =
+                    anchor_inputs.find(|(txin, _)| txin.previous_output.txid != parent_txid)
+                {
+                    return Err(
+                        PsbtValidationError::InvalidPrevoutType(Box::new(psbtin.clone())).into(),
+                    );
+                }
+            }
+        }
+
+        Ok(CpfpTransaction(psbt))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transactions::{
+        tests_helpers::{satisfy_transaction_input, TestDeployment},
+        BranchAndBound, CancelTransaction, CpfpableTransaction, FeePolicy, SpendTransaction,
+        UnvaultEmergencyTransaction,
+    };
+
+    use miniscript::{
+        bitcoin::{consensus::encode, secp256k1, util::bip32, OutPoint},
+        DescriptorTrait,
+    };
+    use std::str::FromStr;
+
+    fn coins(values: &[u64], cpfp_descriptor: &DerivedCpfpDescriptor) -> Vec<CpfpTxIn> {
+        values
+            .iter()
+            .enumerate()
+            .map(|(i, value)| {
+                CpfpTxIn::new(
+                    OutPoint::from_str(&format!(
+                        "f21596dd9df36b86bcf65f0884f1f20675c1fc185bc78a37a9cddb4ae5e3dd9f:{}",
+                        i
+                    ))
+                    .unwrap(),
+                    CpfpTxOut::new(Amount::from_sat(*value), cpfp_descriptor),
+                )
+            })
+            .collect()
+    }
+
+    // A CPFP transaction for the Spend of the deployment, out of two fake listunspent coins
+    fn spend_cpfp(
+        deployment: &TestDeployment,
+        secp: &secp256k1::Secp256k1<secp256k1::All>,
+    ) -> (SpendTransaction, CpfpTransaction) {
+        let fee_policy = FeePolicy::default();
+        let (unvault_tx, _, _, _) = deployment.chain(&fee_policy, secp);
+        let spend_tx = deployment.spend_tx(&unvault_tx, &fee_policy, secp);
+        let change_descriptor = deployment
+            .cpfp_descriptor
+            .derive(bip32::ChildNumber::from(11), secp);
+        let cpfp_txin = spend_tx
+            .cpfp_txin(&deployment.cpfp_descriptor, secp)
+            .unwrap();
+        let (cpfp_tx, _) = CpfpTransaction::from_txins(
+            vec![cpfp_txin],
+            spend_tx.max_weight(),
+            Amount::from_sat(spend_tx.fees()),
+            6121,
+            coins(&[58_000, 23_000], &deployment.der_cpfp_descriptor(secp)),
+            &change_descriptor,
+            false,
+            &LargestFirst,
+        )
+        .unwrap();
+
+        (spend_tx, cpfp_tx)
+    }
+
+    #[test]
+    fn cpfp_coin_selection() {
+        let secp = secp256k1::Secp256k1::new();
+        let deployment = TestDeployment::new(3, 2, false, 360_000_000, &secp);
+        let fee_policy = FeePolicy::default();
+        let (unvault_tx, _, _, _) = deployment.chain(&fee_policy, &secp);
+        let der_cpfp_descriptor = deployment.der_cpfp_descriptor(&secp);
+        let change_descriptor = deployment
+            .cpfp_descriptor
+            .derive(bip32::ChildNumber::from(11), &secp);
+        let cpfp_txin = unvault_tx
+            .cpfp_txin(&deployment.cpfp_descriptor, &secp)
+            .unwrap();
+        let tbc_weight = unvault_tx.max_weight();
+        let tbc_fees = Amount::from_sat(unvault_tx.fees());
+
+        // Bump it by 50sat/WU, which the coins to be CPFPed can't pay for
+        let added_feerate = 50_000;
+        let small_utxos = coins(
+            &[23_000, 31_000, 47_000, 59_000, 71_000],
+            &der_cpfp_descriptor,
+        );
+        let (lf_cpfp_tx, lf_selection) = CpfpTransaction::from_txins(
+            vec![cpfp_txin.clone()],
+            tbc_weight,
+            tbc_fees,
+            added_feerate,
+            small_utxos.clone(),
+            &change_descriptor,
+            false,
+            &LargestFirst,
+        )
+        .unwrap();
+        let (bnb_cpfp_tx, bnb_selection) = CpfpTransaction::from_txins(
+            vec![cpfp_txin.clone()],
+            tbc_weight,
+            tbc_fees,
+            added_feerate,
+            small_utxos.clone(),
+            &change_descriptor,
+            false,
+            &BranchAndBound::default(),
+        )
+        .unwrap();
+        // The branch and bound finds a combination of coins that doesn't need a change output,
+        // wasting less than the largest coin alone
+        assert_eq!(
+            bnb_selection.selected,
+            vec![small_utxos[3].outpoint(), small_utxos[0].outpoint()]
+        );
+        assert!(!bnb_selection.change);
+        assert!(bnb_cpfp_tx.tx().output[0].script_pubkey.is_op_return());
+        assert!(bnb_selection.waste < lf_selection.waste);
+        assert!(bnb_selection.waste < bnb_cpfp_tx.fees());
+        for outpoint in lf_selection.selected.iter() {
+            assert!(lf_cpfp_tx
+                .tx()
+                .input
+                .iter()
+                .any(|txin| txin.previous_output == *outpoint));
+        }
+        assert_eq!(
+            lf_selection.change,
+            !lf_cpfp_tx.tx().output[0].script_pubkey.is_op_return()
+        );
+
+        // When there is no such combination, it falls back to a selection with a change output
+        let large_utxos = coins(&[1_000_000], &der_cpfp_descriptor);
+        let (bnb_cpfp_tx, bnb_selection) = CpfpTransaction::from_txins(
+            vec![cpfp_txin.clone()],
+            tbc_weight,
+            tbc_fees,
+            added_feerate,
+            large_utxos.clone(),
+            &change_descriptor,
+            false,
+            &BranchAndBound::default(),
+        )
+        .unwrap();
+        assert!(bnb_selection.change);
+        assert_eq!(bnb_selection.selected, vec![large_utxos[0].outpoint()]);
+        assert_eq!(
+            Ok((bnb_cpfp_tx, bnb_selection)),
+            CpfpTransaction::from_txins(
+                vec![cpfp_txin],
+                tbc_weight,
+                tbc_fees,
+                added_feerate,
+                large_utxos,
+                &change_descriptor,
+                false,
+                &LargestFirst,
+            )
+        );
     }
 
-    pub fn psbt(&self) -> &Psbt {
-        &self.0
+    #[test]
+    fn cpfp_fresh_change() {
+        let secp = secp256k1::Secp256k1::new();
+        let deployment = TestDeployment::new(3, 2, false, 360_000_000, &secp);
+        let fee_policy = FeePolicy::default();
+        let (unvault_tx, _, _, _) = deployment.chain(&fee_policy, &secp);
+        let change_descriptor = deployment
+            .cpfp_descriptor
+            .derive(bip32::ChildNumber::from(11), &secp);
+        let cpfp_change_txo = CpfpTxOut::new(Amount::from_sat(0), &change_descriptor);
+        let cpfp_txin = unvault_tx
+            .cpfp_txin(&deployment.cpfp_descriptor, &secp)
+            .unwrap();
+
+        // The change is sent to a fresh address, with the derivation info for the signers
+        let big_utxo = coins(&[1_000_000], &deployment.der_cpfp_descriptor(&secp));
+        let (fresh_change_cpfp_tx, selection) = CpfpTransaction::from_txins(
+            vec![cpfp_txin],
+            unvault_tx.max_weight(),
+            Amount::from_sat(unvault_tx.fees()),
+            61_210,
+            big_utxo.clone(),
+            &change_descriptor,
+            false,
+            &LargestFirst,
+        )
+        .unwrap();
+        assert!(selection.change && selection.selected == vec![big_utxo[0].outpoint()]);
+        assert_eq!(
+            fresh_change_cpfp_tx.tx().output[0].script_pubkey,
+            cpfp_change_txo.txout().script_pubkey
+        );
+        assert_eq!(
+            &fresh_change_cpfp_tx.psbt().outputs[0].bip32_derivation,
+            cpfp_change_txo.bip32_derivation()
+        );
+        assert_eq!(
+            CpfpTransaction::from_raw_psbt(&fresh_change_cpfp_tx.as_psbt_serialized()),
+            Ok(fresh_change_cpfp_tx.clone())
+        );
+
+        // A replacement keeps paying to it
+        let bumped_cpfp_tx = fresh_change_cpfp_tx
+            .bump(
+                unvault_tx.max_weight(),
+                Amount::from_sat(unvault_tx.fees()),
+                122_420,
+                big_utxo,
+                &change_descriptor,
+            )
+            .unwrap();
+        assert_eq!(
+            bumped_cpfp_tx.tx().output[0].script_pubkey,
+            cpfp_change_txo.txout().script_pubkey
+        );
+        assert_eq!(
+            &bumped_cpfp_tx.psbt().outputs[0].bip32_derivation,
+            cpfp_change_txo.bip32_derivation()
+        );
     }
 
-    pub fn into_psbt(self) -> Psbt {
-        self.0
+    #[test]
+    fn cpfp_signing() {
+        let secp = secp256k1::Secp256k1::new();
+        let deployment = TestDeployment::new(3, 2, false, 360_000_000, &secp);
+        let (_, cpfp_tx) = spend_cpfp(&deployment, &secp);
+
+        // It can be signed and finalized as any other Revault transaction
+        assert_eq!(
+            CpfpTransaction::from_raw_psbt(&cpfp_tx.as_psbt_serialized()),
+            Ok(cpfp_tx.clone())
+        );
+        let mut signed_cpfp_tx = cpfp_tx;
+        for i in 0..signed_cpfp_tx.psbt().inputs.len() {
+            let sighash = signed_cpfp_tx.signature_hash(i, SigHashType::All).unwrap();
+            satisfy_transaction_input(
+                &secp,
+                &mut signed_cpfp_tx,
+                i,
+                &sighash,
+                &deployment.mancpfp_priv,
+                Some(deployment.child_number),
+            )
+            .unwrap();
+        }
+        assert_eq!(
+            CpfpTransaction::from_raw_psbt(&signed_cpfp_tx.as_psbt_serialized()),
+            Ok(signed_cpfp_tx.clone())
+        );
+        assert!(!signed_cpfp_tx.is_finalized());
+        signed_cpfp_tx.finalize(&secp).unwrap();
+        assert!(signed_cpfp_tx.is_finalized());
+        assert!(signed_cpfp_tx.is_valid(&secp));
+        assert_eq!(
+            CpfpTransaction::from_raw_psbt(&signed_cpfp_tx.as_psbt_serialized()),
+            Ok(signed_cpfp_tx)
+        );
     }
 
-    pub fn tx(&self) -> &Transaction {
-        &self.psbt().global.unsigned_tx
+    #[test]
+    fn cpfp_bump() {
+        let secp = secp256k1::Secp256k1::new();
+        let deployment = TestDeployment::new(3, 2, false, 360_000_000, &secp);
+        let (spend_tx, cpfp_tx) = spend_cpfp(&deployment, &secp);
+        let der_cpfp_descriptor = deployment.der_cpfp_descriptor(&secp);
+        let change_descriptor = deployment
+            .cpfp_descriptor
+            .derive(bip32::ChildNumber::from(11), &secp);
+        let cpfp_txin = spend_tx
+            .cpfp_txin(&deployment.cpfp_descriptor, &secp)
+            .unwrap();
+        let tbc_weight = spend_tx.max_weight();
+        let tbc_fees = Amount::from_sat(spend_tx.fees());
+        let added_feerate = 6121;
+
+        // If it doesn't confirm, it can be replaced by a CPFP transaction paying a higher feerate.
+        // It may only add confirmed coins (BIP125 rule 2).
+        let extra_utxos = vec![CpfpTxIn::new_confirmed(
+            OutPoint::from_str(
+                "f21596dd9df36b86bcf65f0884f1f20675c1fc185bc78a37a9cddb4ae5e3dd9f:2",
+            )
+            .unwrap(),
+            CpfpTxOut::new(Amount::from_sat(500_000), &der_cpfp_descriptor),
+        )];
+        let unconfirmed_utxos = vec![CpfpTxIn::new(
+            extra_utxos[0].outpoint(),
+            extra_utxos[0].txout().clone(),
+        )];
+        assert_eq!(
+            cpfp_tx.bump(
+                tbc_weight,
+                tbc_fees,
+                added_feerate * 2,
+                unconfirmed_utxos,
+                &change_descriptor
+            ),
+            cpfp_tx.bump(
+                tbc_weight,
+                tbc_fees,
+                added_feerate * 2,
+                vec![],
+                &change_descriptor
+            )
+        );
+        let bumped_cpfp_tx = cpfp_tx
+            .bump(
+                tbc_weight,
+                tbc_fees,
+                added_feerate * 2,
+                extra_utxos.clone(),
+                &change_descriptor,
+            )
+            .unwrap();
+        assert_eq!(
+            CpfpTransaction::from_raw_psbt(&bumped_cpfp_tx.as_psbt_serialized()),
+            Ok(bumped_cpfp_tx.clone())
+        );
+        // It spends the same coins, hence CPFPs the same transaction
+        for txin in cpfp_tx.tx().input.iter() {
+            assert!(bumped_cpfp_tx.tx().input.contains(txin));
+        }
+        assert_eq!(
+            bumped_cpfp_tx.tx().input[0].previous_output,
+            cpfp_txin.outpoint()
+        );
+        // BIP125 rules 3 and 4
+        let bumped_weight = utils::psbt_max_weight(bumped_cpfp_tx.psbt());
+        assert!(bumped_cpfp_tx.fees() >= cpfp_tx.fees() + bumped_weight / 4);
+        assert!(
+            1000 * (bumped_cpfp_tx.fees() + spend_tx.fees()) / (bumped_weight + tbc_weight)
+                >= spend_tx.max_feerate() * 1000 + added_feerate * 2
+        );
+        // It can be bumped again
+        let more_utxos = vec![CpfpTxIn::new_confirmed(
+            OutPoint::from_str(
+                "f21596dd9df36b86bcf65f0884f1f20675c1fc185bc78a37a9cddb4ae5e3dd9f:3",
+            )
+            .unwrap(),
+            CpfpTxOut::new(Amount::from_sat(500_000), &der_cpfp_descriptor),
+        )];
+        let rebumped_cpfp_tx = bumped_cpfp_tx
+            .bump(tbc_weight, tbc_fees, 0, more_utxos, &change_descriptor)
+            .unwrap();
+        assert!(rebumped_cpfp_tx.fees() > bumped_cpfp_tx.fees());
+        // But not without enough coins
+        assert_eq!(
+            cpfp_tx.bump(
+                tbc_weight,
+                tbc_fees,
+                added_feerate * 1_000,
+                extra_utxos.clone(),
+                &change_descriptor
+            ),
+            Err(TransactionCreationError::InsufficientFunds)
+        );
+
+        // Once finalized, it lost the witness Scripts and derivation paths of its inputs. We must
+        // provide the coins it spends.
+        let mut signed_cpfp_tx = cpfp_tx;
+        for i in 0..signed_cpfp_tx.psbt().inputs.len() {
+            let sighash = signed_cpfp_tx.signature_hash(i, SigHashType::All).unwrap();
+            satisfy_transaction_input(
+                &secp,
+                &mut signed_cpfp_tx,
+                i,
+                &sighash,
+                &deployment.mancpfp_priv,
+                Some(deployment.child_number),
+            )
+            .unwrap();
+        }
+        signed_cpfp_tx.finalize(&secp).unwrap();
+        assert_eq!(
+            signed_cpfp_tx.bump(
+                tbc_weight,
+                tbc_fees,
+                added_feerate * 2,
+                extra_utxos.clone(),
+                &change_descriptor
+            ),
+            Err(TransactionCreationError::InvalidReplacement)
+        );
+        let coins: Vec<CpfpTxIn> = vec![cpfp_txin]
+            .into_iter()
+            .chain(coins(&[58_000, 23_000], &der_cpfp_descriptor))
+            .chain(extra_utxos)
+            .collect();
+        assert_eq!(
+            signed_cpfp_tx.bump(
+                tbc_weight,
+                tbc_fees,
+                added_feerate * 2,
+                coins,
+                &change_descriptor
+            ),
+            Ok(bumped_cpfp_tx)
+        );
     }
 
-    pub fn fees(&self) -> u64 {
-        // We always set a witness_utxo in our PSBT inputs.
-        utils::psbt_fees(self.psbt()).expect("Fee computation bug: overflow")
+    #[test]
+    fn anchors_cpfp() {
+        let secp = secp256k1::Secp256k1::new();
+        let deployment = TestDeployment::new(3, 2, false, 360_000_000, &secp);
+        let fee_policy = FeePolicy::default();
+        let der_cpfp_descriptor = deployment.der_cpfp_descriptor(&secp);
+        let change_descriptor = deployment
+            .cpfp_descriptor
+            .derive(bip32::ChildNumber::from(11), &secp);
+        let (unvault_tx, _, _, _) = deployment.chain(&fee_policy, &secp);
+        let unvault_txin =
+            unvault_tx.revault_unvault_txin(&deployment.der_unvault_descriptor(&secp));
+        let anchored_cancel = CancelTransaction::new_with_anchor(
+            unvault_txin.clone(),
+            &deployment.der_deposit_descriptor(&secp),
+            0,
+            &fee_policy,
+        )
+        .unwrap();
+        let anchored_unemer = UnvaultEmergencyTransaction::new_with_anchor(
+            unvault_txin,
+            deployment.emergency_address.clone(),
+            0,
+            &fee_policy,
+        )
+        .unwrap();
+
+        // We can bump the anchored revocation transactions all at once
+        let fee_utxos = coins(&[200_000, 1_000], &der_cpfp_descriptor);
+        let cancel_anchor_txin = anchored_cancel.anchor_txin().unwrap();
+        let anchor_txins = vec![
+            cancel_anchor_txin.clone(),
+            anchored_unemer.anchor_txin().unwrap(),
+        ];
+        let tbc_weight = anchored_cancel.max_weight() + anchored_unemer.max_weight();
+        let tbc_fees = Amount::from_sat(anchored_cancel.fees() + anchored_unemer.fees());
+        let mut anchors_cpfp = CpfpTransaction::from_anchors(
+            anchor_txins.clone(),
+            tbc_weight,
+            tbc_fees,
+            1_000,
+            fee_utxos.clone(),
+            &change_descriptor,
+            false,
+        )
+        .unwrap();
+        // Both anchors and the largest coin are spent, the change never goes to an anchor
+        assert_eq!(anchors_cpfp.tx().input.len(), 3);
+        assert!(anchors_cpfp
+            .tx()
+            .input
+            .contains(&cancel_anchor_txin.unsigned_txin()));
+        assert!(anchors_cpfp
+            .tx()
+            .input
+            .contains(&fee_utxos[0].unsigned_txin()));
+        assert_eq!(
+            anchors_cpfp.tx().output[0].script_pubkey,
+            change_descriptor.inner().script_pubkey()
+        );
+        let package_weight = tbc_weight + utils::psbt_max_weight(anchors_cpfp.psbt());
+        let package_fees = tbc_fees.as_sat() + anchors_cpfp.fees();
+        assert!(package_fees * 1_000 / package_weight >= 1_000);
+        assert_eq!(
+            CpfpTransaction::from_anchors(
+                anchor_txins,
+                tbc_weight,
+                tbc_fees,
+                1_000,
+                vec![],
+                &change_descriptor,
+                false
+            ),
+            Err(TransactionCreationError::InsufficientFunds)
+        );
+
+        // The anchors are keyless, only the fee-bumping coin needs to be signed
+        assert_eq!(
+            anchors_cpfp.signature_hash(0, SigHashType::All),
+            Err(InputSatisfactionError::KeylessInput)
+        );
+        let coin_sighash = anchors_cpfp.signature_hash(2, SigHashType::All).unwrap();
+        satisfy_transaction_input(
+            &secp,
+            &mut anchors_cpfp,
+            2,
+            &coin_sighash,
+            &deployment.mancpfp_priv,
+            Some(deployment.child_number),
+        )
+        .unwrap();
+        anchors_cpfp.finalize(&secp).unwrap();
+        assert!(anchors_cpfp.is_valid(&secp));
+        assert_eq!(
+            anchors_cpfp.psbt().inputs[0].final_script_witness,
+            Some(vec![])
+        );
+        assert_eq!(
+            CpfpTransaction::from_raw_psbt(&anchors_cpfp.as_psbt_serialized()),
+            Ok(anchors_cpfp)
+        );
+        // A revocation transaction is not a CPFP transaction
+        assert!(CpfpTransaction::from_psbt_str(&anchored_cancel.as_psbt_string()).is_err());
+    }
+
+    #[test]
+    fn truc_cpfp() {
+        let secp = secp256k1::Secp256k1::new();
+        let deployment = TestDeployment::new(3, 2, false, 360_000_000, &secp);
+        let fee_policy = FeePolicy::default();
+        let truc_policy = FeePolicy {
+            truc: true,
+            ..fee_policy.clone()
+        };
+        let der_cpfp_descriptor = deployment.der_cpfp_descriptor(&secp);
+        let change_descriptor = deployment
+            .cpfp_descriptor
+            .derive(bip32::ChildNumber::from(11), &secp);
+        let (truc_unvault, _, _, _) = deployment.chain(&truc_policy, &secp);
+        let truc_unvault_txin =
+            truc_unvault.revault_unvault_txin(&deployment.der_unvault_descriptor(&secp));
+        let truc_cancel = CancelTransaction::new_with_anchor(
+            truc_unvault_txin.clone(),
+            &deployment.der_deposit_descriptor(&secp),
+            0,
+            &truc_policy,
+        )
+        .unwrap();
+        let truc_unemer = UnvaultEmergencyTransaction::new_with_anchor(
+            truc_unvault_txin,
+            deployment.emergency_address.clone(),
+            0,
+            &truc_policy,
+        )
+        .unwrap();
+
+        // The TRUC CPFP is a small child with a single unconfirmed parent, so it may only add
+        // confirmed coins.
+        let fee_utxos = coins(&[200_000, 1_000], &der_cpfp_descriptor);
+        assert_eq!(
+            CpfpTransaction::from_anchors(
+                vec![truc_cancel.anchor_txin().unwrap()],
+                truc_cancel.max_weight(),
+                Amount::from_sat(truc_cancel.fees()),
+                1_000,
+                fee_utxos.clone(),
+                &change_descriptor,
+                true,
+            ),
+            Err(TransactionCreationError::InsufficientFunds)
+        );
+        let confirmed_fee_utxos: Vec<CpfpTxIn> = fee_utxos
+            .iter()
+            .map(|utxo| CpfpTxIn::new_confirmed(utxo.outpoint(), utxo.txout().clone()))
+            .collect();
+        let truc_cpfp = CpfpTransaction::from_anchors(
+            vec![truc_cancel.anchor_txin().unwrap()],
+            truc_cancel.max_weight(),
+            Amount::from_sat(truc_cancel.fees()),
+            1_000,
+            confirmed_fee_utxos.clone(),
+            &change_descriptor,
+            true,
+        )
+        .unwrap();
+        assert_eq!(truc_cpfp.tx().version, TRUC_TX_VERSION);
+        assert!(utils::psbt_max_weight(truc_cpfp.psbt()) <= TRUC_CHILD_MAX_WEIGHT);
+        assert_eq!(
+            CpfpTransaction::from_raw_psbt(&truc_cpfp.as_psbt_serialized()),
+            Ok(truc_cpfp.clone())
+        );
+
+        // It can't CPFP another parent's anchor
+        let (unvault_tx, _, _, _) = deployment.chain(&fee_policy, &secp);
+        let other_anchor_txin = CancelTransaction::new_with_anchor(
+            unvault_tx.revault_unvault_txin(&deployment.der_unvault_descriptor(&secp)),
+            &deployment.der_deposit_descriptor(&secp),
+            0,
+            &fee_policy,
+        )
+        .unwrap()
+        .anchor_txin()
+        .unwrap();
+        let mut two_parents_psbt = truc_cpfp.psbt().clone();
+        two_parents_psbt
+            .global
+            .unsigned_tx
+            .input
+            .push(other_anchor_txin.unsigned_txin());
+        two_parents_psbt.inputs.push(PsbtIn {
+            witness_utxo: Some(other_anchor_txin.into_txout().into_txout()),
+            ..PsbtIn::default()
+        });
+        assert!(matches!(
+            CpfpTransaction::from_raw_psbt(&encode::serialize(&two_parents_psbt)),
+            Err(TransactionSerialisationError::Validation(
+                PsbtValidationError::InvalidPrevoutType(..)
+            ))
+        ));
+        assert_eq!(
+            CpfpTransaction::from_anchors(
+                vec![
+                    truc_cancel.anchor_txin().unwrap(),
+                    truc_unemer.anchor_txin().unwrap()
+                ],
+                truc_cancel.max_weight() + truc_unemer.max_weight(),
+                Amount::from_sat(truc_cancel.fees() + truc_unemer.fees()),
+                1_000,
+                confirmed_fee_utxos,
+                &change_descriptor,
+                true,
+            ),
+            Err(TransactionCreationError::TrucViolation)
+        );
+
+        // Too many small coins would make it exceed the TRUC child size limit. They must be worth
+        // more than the fees to spend them (here at 100sat/WU), otherwise they are not considered.
+        let small_coin_weight = 4 * (32 + 4 + 1 + 4)
+            + CpfpTxOut::new(Amount::from_sat(0), &der_cpfp_descriptor).max_sat_weight() as u64;
+        let small_utxos: Vec<CpfpTxIn> =
+            coins(&[100 * small_coin_weight + 5_000; 80], &der_cpfp_descriptor)
+                .into_iter()
+                .map(|utxo| CpfpTxIn::new_confirmed(utxo.outpoint(), utxo.txout().clone()))
+                .collect();
+        assert_eq!(
+            CpfpTransaction::from_anchors(
+                vec![truc_cancel.anchor_txin().unwrap()],
+                1_000,
+                Amount::from_sat(1_000),
+                98_000,
+                small_utxos,
+                &change_descriptor,
+                true,
+            ),
+            Err(TransactionCreationError::TrucViolation)
+        );
     }
 }
@@ -2,7 +2,8 @@ use crate::{
     error::*,
     scripts::*,
     transactions::{
-        utils, RevaultTransaction, EMER_TX_FEERATE, INSANE_FEES, MAX_STANDARD_TX_WEIGHT, TX_VERSION,
+        utils, CpfpableTransaction, FeePolicy, RevaultTransaction, RevocationTransaction,
+        MAX_STANDARD_TX_WEIGHT, TRUC_MAX_WEIGHT,
     },
     txins::*,
     txouts::*,
@@ -29,6 +30,7 @@ use std::{collections::BTreeMap, convert::TryInto};
 
 impl_revault_transaction!(
     EmergencyTransaction,
+    parse_psbt,
     doc = "The transaction spending a deposit output to The Emergency Script."
 );
 impl EmergencyTransaction {
@@ -37,7 +39,9 @@ impl EmergencyTransaction {
         deposit_txin: DepositTxIn,
         feebump_txin: Option<FeeBumpTxIn>,
         emergency_txo: EmergencyTxOut,
+        anchor_txo: Option<AnchorTxOut>,
         lock_time: u32,
+        version: i32,
     ) -> Psbt {
         let mut txins = vec![deposit_txin.unsigned_txin()];
         let mut psbtins = vec![PsbtIn {
@@ -56,13 +60,21 @@ impl EmergencyTransaction {
             });
         }
 
+        // Emergency txout, and the optional anchor txout
+        let mut psbtouts = vec![PsbtOut::default()];
+        let mut txouts = vec![emergency_txo.into_txout()];
+        if let Some(anchor_txo) = anchor_txo {
+            psbtouts.push(PsbtOut::default());
+            txouts.push(anchor_txo.into_txout());
+        }
+
         Psbt {
             global: PsbtGlobal {
                 unsigned_tx: Transaction {
-                    version: TX_VERSION,
+                    version,
                     lock_time,
                     input: txins,
-                    output: vec![emergency_txo.into_txout()],
+                    output: txouts,
                 },
                 version: 0,
                 xpub: BTreeMap::new(),
@@ -70,8 +82,7 @@ impl EmergencyTransaction {
                 unknown: BTreeMap::new(),
             },
             inputs: psbtins,
-            // Deposit txout
-            outputs: vec![PsbtOut::default()],
+            outputs: psbtouts,
         }
     }
 
@@ -85,6 +96,51 @@ impl EmergencyTransaction {
         feebump_input: Option<FeeBumpTxIn>,
         emer_address: EmergencyAddress,
         lock_time: u32,
+        fee_policy: &FeePolicy,
+    ) -> Result<EmergencyTransaction, TransactionCreationError> {
+        EmergencyTransaction::with_feerate(
+            deposit_input,
+            feebump_input,
+            None,
+            emer_address,
+            lock_time,
+            fee_policy.emer_feerate,
+            fee_policy,
+        )
+    }
+
+    /// Create an Emergency transaction with a keyless anchor output in addition to its main output,
+    /// so that it can be fee-bumped through CPFP instead of by adding a fee-bumping input. The
+    /// anchor output value is paid for by the revocation input, on top of the fees.
+    ///
+    /// BIP174 Creator and Updater roles.
+    pub fn new_with_anchor(
+        deposit_input: DepositTxIn,
+        emer_address: EmergencyAddress,
+        lock_time: u32,
+        fee_policy: &FeePolicy,
+    ) -> Result<EmergencyTransaction, TransactionCreationError> {
+        EmergencyTransaction::with_feerate(
+            deposit_input,
+            None,
+            Some(AnchorTxOut::default()),
+            emer_address,
+            lock_time,
+            fee_policy.emer_feerate,
+            fee_policy,
+        )
+    }
+
+    // Create an Emergency transaction at this feerate (in sat/WU), used for the fee ladder
+    // and the anchored variant.
+    pub(super) fn with_feerate(
+        deposit_input: DepositTxIn,
+        feebump_input: Option<FeeBumpTxIn>,
+        anchor_txo: Option<AnchorTxOut>,
+        emer_address: EmergencyAddress,
+        lock_time: u32,
+        feerate: u64,
+        fee_policy: &FeePolicy,
     ) -> Result<EmergencyTransaction, TransactionCreationError> {
         if let Some(ref txin) = feebump_input {
             if txin.txout().txout().value > max_money(Network::Bitcoin) {
@@ -95,10 +151,16 @@ impl EmergencyTransaction {
         // First, create a dummy transaction to get its weight without Witness. Note that we always
         // account for the weight *without* feebump input. It has to pay for itself.
         let emer_txo = EmergencyTxOut::new(emer_address.clone(), Amount::from_sat(u64::MAX));
-        let dummy_tx =
-            EmergencyTransaction::create_psbt(deposit_input.clone(), None, emer_txo, lock_time)
-                .global
-                .unsigned_tx;
+        let dummy_tx = EmergencyTransaction::create_psbt(
+            deposit_input.clone(),
+            None,
+            emer_txo,
+            anchor_txo.clone(),
+            lock_time,
+            fee_policy.tx_version(),
+        )
+        .global
+        .unsigned_tx;
 
         // The weight of the emergency transaction without a feebump input is the weight of the
         // witness-stripped transaction plus the weight required to satisfy the deposit txin
@@ -107,44 +169,60 @@ impl EmergencyTransaction {
             .checked_add(deposit_input.txout().max_sat_weight())
             .expect("Weight computation bug");
         let total_weight: u64 = total_weight.try_into().expect("usize in u64");
-        let fees = EMER_TX_FEERATE
+        let fees = feerate
             .checked_mul(total_weight)
-            .expect("Weight computation bug");
-        // Without the feebump input, it should not be reachable.
-        debug_assert!(fees < INSANE_FEES);
+            .ok_or(TransactionCreationError::InsaneFees)?;
 
         assert!(
             total_weight <= MAX_STANDARD_TX_WEIGHT as u64,
-            "At most 2 inputs and a single output"
+            "At most 2 inputs and 2 outputs"
         );
 
         // Now, get the emergency output value out of it.
         let deposit_value = deposit_input.txout().txout().value;
+        // The anchor output, if any, is paid for by the revocation input too.
+        let anchor_value = anchor_txo
+            .as_ref()
+            .map(|txo| txo.txout().value)
+            .unwrap_or(0);
         let emer_value = deposit_value
             .checked_sub(fees)
+            .and_then(|v| v.checked_sub(anchor_value))
             .ok_or(TransactionCreationError::Dust)?;
-        // The emer output is the single one
+        if emer_value < fee_policy.dust_limit {
+            return Err(TransactionCreationError::Dust);
+        }
+        // The emer output is the main one
         if emer_value > max_money(Network::Bitcoin) {
             return Err(TransactionCreationError::InsaneAmounts);
         }
         let emer_txo = EmergencyTxOut::new(emer_address, Amount::from_sat(emer_value));
 
-        Ok(EmergencyTransaction(EmergencyTransaction::create_psbt(
+        let psbt = EmergencyTransaction::create_psbt(
             deposit_input,
             feebump_input,
             emer_txo,
+            anchor_txo,
             lock_time,
-        )))
+            fee_policy.tx_version(),
+        );
+        if fee_policy.truc && utils::psbt_max_weight(&psbt) > TRUC_MAX_WEIGHT {
+            return Err(TransactionCreationError::TrucViolation);
+        }
+
+        Ok(EmergencyTransaction(psbt))
     }
 
-    /// Parse an Emergency transaction from a PSBT
-    pub fn from_raw_psbt(raw_psbt: &[u8]) -> Result<Self, TransactionSerialisationError> {
+    // Parse an Emergency transaction from a PSBT, only checking its structure. This is what the
+    // deserialization routines use, as they don't know about the fee policy.
+    fn parse_psbt(raw_psbt: &[u8]) -> Result<Self, TransactionSerialisationError> {
         let psbt = Decodable::consensus_decode(raw_psbt)?;
-        let psbt = utils::psbt_common_sanity_checks(psbt)?;
+        let psbt = utils::psbt_common_sanity_checks(psbt, false, Some(TRUC_MAX_WEIGHT))?;
 
-        // Emergency txo
+        // Emergency txo, and an optional anchor txo
+        let has_anchor = utils::check_revocationtx_anchor(&psbt)?;
         let output_count = psbt.global.unsigned_tx.output.len();
-        if output_count != 1 {
+        if output_count != 1 + has_anchor as usize {
             return Err(PsbtValidationError::InvalidOutputCount(output_count).into());
         }
 
@@ -164,6 +242,23 @@ impl EmergencyTransaction {
         Ok(EmergencyTransaction(psbt))
     }
 
+    /// Parse an Emergency transaction from a PSBT, checking it was created under this fee policy.
+    pub fn from_raw_psbt(
+        raw_psbt: &[u8],
+        fee_policy: &FeePolicy,
+    ) -> Result<Self, TransactionSerialisationError> {
+        let tx = EmergencyTransaction::parse_psbt(raw_psbt)?;
+        if tx.tx().version != fee_policy.tx_version() {
+            return Err(PsbtValidationError::InvalidTransactionVersion(tx.tx().version).into());
+        }
+        let value = tx.tx().output[0].value;
+        if value < fee_policy.dust_limit {
+            return Err(PsbtValidationError::DustOutput(value).into());
+        }
+        utils::check_revocationtx_fees(tx.psbt(), fee_policy.emer_feerate)?;
+        Ok(tx)
+    }
+
     /// Add a signature for the input spending the Deposit transaction
     pub fn add_emer_sig<C: secp256k1::Verification>(
         &mut self,
@@ -185,3 +280,181 @@ impl EmergencyTransaction {
         }
     }
 }
+
+impl CpfpableTransaction for EmergencyTransaction {
+    fn max_weight(&self) -> u64 {
+        utils::psbt_max_weight(self.psbt())
+    }
+}
+
+impl RevocationTransaction for EmergencyTransaction {
+    fn add_feebump_input(
+        &mut self,
+        feebump_input: FeeBumpTxIn,
+    ) -> Result<u64, TransactionCreationError> {
+        utils::add_feebump_input(&mut self.0, feebump_input)?;
+        Ok(self.max_feerate())
+    }
+
+    fn remove_feebump_input(&mut self) -> Option<FeeBumpTxIn> {
+        utils::remove_feebump_input(&mut self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transactions::{
+        tests_helpers::{get_random_privkey, satisfy_transaction_input, TestDeployment},
+        RevocationTransaction, ANCHOR_VALUE,
+    };
+
+    use miniscript::{
+        bitcoin::{consensus::encode, util::bip32, OutPoint, TxOut},
+        descriptor::{DescriptorPublicKey, DescriptorXKey, Wildcard},
+        Descriptor, DescriptorTrait,
+    };
+    use std::str::FromStr;
+
+    #[test]
+    fn anchored_emergency() {
+        let secp = secp256k1::Secp256k1::new();
+        let deployment = TestDeployment::new(3, 2, false, 360_000_000, &secp);
+        let fee_policy = FeePolicy::default();
+        let anchored_emer = EmergencyTransaction::new_with_anchor(
+            deployment.deposit_txin.clone(),
+            deployment.emergency_address.clone(),
+            0,
+            &fee_policy,
+        )
+        .unwrap();
+        assert_eq!(
+            EmergencyTransaction::from_raw_psbt(&anchored_emer.as_psbt_serialized(), &fee_policy),
+            Ok(anchored_emer.clone())
+        );
+        assert_eq!(anchored_emer.tx().output[1].value, ANCHOR_VALUE);
+
+        // Anyone may spend the anchor output, it can't be given more than its value
+        let mut psbt = anchored_emer.psbt().clone();
+        psbt.global.unsigned_tx.output[0].value -= 10_000;
+        psbt.global.unsigned_tx.output[1].value += 10_000;
+        assert!(matches!(
+            EmergencyTransaction::from_raw_psbt(&encode::serialize(&psbt), &fee_policy),
+            Err(TransactionSerialisationError::Validation(
+                PsbtValidationError::InvalidAnchorValue(value)
+            )) if value == ANCHOR_VALUE + 10_000
+        ));
+    }
+
+    #[test]
+    fn late_feebump_input() {
+        let secp = secp256k1::Secp256k1::new();
+        let deployment = TestDeployment::new(3, 2, false, 360_000_000, &secp);
+        let fee_policy = FeePolicy::default();
+        let (_, _, emer_tx, _) = deployment.chain(&fee_policy, &secp);
+
+        // We simulate a watchtower wallet with two P2WPKH fee-bumping coins
+        let mut rng = fastrand::Rng::new();
+        let feebump_xpriv = get_random_privkey(&mut rng);
+        let feebump_descriptor = Descriptor::new_wpkh(
+            DescriptorPublicKey::XPub(DescriptorXKey {
+                origin: None,
+                xkey: bip32::ExtendedPubKey::from_private(&secp, &feebump_xpriv),
+                derivation_path: bip32::DerivationPath::from(vec![]),
+                wildcard: Wildcard::None,
+            })
+            .derive_public_key(&secp)
+            .unwrap(),
+        )
+        .unwrap();
+        let feebump_txins: Vec<FeeBumpTxIn> = [100_000, 200_000]
+            .iter()
+            .enumerate()
+            .map(|(i, value)| {
+                FeeBumpTxIn::new(
+                    OutPoint::from_str(&format!(
+                        "4bb4545bb4bc8853cb03e42984d677fbe880c81e7d95609360eed0d8f45b52f8:{}",
+                        i
+                    ))
+                    .unwrap(),
+                    FeeBumpTxOut::new(TxOut {
+                        value: *value,
+                        script_pubkey: feebump_descriptor.script_pubkey(),
+                    })
+                    .unwrap(),
+                )
+            })
+            .collect();
+
+        // The fee-bumping input may be added once the stakeholders signed
+        let mut late_bumped_emer = emer_tx;
+        let vault_sighash = late_bumped_emer
+            .signature_hash(0, SigHashType::AllPlusAnyoneCanPay)
+            .unwrap();
+        satisfy_transaction_input(
+            &secp,
+            &mut late_bumped_emer,
+            0,
+            &vault_sighash,
+            &deployment.stakeholders_priv,
+            Some(deployment.child_number),
+        )
+        .unwrap();
+        let signed_emer = late_bumped_emer.clone();
+        let feerate = late_bumped_emer
+            .add_feebump_input(feebump_txins[0].clone())
+            .unwrap();
+        assert!(feerate > signed_emer.max_feerate());
+        assert_eq!(feerate, late_bumped_emer.max_feerate());
+        assert_eq!(
+            late_bumped_emer.psbt().inputs[0].partial_sigs,
+            signed_emer.psbt().inputs[0].partial_sigs
+        );
+        assert_eq!(
+            late_bumped_emer.add_feebump_input(feebump_txins[1].clone()),
+            Err(TransactionCreationError::AlreadyFeeBumped)
+        );
+
+        // It can be replaced, or removed
+        let feerate = late_bumped_emer
+            .replace_feebump_input(feebump_txins[1].clone())
+            .unwrap();
+        assert_eq!(feerate, late_bumped_emer.max_feerate());
+        assert_eq!(
+            late_bumped_emer.tx().input[1].previous_output,
+            feebump_txins[1].outpoint()
+        );
+        assert_eq!(
+            late_bumped_emer.remove_feebump_input(),
+            Some(feebump_txins[1].clone())
+        );
+        assert_eq!(late_bumped_emer.remove_feebump_input(), None);
+        assert_eq!(late_bumped_emer, signed_emer);
+
+        // Once its own input signed, it's valid
+        late_bumped_emer
+            .add_feebump_input(feebump_txins[0].clone())
+            .unwrap();
+        assert_eq!(
+            EmergencyTransaction::from_raw_psbt(
+                &late_bumped_emer.as_psbt_serialized(),
+                &fee_policy
+            ),
+            Ok(late_bumped_emer.clone())
+        );
+        let feebump_sighash = late_bumped_emer
+            .signature_hash(1, SigHashType::All)
+            .unwrap();
+        satisfy_transaction_input(
+            &secp,
+            &mut late_bumped_emer,
+            1,
+            &feebump_sighash,
+            &vec![feebump_xpriv],
+            None,
+        )
+        .unwrap();
+        late_bumped_emer.finalize(&secp).unwrap();
+        assert!(late_bumped_emer.is_valid(&secp));
+    }
+}
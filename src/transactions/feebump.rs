@@ -0,0 +1,327 @@
+use crate::{
+    error::*,
+    transactions::{utils, RevocationTransaction, MAX_STANDARD_TX_WEIGHT, TX_VERSION},
+    txins::*,
+    txouts::*,
+};
+
+use miniscript::bitcoin::{
+    blockdata::constants::max_money, Network, OutPoint, Script, Transaction, TxOut,
+};
+
+use std::convert::TryInto;
+
+// The weight of a P2WPKH txin, including its witness.
+const FEEBUMP_TXIN_WEIGHT: u64 = 4 * (32 + 4 + 1 + 4) + utils::P2WPKH_MAX_SAT_WEIGHT;
+
+// The weight of the segwit marker and flag, not accounted for in the weight of a transaction with
+// empty witnesses.
+const SEGWIT_MARKER_FLAG_WEIGHT: u64 = 2;
+
+// The dust threshold of a P2WPKH output at the default dust relay feerate. We never create
+// fee-bumping coins or change below it.
+const P2WPKH_DUST_LIMIT: u64 = 294;
+
+/// A revocation transaction to be fee-bumped by a coin created by a [FeeBumpTransaction].
+#[derive(Debug, Clone, PartialEq)]
+pub struct FeeBumpTarget {
+    txout: FeeBumpTxOut,
+}
+
+impl FeeBumpTarget {
+    /// Create a coin paying to this P2WPKH `script_pubkey` large enough for this revocation
+    /// transaction to reach `feerate` (in sat/WU) once spending it. The coin pays for its own
+    /// weight, and is never smaller than the P2WPKH dust limit.
+    ///
+    /// Will error if the revocation transaction already has a fee-bumping input, or if it has an
+    /// anchor output as it's then fee-bumped through CPFP.
+    pub fn new<T: RevocationTransaction>(
+        revocation_tx: &T,
+        feerate: u64,
+        script_pubkey: Script,
+    ) -> Result<FeeBumpTarget, TransactionCreationError> {
+        if utils::find_feebumping_input(&revocation_tx.psbt().inputs).is_some() {
+            return Err(TransactionCreationError::AlreadyFeeBumped);
+        }
+        if revocation_tx.anchor_txin().is_some() {
+            return Err(TransactionCreationError::AnchoredRevocation);
+        }
+
+        let weight = revocation_tx.max_weight() + FEEBUMP_TXIN_WEIGHT;
+        let fees_needed = feerate
+            .checked_mul(weight)
+            .ok_or(TransactionCreationError::InsaneFees)?;
+        let value = fees_needed.saturating_sub(revocation_tx.fees());
+        let txout = FeeBumpTxOut::new(TxOut {
+            value: std::cmp::max(value, P2WPKH_DUST_LIMIT),
+            script_pubkey,
+        })
+        .map_err(|_| TransactionCreationError::InvalidFeeBumpScript)?;
+
+        Ok(FeeBumpTarget { txout })
+    }
+
+    /// The value of the fee-bumping coin
+    pub fn value(&self) -> u64 {
+        self.txout.txout().value
+    }
+}
+
+/// The fee-bumping transaction, creating the coins the revocation transactions will spend to
+/// bump their feerate. We create it, but we never sign it: it spends coins from an external wallet.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FeeBumpTransaction(pub Transaction);
+
+impl FeeBumpTransaction {
+    /// Create a transaction spending some of these wallet P2WPKH coins to fan-out one fee-bumping
+    /// coin per target, at this `feerate` (in sat/WU). The wallet coins are selected largest
+    /// first, and any change above the dust limit is sent to the `change_script_pubkey`.
+    ///
+    /// Returns the transaction along with the fee-bumping txins, in the order of the targets, to
+    /// be added to the revocation transactions.
+    /// Will error with [TransactionCreationError::InsufficientFunds] if the wallet coins can't
+    /// cover the targets and the fees, or [TransactionCreationError::NoFeeBumpTarget] if there
+    /// are no targets.
+    pub fn new(
+        mut wallet_utxos: Vec<FeeBumpTxIn>,
+        targets: Vec<FeeBumpTarget>,
+        change_script_pubkey: Script,
+        feerate: u64,
+    ) -> Result<(FeeBumpTransaction, Vec<FeeBumpTxIn>), TransactionCreationError> {
+        if targets.is_empty() {
+            return Err(TransactionCreationError::NoFeeBumpTarget);
+        }
+        // Sorted in ascending order, we pop() from it for a largest first CS.
+        wallet_utxos.sort_unstable_by_key(|l| l.txout().txout().value);
+
+        let mut txouts: Vec<TxOut> = targets
+            .into_iter()
+            .map(|target| target.txout.into_txout())
+            .collect();
+        let outputs_sum = txouts
+            .iter()
+            .try_fold(0u64, |sum, txo| sum.checked_add(txo.value))
+            .filter(|sum| *sum <= max_money(Network::Bitcoin))
+            .ok_or(TransactionCreationError::InsaneAmounts)?;
+        let coins_count = txouts.len();
+        txouts.push(TxOut {
+            value: 0,
+            script_pubkey: change_script_pubkey,
+        });
+
+        let mut tx = Transaction {
+            version: TX_VERSION,
+            lock_time: 0,
+            input: vec![],
+            output: txouts,
+        };
+        let mut inputs_sum: u64 = 0;
+        loop {
+            let satisfaction_weight = tx.input.len() as u64 * utils::P2WPKH_MAX_SAT_WEIGHT;
+            let tx_weight: u64 = tx.get_weight().try_into().expect("usize in u64");
+            let weight = tx_weight + SEGWIT_MARKER_FLAG_WEIGHT + satisfaction_weight;
+            if weight > MAX_STANDARD_TX_WEIGHT as u64 {
+                return Err(TransactionCreationError::TooLarge);
+            }
+            let fees = feerate
+                .checked_mul(weight)
+                .ok_or(TransactionCreationError::InsaneFees)?;
+
+            if !tx.input.is_empty() && inputs_sum >= outputs_sum + fees {
+                // Alright, we found it! Have a change if it's not dust.
+                let change_value = inputs_sum - outputs_sum - fees;
+                if change_value >= P2WPKH_DUST_LIMIT {
+                    tx.output[coins_count].value = change_value;
+                } else {
+                    tx.output.pop();
+                }
+                break;
+            }
+
+            let new_input = wallet_utxos
+                .pop()
+                .ok_or(TransactionCreationError::InsufficientFunds)?;
+            inputs_sum = inputs_sum
+                .checked_add(new_input.txout().txout().value)
+                .ok_or(TransactionCreationError::InsaneAmounts)?;
+            tx.input.push(new_input.unsigned_txin());
+        }
+
+        let txid = tx.txid();
+        let feebump_txins = tx.output[..coins_count]
+            .iter()
+            .enumerate()
+            .map(|(vout, txo)| {
+                FeeBumpTxIn::new(
+                    OutPoint {
+                        txid,
+                        vout: vout.try_into().expect("vout doesn't fit in a u32?"),
+                    },
+                    FeeBumpTxOut::new(txo.clone()).expect("Checked at target creation"),
+                )
+            })
+            .collect();
+
+        Ok((FeeBumpTransaction(tx), feebump_txins))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transactions::{
+        tests_helpers::{get_random_privkey, TestDeployment},
+        CancelTransaction, CpfpableTransaction, EmergencyTransaction, FeePolicy,
+    };
+
+    use miniscript::{
+        bitcoin::{secp256k1, util::bip32},
+        descriptor::{DescriptorPublicKey, DescriptorXKey, Wildcard},
+        Descriptor, DescriptorTrait,
+    };
+    use std::str::FromStr;
+
+    #[test]
+    fn feebump_transaction() {
+        let secp = secp256k1::Secp256k1::new();
+        let deployment = TestDeployment::new(3, 2, false, 360_000_000, &secp);
+        let fee_policy = FeePolicy::default();
+        let (unvault_tx, cancel_tx, emer_tx, _) = deployment.chain(&fee_policy, &secp);
+
+        // We simulate a wallet with two P2WPKH utxos
+        let mut rng = fastrand::Rng::new();
+        let feebump_xpriv = get_random_privkey(&mut rng);
+        let feebump_descriptor = Descriptor::new_wpkh(
+            DescriptorPublicKey::XPub(DescriptorXKey {
+                origin: None,
+                xkey: bip32::ExtendedPubKey::from_private(&secp, &feebump_xpriv),
+                derivation_path: bip32::DerivationPath::from(vec![]),
+                wildcard: Wildcard::None,
+            })
+            .derive_public_key(&secp)
+            .unwrap(),
+        )
+        .unwrap();
+        let wallet_utxos: Vec<FeeBumpTxIn> = [50_000, 1_000_000_000]
+            .iter()
+            .enumerate()
+            .map(|(i, value)| {
+                FeeBumpTxIn::new(
+                    OutPoint::from_str(&format!(
+                        "4bb4545bb4bc8853cb03e42984d677fbe880c81e7d95609360eed0d8f45b52f8:{}",
+                        i
+                    ))
+                    .unwrap(),
+                    FeeBumpTxOut::new(TxOut {
+                        value: *value,
+                        script_pubkey: feebump_descriptor.script_pubkey(),
+                    })
+                    .unwrap(),
+                )
+            })
+            .collect();
+
+        // Watchtowers can create the coins to fee-bump the revocation transactions with
+        let emer_target = FeeBumpTarget::new(
+            &emer_tx,
+            fee_policy.emer_feerate * 2,
+            feebump_descriptor.script_pubkey(),
+        )
+        .unwrap();
+        let cancel_target = FeeBumpTarget::new(
+            &cancel_tx,
+            fee_policy.cancel_feerate * 3,
+            feebump_descriptor.script_pubkey(),
+        )
+        .unwrap();
+        let (fanout_tx, fanout_txins) = FeeBumpTransaction::new(
+            wallet_utxos.clone(),
+            vec![emer_target.clone(), cancel_target.clone()],
+            feebump_descriptor.script_pubkey(),
+            1,
+        )
+        .unwrap();
+        assert_eq!(fanout_txins.len(), 2);
+        assert_eq!(fanout_tx.0.output.len(), 3);
+        assert_eq!(fanout_txins[0].txout().txout().value, emer_target.value());
+        assert_eq!(fanout_txins[1].txout().txout().value, cancel_target.value());
+        for txin in fanout_txins.iter() {
+            assert_eq!(txin.outpoint().txid, fanout_tx.0.txid());
+        }
+        // It pays for its weight once signed, including the segwit marker and flag
+        let inputs_value: u64 = wallet_utxos
+            .iter()
+            .filter(|utxo| {
+                fanout_tx
+                    .0
+                    .input
+                    .iter()
+                    .any(|txin| txin.previous_output == utxo.outpoint())
+            })
+            .map(|utxo| utxo.txout().txout().value)
+            .sum();
+        let outputs_value: u64 = fanout_tx.0.output.iter().map(|txo| txo.value).sum();
+        let signed_weight = fanout_tx.0.get_weight() as u64
+            + 2
+            + fanout_tx.0.input.len() as u64 * utils::P2WPKH_MAX_SAT_WEIGHT;
+        assert!(inputs_value - outputs_value >= signed_weight);
+
+        // Spending them, the revocation transactions reach the target feerates
+        let bumped_emer = EmergencyTransaction::new(
+            deployment.deposit_txin.clone(),
+            Some(fanout_txins[0].clone()),
+            deployment.emergency_address.clone(),
+            0,
+            &fee_policy,
+        )
+        .unwrap();
+        assert!(bumped_emer.max_feerate() >= fee_policy.emer_feerate * 2);
+        let bumped_cancel = CancelTransaction::new(
+            unvault_tx.revault_unvault_txin(&deployment.der_unvault_descriptor(&secp)),
+            Some(fanout_txins[1].clone()),
+            &deployment.der_deposit_descriptor(&secp),
+            0,
+            &fee_policy,
+        )
+        .unwrap();
+        assert!(bumped_cancel.max_feerate() >= fee_policy.cancel_feerate * 3);
+
+        // But they can't be fee-bumped twice
+        assert_eq!(
+            FeeBumpTarget::new(&bumped_emer, 1_000, feebump_descriptor.script_pubkey()),
+            Err(TransactionCreationError::AlreadyFeeBumped)
+        );
+        // Nor if they have an anchor output
+        let anchored_cancel = CancelTransaction::new_with_anchor(
+            unvault_tx.revault_unvault_txin(&deployment.der_unvault_descriptor(&secp)),
+            &deployment.der_deposit_descriptor(&secp),
+            0,
+            &fee_policy,
+        )
+        .unwrap();
+        assert_eq!(
+            FeeBumpTarget::new(&anchored_cancel, 1_000, feebump_descriptor.script_pubkey()),
+            Err(TransactionCreationError::AnchoredRevocation)
+        );
+        // The coins must be P2WPKH
+        assert_eq!(
+            FeeBumpTarget::new(&emer_tx, 1_000, Script::new_op_return(&[])),
+            Err(TransactionCreationError::InvalidFeeBumpScript)
+        );
+        // And the wallet must have enough funds
+        assert_eq!(
+            FeeBumpTransaction::new(
+                wallet_utxos[..1].to_vec(),
+                vec![emer_target, cancel_target],
+                feebump_descriptor.script_pubkey(),
+                1,
+            ),
+            Err(TransactionCreationError::InsufficientFunds)
+        );
+        // It must create at least one coin
+        assert_eq!(
+            FeeBumpTransaction::new(wallet_utxos, vec![], feebump_descriptor.script_pubkey(), 1,),
+            Err(TransactionCreationError::NoFeeBumpTarget)
+        );
+    }
+}
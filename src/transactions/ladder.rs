@@ -0,0 +1,587 @@
+//! Fee ladders: sets of pre-signed revocation transactions spending the same output at
+//! increasing feerates, so that the one to broadcast can be picked depending on the state of
+//! the mempool.
+
+use crate::{
+    error::*,
+    scripts::*,
+    transactions::{
+        utils, CancelTransaction, EmergencyTransaction, FeePolicy, RevaultTransaction,
+        UnvaultEmergencyTransaction,
+    },
+    txins::*,
+};
+
+use miniscript::bitcoin::{secp256k1, OutPoint, SigHash, SigHashType};
+
+#[cfg(feature = "use-serde")]
+use {
+    serde::de::{self, Deserialize, Deserializer},
+    serde::ser::{Serialize, Serializer},
+};
+
+// Get the outpoint spent by the (single) P2WSH input of a revocation transaction
+fn revocation_outpoint(tx: &impl RevaultTransaction) -> OutPoint {
+    let index = utils::p2wsh_input_index(tx.psbt())
+        .expect("We are always created with a (single) P2WSH input");
+    tx.tx().input[index].previous_output
+}
+
+/// Boilerplate for defining a fee ladder of a revocation transaction, as a list of
+/// (feerate, transaction) ordered by increasing feerate.
+macro_rules! impl_fee_ladder {
+    ( $ladder_name:ident, $transaction_name:ident, $doc_comment:meta ) => {
+        #[$doc_comment]
+        #[derive(Debug, Clone, PartialEq)]
+        pub struct $ladder_name(Vec<(u64, $transaction_name)>);
+
+        impl $ladder_name {
+            // Internal routine to check the entries and order them by increasing feerate
+            fn from_entries(
+                mut entries: Vec<(u64, $transaction_name)>,
+            ) -> Result<$ladder_name, TransactionCreationError> {
+                if entries.is_empty() {
+                    return Err(TransactionCreationError::InvalidFeeLadder);
+                }
+                entries.sort_by_key(|(feerate, _)| *feerate);
+
+                // All entries must spend the same output, at distinct feerates
+                let outpoint = revocation_outpoint(&entries[0].1);
+                if entries
+                    .iter()
+                    .any(|(_, tx)| revocation_outpoint(tx) != outpoint)
+                    || entries.windows(2).any(|w| w[0].0 == w[1].0)
+                {
+                    return Err(TransactionCreationError::InvalidFeeLadder);
+                }
+
+                Ok($ladder_name(entries))
+            }
+
+            /// Create a ladder out of already existing transactions, for instance after fetching
+            /// them from storage. The feerate of each entry is recomputed.
+            pub fn from_transactions(
+                txs: Vec<$transaction_name>,
+            ) -> Result<$ladder_name, TransactionCreationError> {
+                let entries = txs
+                    .into_iter()
+                    .map(|tx| {
+                        let feerate = utils::revocationtx_feerate(tx.psbt())
+                            .map_err(|_| TransactionCreationError::InvalidFeeLadder)?;
+                        Ok((feerate, tx))
+                    })
+                    .collect::<Result<Vec<_>, TransactionCreationError>>()?;
+
+                $ladder_name::from_entries(entries)
+            }
+
+            /// Get the (feerate, transaction) entries of this ladder, ordered by increasing
+            /// feerate. The feerates are in sat/WU.
+            pub fn entries(&self) -> &[(u64, $transaction_name)] {
+                &self.0
+            }
+
+            /// Move the transactions out of the ladder, ordered by increasing feerate.
+            pub fn into_transactions(self) -> Vec<$transaction_name> {
+                self.0.into_iter().map(|(_, tx)| tx).collect()
+            }
+
+            /// Get the feerates, in sat/WU, of the ladder entries in increasing order.
+            pub fn feerates(&self) -> Vec<u64> {
+                self.0.iter().map(|(feerate, _)| *feerate).collect()
+            }
+
+            /// Get the transaction created at this feerate (in sat/WU), if any.
+            pub fn get(&self, feerate: u64) -> Option<&$transaction_name> {
+                self.0.iter().find(|(f, _)| *f == feerate).map(|(_, tx)| tx)
+            }
+
+            /// Get the lowest feerate entry that pays at least this feerate (in sat/WU), if any.
+            pub fn select(&self, target_feerate: u64) -> Option<&$transaction_name> {
+                self.0
+                    .iter()
+                    .find(|(feerate, _)| *feerate >= target_feerate)
+                    .map(|(_, tx)| tx)
+            }
+
+            /// Pick the entry to broadcast given the current feerate estimate (in sat/WU): the
+            /// lowest feerate entry that pays at least the estimate or, if none does, the highest
+            /// feerate one.
+            pub fn select_for_estimate(&self, feerate_estimate: u64) -> &$transaction_name {
+                self.select(feerate_estimate)
+                    .unwrap_or_else(|| self.highest())
+            }
+
+            /// Get the entry with the lowest feerate.
+            pub fn lowest(&self) -> &$transaction_name {
+                &self.0.first().expect("Never empty").1
+            }
+
+            /// Get the entry with the highest feerate.
+            pub fn highest(&self) -> &$transaction_name {
+                &self.0.last().expect("Never empty").1
+            }
+
+            /// Get the sighash of the revocation input of each entry, along with its feerate.
+            pub fn signature_hashes(&self) -> Result<Vec<(u64, SigHash)>, InputSatisfactionError> {
+                self.0
+                    .iter()
+                    .map(|(feerate, tx)| {
+                        let input_index = utils::p2wsh_input_index(tx.psbt())
+                            .expect("We are always created with a (single) P2WSH input");
+                        tx.signature_hash(input_index, SigHashType::AllPlusAnyoneCanPay)
+                            .map(|sighash| (*feerate, sighash))
+                    })
+                    .collect()
+            }
+
+            /// Add a signature for the revocation input of the entry at this feerate (in sat/WU).
+            ///
+            /// Will error with [InputSatisfactionError::OutOfBounds] if there is no entry at this
+            /// feerate.
+            pub fn add_sig<C: secp256k1::Verification>(
+                &mut self,
+                feerate: u64,
+                pubkey: secp256k1::PublicKey,
+                signature: secp256k1::Signature,
+                secp: &secp256k1::Secp256k1<C>,
+            ) -> Result<Option<Vec<u8>>, InputSatisfactionError> {
+                let tx = self
+                    .0
+                    .iter_mut()
+                    .find(|(f, _)| *f == feerate)
+                    .map(|(_, tx)| tx)
+                    .ok_or(InputSatisfactionError::OutOfBounds)?;
+                let input_index = utils::p2wsh_input_index(tx.psbt())
+                    .expect("We are always created with a (single) P2WSH input");
+                RevaultTransaction::add_signature(tx, input_index, pubkey, signature, secp)
+            }
+
+            /// Finalize all the entries of the ladder.
+            pub fn finalize(
+                &mut self,
+                secp: &secp256k1::Secp256k1<impl secp256k1::Verification>,
+            ) -> Result<(), Error> {
+                for (_, tx) in self.0.iter_mut() {
+                    tx.finalize(secp)?;
+                }
+
+                Ok(())
+            }
+        }
+
+        #[cfg(feature = "use-serde")]
+        impl Serialize for $ladder_name {
+            fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+            where
+                S: Serializer,
+            {
+                serializer.collect_seq(self.0.iter().map(|(_, tx)| tx))
+            }
+        }
+
+        #[cfg(feature = "use-serde")]
+        impl<'de> Deserialize<'de> for $ladder_name {
+            fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+            where
+                D: Deserializer<'de>,
+            {
+                let txs = Vec::<$transaction_name>::deserialize(deserializer)?;
+                $ladder_name::from_transactions(txs).map_err(de::Error::custom)
+            }
+        }
+    };
+}
+
+impl_fee_ladder!(
+    CancelLadder,
+    CancelTransaction,
+    doc = "A set of Cancel transactions spending the same Unvault output at increasing feerates."
+);
+impl CancelLadder {
+    /// Create a Cancel transaction spending this Unvault output at each of these feerates (in
+    /// sat/WU). None of them has a fee-bumping input. They are created as TRUC (version 3)
+    /// transactions if the fee policy says so.
+    ///
+    /// Will error if no feerate is given, if the same feerate is given twice, or if the revaulted
+    /// output would be below the dust limit of the fee policy at one of these feerates.
+    pub fn new(
+        unvault_input: UnvaultTxIn,
+        deposit_descriptor: &DerivedDepositDescriptor,
+        lock_time: u32,
+        feerates: &[u64],
+        fee_policy: &FeePolicy,
+    ) -> Result<CancelLadder, TransactionCreationError> {
+        let entries = feerates
+            .iter()
+            .map(|feerate| {
+                CancelTransaction::with_feerate(
+                    unvault_input.clone(),
+                    None,
+                    None,
+                    deposit_descriptor,
+                    lock_time,
+                    *feerate,
+                    fee_policy,
+                )
+                .map(|tx| (*feerate, tx))
+            })
+            .collect::<Result<Vec<_>, TransactionCreationError>>()?;
+
+        CancelLadder::from_entries(entries)
+    }
+}
+
+impl_fee_ladder!(
+    EmergencyLadder,
+    EmergencyTransaction,
+    doc =
+        "A set of Emergency transactions spending the same Deposit output at increasing feerates."
+);
+impl EmergencyLadder {
+    /// Create an Emergency transaction spending this Deposit output at each of these feerates (in
+    /// sat/WU). None of them has a fee-bumping input. They are created as TRUC (version 3)
+    /// transactions if the fee policy says so.
+    ///
+    /// Will error if no feerate is given, if the same feerate is given twice, or if the Emergency
+    /// output would be below the dust limit of the fee policy at one of these feerates.
+    pub fn new(
+        deposit_input: DepositTxIn,
+        emer_address: EmergencyAddress,
+        lock_time: u32,
+        feerates: &[u64],
+        fee_policy: &FeePolicy,
+    ) -> Result<EmergencyLadder, TransactionCreationError> {
+        let entries = feerates
+            .iter()
+            .map(|feerate| {
+                EmergencyTransaction::with_feerate(
+                    deposit_input.clone(),
+                    None,
+                    None,
+                    emer_address.clone(),
+                    lock_time,
+                    *feerate,
+                    fee_policy,
+                )
+                .map(|tx| (*feerate, tx))
+            })
+            .collect::<Result<Vec<_>, TransactionCreationError>>()?;
+
+        EmergencyLadder::from_entries(entries)
+    }
+}
+
+impl_fee_ladder!(
+    UnvaultEmergencyLadder,
+    UnvaultEmergencyTransaction,
+    doc = "A set of UnvaultEmergency transactions spending the same Unvault output at increasing feerates."
+);
+impl UnvaultEmergencyLadder {
+    /// Create an UnvaultEmergency transaction spending this Unvault output at each of these
+    /// feerates (in sat/WU). None of them has a fee-bumping input. They are created as TRUC
+    /// (version 3) transactions if the fee policy says so.
+    ///
+    /// Will error if no feerate is given, if the same feerate is given twice, or if the Emergency
+    /// output would be below the dust limit of the fee policy at one of these feerates.
+    pub fn new(
+        unvault_input: UnvaultTxIn,
+        emer_address: EmergencyAddress,
+        lock_time: u32,
+        feerates: &[u64],
+        fee_policy: &FeePolicy,
+    ) -> Result<UnvaultEmergencyLadder, TransactionCreationError> {
+        let entries = feerates
+            .iter()
+            .map(|feerate| {
+                UnvaultEmergencyTransaction::with_feerate(
+                    unvault_input.clone(),
+                    None,
+                    None,
+                    emer_address.clone(),
+                    lock_time,
+                    *feerate,
+                    fee_policy,
+                )
+                .map(|tx| (*feerate, tx))
+            })
+            .collect::<Result<Vec<_>, TransactionCreationError>>()?;
+
+        UnvaultEmergencyLadder::from_entries(entries)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transactions::{
+        tests_helpers::TestDeployment, transaction_chain_ladder, FeePolicy, TRUC_TX_VERSION,
+    };
+
+    use miniscript::bitcoin::secp256k1;
+
+    #[test]
+    fn cancel_ladder() {
+        let secp = secp256k1::Secp256k1::new();
+        let deployment = TestDeployment::new(3, 2, false, 360_000_000, &secp);
+        let fee_policy = FeePolicy::default();
+        let (unvault_tx, cancel_tx, emer_tx, unemer_tx) = deployment.chain(&fee_policy, &secp);
+        let rev_unvault_txin =
+            unvault_tx.revault_unvault_txin(&deployment.der_unvault_descriptor(&secp));
+        let der_deposit_descriptor = deployment.der_deposit_descriptor(&secp);
+
+        // The feerates are ordered, the lowest one being the Cancel of the chain
+        let cancel_feerate = fee_policy.cancel_feerate;
+        let mut cancel_ladder = CancelLadder::new(
+            rev_unvault_txin.clone(),
+            &der_deposit_descriptor,
+            0,
+            &[cancel_feerate * 4, cancel_feerate, cancel_feerate * 2],
+            &fee_policy,
+        )
+        .unwrap();
+        assert_eq!(
+            cancel_ladder.feerates(),
+            vec![cancel_feerate, cancel_feerate * 2, cancel_feerate * 4]
+        );
+        assert_eq!(cancel_ladder.lowest(), &cancel_tx);
+        assert_eq!(
+            cancel_ladder.select(cancel_feerate + 1),
+            cancel_ladder.get(cancel_feerate * 2)
+        );
+        assert_eq!(
+            cancel_ladder.select(cancel_feerate * 4),
+            Some(cancel_ladder.highest())
+        );
+        assert!(cancel_ladder.select(cancel_feerate * 4 + 1).is_none());
+        assert_eq!(
+            CancelLadder::new(
+                rev_unvault_txin.clone(),
+                &der_deposit_descriptor,
+                0,
+                &[cancel_feerate, cancel_feerate],
+                &fee_policy,
+            ),
+            Err(TransactionCreationError::InvalidFeeLadder)
+        );
+
+        // The transaction chain helper creates the same ladders
+        let emer_feerate = fee_policy.emer_feerate;
+        let (_, h_cancel_ladder, h_emer_ladder, h_unemer_ladder) = transaction_chain_ladder(
+            deployment.deposit_outpoint(),
+            deployment.deposit_amount(),
+            &deployment.deposit_descriptor,
+            &deployment.unvault_descriptor,
+            &deployment.cpfp_descriptor,
+            deployment.child_number,
+            deployment.emergency_address.clone(),
+            0,
+            &fee_policy,
+            &[cancel_feerate, cancel_feerate * 2, cancel_feerate * 4],
+            &[emer_feerate, emer_feerate * 3],
+            &secp,
+        )
+        .unwrap();
+        assert_eq!(h_cancel_ladder, cancel_ladder);
+        assert_eq!(h_emer_ladder.lowest(), &emer_tx);
+        assert_eq!(h_unemer_ladder.lowest(), &unemer_tx);
+
+        // All the rungs are signed at once
+        for (feerate, sighash) in cancel_ladder.signature_hashes().unwrap() {
+            for xpriv in deployment.stakeholders_priv.iter() {
+                let privkey = xpriv
+                    .derive_priv(&secp, &[deployment.child_number])
+                    .unwrap()
+                    .private_key;
+                let sig = secp.sign(
+                    &secp256k1::Message::from_slice(&sighash).unwrap(),
+                    &privkey.key,
+                );
+                cancel_ladder
+                    .add_sig(feerate, privkey.public_key(&secp).key, sig, &secp)
+                    .unwrap();
+            }
+        }
+        cancel_ladder.finalize(&secp).unwrap();
+        for (feerate, tx) in cancel_ladder.entries() {
+            CancelTransaction::from_raw_psbt(
+                &tx.as_psbt_serialized(),
+                &FeePolicy {
+                    cancel_feerate: *feerate,
+                    ..fee_policy.clone()
+                },
+            )
+            .expect("Created at this feerate");
+        }
+        assert_eq!(
+            CancelLadder::from_transactions(cancel_ladder.clone().into_transactions()),
+            Ok(cancel_ladder.clone())
+        );
+        #[cfg(feature = "use-serde")]
+        {
+            let serialized_ladder = serde_json::to_string(&cancel_ladder).unwrap();
+            let deserialized_ladder: CancelLadder =
+                serde_json::from_str(&serialized_ladder).unwrap();
+            assert_eq!(cancel_ladder, deserialized_ladder);
+        }
+    }
+
+    #[test]
+    fn emergency_ladders() {
+        let secp = secp256k1::Secp256k1::new();
+        let deposit_value = 360_000_000;
+        let deployment = TestDeployment::new(3, 2, false, deposit_value, &secp);
+        let fee_policy = FeePolicy::default();
+        let (unvault_tx, _, emer_tx, unemer_tx) = deployment.signed_chain(&fee_policy, &secp);
+        let rev_unvault_txin =
+            unvault_tx.revault_unvault_txin(&deployment.der_unvault_descriptor(&secp));
+
+        let emer_feerate = fee_policy.emer_feerate;
+        let emer_ladder = EmergencyLadder::new(
+            deployment.deposit_txin.clone(),
+            deployment.emergency_address.clone(),
+            0,
+            &[emer_feerate * 3, emer_feerate],
+            &fee_policy,
+        )
+        .unwrap();
+        assert_eq!(emer_ladder.feerates(), vec![emer_feerate, emer_feerate * 3]);
+        assert_eq!(emer_ladder.lowest().txid(), emer_tx.txid());
+        assert_eq!(
+            emer_ladder.select_for_estimate(emer_feerate - 1).txid(),
+            emer_tx.txid()
+        );
+        assert_eq!(
+            emer_ladder.select_for_estimate(emer_feerate + 1),
+            emer_ladder.highest()
+        );
+        // If none clears the estimate, we still broadcast the highest one
+        assert_eq!(
+            emer_ladder.select_for_estimate(emer_feerate * 10),
+            emer_ladder.highest()
+        );
+        assert_eq!(
+            EmergencyLadder::new(
+                deployment.deposit_txin.clone(),
+                deployment.emergency_address.clone(),
+                0,
+                &[emer_feerate, deposit_value],
+                &fee_policy,
+            ),
+            Err(TransactionCreationError::Dust)
+        );
+
+        let unemer_ladder = UnvaultEmergencyLadder::new(
+            rev_unvault_txin,
+            deployment.emergency_address.clone(),
+            0,
+            &[emer_feerate, emer_feerate * 3],
+            &fee_policy,
+        )
+        .unwrap();
+        assert_eq!(unemer_ladder.lowest().txid(), unemer_tx.txid());
+        assert_eq!(
+            UnvaultEmergencyLadder::from_transactions(unemer_ladder.clone().into_transactions()),
+            Ok(unemer_ladder)
+        );
+
+        // They can be created as TRUC transactions
+        let truc_emer_ladder = EmergencyLadder::new(
+            deployment.deposit_txin.clone(),
+            deployment.emergency_address.clone(),
+            0,
+            &[emer_feerate, emer_feerate * 2],
+            &FeePolicy {
+                truc: true,
+                ..fee_policy.clone()
+            },
+        )
+        .unwrap();
+        assert_eq!(truc_emer_ladder.lowest().tx().version, TRUC_TX_VERSION);
+    }
+
+    #[test]
+    fn ladders_dust() {
+        let secp = secp256k1::Secp256k1::new();
+        let deployment = TestDeployment::new(3, 2, false, 500_000, &secp);
+        let fee_policy = FeePolicy::default();
+        let (unvault_tx, cancel_tx, emer_tx, unemer_tx) = deployment.chain(&fee_policy, &secp);
+        let rev_unvault_txin =
+            unvault_tx.revault_unvault_txin(&deployment.der_unvault_descriptor(&secp));
+        let unvault_value = unvault_tx.tx().output[0].value;
+        let deposit_value = deployment.deposit_amount().as_sat();
+
+        // The highest feerate at which the revaulted output is still above the dust limit
+        let max_feerate = |input_value: u64, fees: u64, feerate: u64| {
+            let weight = fees / feerate;
+            (input_value - fee_policy.dust_limit) / weight
+        };
+
+        let cancel_feerate = fee_policy.cancel_feerate;
+        let cancel_max_feerate = max_feerate(unvault_value, cancel_tx.fees(), cancel_feerate);
+        assert!(cancel_max_feerate > cancel_feerate);
+        let der_deposit_descriptor = deployment.der_deposit_descriptor(&secp);
+        let cancel_ladder = CancelLadder::new(
+            rev_unvault_txin.clone(),
+            &der_deposit_descriptor,
+            0,
+            &[cancel_feerate, cancel_max_feerate],
+            &fee_policy,
+        )
+        .unwrap();
+        assert!(cancel_ladder.highest().tx().output[0].value >= fee_policy.dust_limit);
+        assert_eq!(
+            CancelLadder::new(
+                rev_unvault_txin.clone(),
+                &der_deposit_descriptor,
+                0,
+                &[cancel_feerate, cancel_max_feerate + 1],
+                &fee_policy,
+            ),
+            Err(TransactionCreationError::Dust)
+        );
+
+        let emer_feerate = fee_policy.emer_feerate;
+        let emer_max_feerate = max_feerate(deposit_value, emer_tx.fees(), emer_feerate);
+        assert!(emer_max_feerate > emer_feerate);
+        EmergencyLadder::new(
+            deployment.deposit_txin.clone(),
+            deployment.emergency_address.clone(),
+            0,
+            &[emer_feerate, emer_max_feerate],
+            &fee_policy,
+        )
+        .unwrap();
+        assert_eq!(
+            EmergencyLadder::new(
+                deployment.deposit_txin.clone(),
+                deployment.emergency_address.clone(),
+                0,
+                &[emer_feerate, emer_max_feerate + 1],
+                &fee_policy,
+            ),
+            Err(TransactionCreationError::Dust)
+        );
+
+        let unemer_max_feerate = max_feerate(unvault_value, unemer_tx.fees(), emer_feerate);
+        assert!(unemer_max_feerate > emer_feerate);
+        UnvaultEmergencyLadder::new(
+            rev_unvault_txin.clone(),
+            deployment.emergency_address.clone(),
+            0,
+            &[emer_feerate, unemer_max_feerate],
+            &fee_policy,
+        )
+        .unwrap();
+        assert_eq!(
+            UnvaultEmergencyLadder::new(
+                rev_unvault_txin,
+                deployment.emergency_address.clone(),
+                0,
+                &[emer_feerate, unemer_max_feerate + 1],
+                &fee_policy,
+            ),
+            Err(TransactionCreationError::Dust)
+        );
+    }
+}
@@ -21,22 +21,33 @@ use miniscript::{
     DescriptorTrait,
 };
 
-use std::{convert::TryInto, fmt};
+use std::{collections::BTreeMap, convert::TryInto, fmt};
 
 #[macro_use]
 mod utils;
 
 mod cancel;
+mod classify;
+mod coin_selection;
 mod cpfp;
 mod emergency;
+mod feebump;
+mod ladder;
 mod spend;
 mod unvault;
 mod unvaultemergency;
 
 pub use cancel::CancelTransaction;
+pub use classify::{RevaultRole, TransactionClassifier, TransactionRoles};
+pub use coin_selection::{
+    BranchAndBound, CoinCandidate, CoinSelection, CoinSelector, LargestFirst, OldestFirst,
+    SelectionTarget,
+};
 pub use cpfp::CpfpTransaction;
 pub use emergency::EmergencyTransaction;
-pub use spend::SpendTransaction;
+pub use feebump::{FeeBumpTarget, FeeBumpTransaction};
+pub use ladder::{CancelLadder, EmergencyLadder, UnvaultEmergencyLadder};
+pub use spend::{SpendTransaction, SplitSpend};
 pub use unvault::UnvaultTransaction;
 pub use unvaultemergency::UnvaultEmergencyTransaction;
 
@@ -61,9 +72,25 @@ pub const DUST_LIMIT: u64 = 200_000;
 /// and the spend. This is 0.2BTC, or 3k€ currently.
 pub const INSANE_FEES: u64 = 20_000_000;
 
+/// The value of the keyless anchor output of the revocation transactions created with one. It's
+/// the dust threshold of a "Pay To Anchor" output.
+pub const ANCHOR_VALUE: u64 = 240;
+
 /// This enables CSV and is easier to apply to all transactions anyways.
 pub const TX_VERSION: i32 = 2;
 
+/// The version of the TRUC ("Topologically Restricted Until Confirmation") transactions, which
+/// get the pinning protections of package relay.
+///
+/// <https://github.com/bitcoin/bips/blob/master/bip-0431.mediawiki>
+pub const TRUC_TX_VERSION: i32 = 3;
+
+/// Maximum weight of a TRUC transaction to be relayed.
+pub const TRUC_MAX_WEIGHT: u64 = 40_000;
+
+/// Maximum weight of a TRUC transaction spending an unconfirmed TRUC transaction to be relayed.
+pub const TRUC_CHILD_MAX_WEIGHT: u64 = 4_000;
+
 /// Maximum weight of a transaction to be relayed.
 ///
 /// <https://github.com/bitcoin/bitcoin/blob/590e49ccf2af27c6c1f1e0eb8be3a4bf4d92ce8b/src/policy/policy.h#L23-L24>
@@ -73,6 +100,51 @@ pub const MAX_STANDARD_TX_WEIGHT: u32 = 400_000;
 /// of the inputs minus the fees is less than CPFP_MIN_CHANGE, we'll throw everything in fees.
 pub const CPFP_MIN_CHANGE: u64 = 10_000;
 
+/// The fee parameters of a Revault deployment.
+///
+/// All participants must agree on the same policy, as it determines the content of the
+/// pre-signed transactions. The [Default] policy uses the constants defined in this module.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FeePolicy {
+    /// The feerate, in sat / WU, to create the unvaulting transactions with.
+    pub unvault_feerate: u64,
+    /// The feerate, in sat / WU, to create the Cancel transaction with.
+    pub cancel_feerate: u64,
+    /// The feerate, in sat / WU, to create the Emergency transactions with.
+    pub emer_feerate: u64,
+    /// The value of the CPFP output in the Unvault transaction.
+    pub unvault_cpfp_value: u64,
+    /// We refuse to create a stakeholder-pre-signed transaction that would create an output
+    /// worth less than this amount of sats.
+    pub dust_limit: u64,
+    /// The maximum fees, in sats, we accept to pay for an Unvault or a Spend transaction.
+    pub insane_fees: u64,
+    /// Whether to create the Unvault and revocation transactions as TRUC (version 3)
+    /// transactions. They must then be fee-bumped by TRUC CPFP transactions.
+    pub truc: bool,
+}
+
+impl Default for FeePolicy {
+    fn default() -> Self {
+        FeePolicy {
+            unvault_feerate: UNVAULT_TX_FEERATE,
+            cancel_feerate: CANCEL_TX_FEERATE,
+            emer_feerate: EMER_TX_FEERATE,
+            unvault_cpfp_value: UNVAULT_CPFP_VALUE,
+            dust_limit: DUST_LIMIT,
+            insane_fees: INSANE_FEES,
+            truc: false,
+        }
+    }
+}
+
+impl FeePolicy {
+    /// The version of the Unvault and revocation transactions created under this policy.
+    pub fn tx_version(&self) -> i32 {
+        utils::tx_version(self.truc)
+    }
+}
+
 /// This private module is used to make mutable references to the PSBT inside transaction newtypes
 /// available to functions inside the transaction module, but not beyond that. This is needed to
 /// guarantee invariants that could not be guaranteed if users had arbitrary mutable access to the
@@ -175,6 +247,10 @@ pub trait RevaultTransaction: fmt::Debug + Clone + PartialEq {
     fn as_psbt_serialized(&self) -> Vec<u8>;
 
     /// Create a RevaultTransaction from a base64-encoded BIP174-serialized transaction.
+    ///
+    /// This only checks the structure of the transaction. The Unvault and revocation
+    /// transactions of a counterparty must be parsed with their `from_raw_psbt` method, to
+    /// check them against the [FeePolicy] of the deployment.
     fn from_psbt_serialized(raw_psbt: &[u8]) -> Result<Self, TransactionSerialisationError>;
 
     /// Get the BIP174-serialized (inner) transaction encoded in base64.
@@ -243,6 +319,8 @@ impl<T: inner_mut::PrivateInnerMut + fmt::Debug + Clone + PartialEq> RevaultTran
                 .as_ref()
                 .ok_or(InputSatisfactionError::MissingWitnessScript)?;
             Ok(cache.signature_hash(input_index, &witscript, prev_txo.value, sighash_type))
+        } else if prev_txo.script_pubkey == anchor_script_pubkey() {
+            Err(InputSatisfactionError::KeylessInput)
         } else {
             assert!(
                 prev_txo.script_pubkey.is_v0_p2wpkh(),
@@ -296,6 +374,8 @@ impl<T: inner_mut::PrivateInnerMut + fmt::Debug + Clone + PartialEq> RevaultTran
                 expected_script_pubkey == prev_txo.script_pubkey,
                 "We create TxOut scriptPubKey out of this exact witnessScript."
             );
+        } else if prev_txo.script_pubkey == anchor_script_pubkey() {
+            return Err(InputSatisfactionError::KeylessInput);
         } else {
             // We only use P2WSH utxos internally. External inputs are only ever added for fee
             // bumping, for which we require P2WPKH.
@@ -311,8 +391,9 @@ impl<T: inner_mut::PrivateInnerMut + fmt::Debug + Clone + PartialEq> RevaultTran
             .expect("We always set the SigHashType in the constructor.");
         let sighash = self.signature_hash(input_index, expected_sighash_type)?;
         let sighash = secp256k1::Message::from_slice(&sighash).expect("sighash is 32 a bytes hash");
-        secp.verify(&sighash, &signature, &pubkey)
-            .map_err(|_| InputSatisfactionError::InvalidSignature(signature, pubkey, sighash))?;
+        secp.verify(&sighash, &signature, &pubkey).map_err(|_| {
+            InputSatisfactionError::InvalidSignature(Box::new((signature, pubkey, sighash)))
+        })?;
 
         let pubkey = BitcoinPubKey {
             compressed: true,
@@ -338,7 +419,7 @@ impl<T: inner_mut::PrivateInnerMut + fmt::Debug + Clone + PartialEq> RevaultTran
         // libbitcoinconsensus' one.
         let mut psbt = self.psbt_mut();
 
-        miniscript::psbt::finalize(&mut psbt, ctx)
+        utils::finalize_psbt(&mut psbt, ctx)
             .map_err(|e| Error::TransactionFinalisation(e.to_string()))?;
 
         // Miniscript's finalize does not check against libbitcoinconsensus. And we are better safe
@@ -352,7 +433,7 @@ impl<T: inner_mut::PrivateInnerMut + fmt::Debug + Clone + PartialEq> RevaultTran
     /// Slighty more efficient than calling [RevaultTransaction::finalize] on a clone as it gets
     /// rid of the belt-and-suspenders checks.
     fn is_finalizable(&self, ctx: &secp256k1::Secp256k1<impl secp256k1::Verification>) -> bool {
-        miniscript::psbt::finalize(&mut self.psbt().clone(), ctx).is_ok()
+        utils::finalize_psbt(&mut self.psbt().clone(), ctx).is_ok()
     }
 
     /// Check if the transaction was already finalized.
@@ -380,7 +461,7 @@ impl<T: inner_mut::PrivateInnerMut + fmt::Debug + Clone + PartialEq> RevaultTran
         }
         assert_eq!(self.psbt().inputs.len(), self.tx().input.len());
 
-        miniscript::psbt::interpreter_check(&self.psbt(), ctx).is_ok()
+        utils::interpreter_check(&self.psbt(), ctx).is_ok()
     }
 
     /// Verify all PSBT inputs against libbitcoinconsensus
@@ -509,6 +590,26 @@ pub trait CpfpableTransaction: RevaultTransaction {
         None
     }
 
+    /// Return the txin refering to the keyless anchor output of this transaction, if any.
+    fn anchor_txin(&self) -> Option<AnchorTxIn> {
+        let anchor_spk = anchor_script_pubkey();
+
+        self.tx()
+            .output
+            .iter()
+            .position(|txo| txo.script_pubkey == anchor_spk)
+            .map(|i| {
+                let txo = &self.tx().output[i];
+                AnchorTxIn::new(
+                    OutPoint {
+                        txid: self.txid(),
+                        vout: i.try_into().expect("vout doesn't fit in a u32?"),
+                    },
+                    AnchorTxOut::new(Amount::from_sat(txo.value)),
+                )
+            })
+    }
+
     /// Get the feerate of this transaction, assuming fully-satisfied inputs. If the transaction
     /// is already finalized, returns the exact feerate. Otherwise computes the maximum reasonable
     /// weight of a satisfaction and returns the feerate based on this estimation.
@@ -528,6 +629,47 @@ pub trait CpfpableTransaction: RevaultTransaction {
     fn max_weight(&self) -> u64;
 }
 
+/// A transaction revoking a deposit ([Emergency](EmergencyTransaction)) or an unvault
+/// ([Cancel](CancelTransaction), [UnvaultEmergency](UnvaultEmergencyTransaction)). Its feerate
+/// may be bumped by a [FeeBumpTxIn], as created by a [FeeBumpTransaction].
+///
+/// The revocation input is signed with `ALL|ANYONECANPAY`, so the fee-bumping input can be
+/// added, removed or replaced after the stakeholders signed the transaction without
+/// invalidating their signatures.
+pub trait RevocationTransaction: CpfpableTransaction {
+    /// Add a fee-bumping input to this transaction, keeping the existing signatures. Returns the
+    /// resulting feerate of the transaction, in sat/WU.
+    ///
+    /// Will error if the transaction already has a fee-bumping input, or if it has an anchor
+    /// output as it's then fee-bumped through CPFP.
+    fn add_feebump_input(
+        &mut self,
+        feebump_input: FeeBumpTxIn,
+    ) -> Result<u64, TransactionCreationError>;
+
+    /// Remove the fee-bumping input of this transaction, if it has one, keeping the signatures
+    /// of the revocation input.
+    fn remove_feebump_input(&mut self) -> Option<FeeBumpTxIn>;
+
+    /// Replace the fee-bumping input of this transaction, if it has one, by this one. Returns
+    /// the resulting feerate of the transaction, in sat/WU.
+    ///
+    /// On error, the transaction is left untouched.
+    fn replace_feebump_input(
+        &mut self,
+        feebump_input: FeeBumpTxIn,
+    ) -> Result<u64, TransactionCreationError> {
+        let previous = self.remove_feebump_input();
+        self.add_feebump_input(feebump_input).map_err(|e| {
+            if let Some(previous) = previous {
+                self.add_feebump_input(previous)
+                    .expect("It was part of the transaction");
+            }
+            e
+        })
+    }
+}
+
 /// The funding transaction, we don't create nor sign it.
 #[derive(Debug, Clone, PartialEq)]
 pub struct DepositTransaction(pub Transaction);
@@ -548,10 +690,6 @@ impl DepositTransaction {
     }
 }
 
-/// The fee-bumping transaction, we don't create nor sign it.
-#[derive(Debug, Clone, PartialEq)]
-pub struct FeeBumpTransaction(pub Transaction);
-
 /// Get the chain of pre-signed transaction out of a deposit available for a manager.
 /// No feebump input.
 #[allow(clippy::too_many_arguments)]
@@ -563,6 +701,7 @@ pub fn transaction_chain_manager<C: secp256k1::Verification>(
     cpfp_descriptor: &CpfpDescriptor,
     derivation_index: ChildNumber,
     lock_time: u32,
+    fee_policy: &FeePolicy,
     secp: &secp256k1::Secp256k1<C>,
 ) -> Result<(UnvaultTransaction, CancelTransaction), Error> {
     let (der_deposit_descriptor, der_unvault_descriptor, der_cpfp_descriptor) = (
@@ -580,6 +719,7 @@ pub fn transaction_chain_manager<C: secp256k1::Verification>(
         &der_unvault_descriptor,
         &der_cpfp_descriptor,
         lock_time,
+        fee_policy,
     )?;
 
     let cancel_tx = CancelTransaction::new(
@@ -587,6 +727,7 @@ pub fn transaction_chain_manager<C: secp256k1::Verification>(
         None,
         &der_deposit_descriptor,
         lock_time,
+        fee_policy,
     )?;
 
     Ok((unvault_tx, cancel_tx))
@@ -603,6 +744,7 @@ pub fn transaction_chain<C: secp256k1::Verification>(
     derivation_index: ChildNumber,
     emer_address: EmergencyAddress,
     lock_time: u32,
+    fee_policy: &FeePolicy,
     secp: &secp256k1::Secp256k1<C>,
 ) -> Result<
     (
@@ -621,6 +763,7 @@ pub fn transaction_chain<C: secp256k1::Verification>(
         cpfp_descriptor,
         derivation_index,
         lock_time,
+        fee_policy,
         secp,
     )?;
 
@@ -629,17 +772,287 @@ pub fn transaction_chain<C: secp256k1::Verification>(
         deposit_outpoint,
         DepositTxOut::new(deposit_amount, &der_deposit_descriptor),
     );
-    let emergency_tx =
-        EmergencyTransaction::new(deposit_txin, None, emer_address.clone(), lock_time)?;
+    let emergency_tx = EmergencyTransaction::new(
+        deposit_txin,
+        None,
+        emer_address.clone(),
+        lock_time,
+        fee_policy,
+    )?;
 
     let der_unvault_descriptor = unvault_descriptor.derive(derivation_index, secp);
     let unvault_txin = unvault_tx.revault_unvault_txin(&der_unvault_descriptor);
     let unvault_emergency_tx =
-        UnvaultEmergencyTransaction::new(unvault_txin, None, emer_address, lock_time)?;
+        UnvaultEmergencyTransaction::new(unvault_txin, None, emer_address, lock_time, fee_policy)?;
 
     Ok((unvault_tx, cancel_tx, emergency_tx, unvault_emergency_tx))
 }
 
+/// Get the entire chain of pre-signed transaction for this derivation index out of a deposit,
+/// with fee ladders instead of single revocation transactions: the Cancel transactions are
+/// created at each of the `cancel_feerates` and both Emergency transactions at each of the
+/// `emer_feerates` (in sat/WU). No feebump input.
+#[allow(clippy::too_many_arguments)]
+pub fn transaction_chain_ladder<C: secp256k1::Verification>(
+    deposit_outpoint: OutPoint,
+    deposit_amount: Amount,
+    deposit_descriptor: &DepositDescriptor,
+    unvault_descriptor: &UnvaultDescriptor,
+    cpfp_descriptor: &CpfpDescriptor,
+    derivation_index: ChildNumber,
+    emer_address: EmergencyAddress,
+    lock_time: u32,
+    fee_policy: &FeePolicy,
+    cancel_feerates: &[u64],
+    emer_feerates: &[u64],
+    secp: &secp256k1::Secp256k1<C>,
+) -> Result<
+    (
+        UnvaultTransaction,
+        CancelLadder,
+        EmergencyLadder,
+        UnvaultEmergencyLadder,
+    ),
+    Error,
+> {
+    let (der_deposit_descriptor, der_unvault_descriptor, der_cpfp_descriptor) = (
+        deposit_descriptor.derive(derivation_index, secp),
+        unvault_descriptor.derive(derivation_index, secp),
+        cpfp_descriptor.derive(derivation_index, secp),
+    );
+
+    let deposit_txin = DepositTxIn::new(
+        deposit_outpoint,
+        DepositTxOut::new(deposit_amount, &der_deposit_descriptor),
+    );
+    let unvault_tx = UnvaultTransaction::new(
+        deposit_txin.clone(),
+        &der_unvault_descriptor,
+        &der_cpfp_descriptor,
+        lock_time,
+        fee_policy,
+    )?;
+    let unvault_txin = unvault_tx.revault_unvault_txin(&der_unvault_descriptor);
+
+    let cancel_ladder = CancelLadder::new(
+        unvault_txin.clone(),
+        &der_deposit_descriptor,
+        lock_time,
+        cancel_feerates,
+        fee_policy,
+    )?;
+    let emergency_ladder = EmergencyLadder::new(
+        deposit_txin,
+        emer_address.clone(),
+        lock_time,
+        emer_feerates,
+        fee_policy,
+    )?;
+    let unvault_emergency_ladder = UnvaultEmergencyLadder::new(
+        unvault_txin,
+        emer_address,
+        lock_time,
+        emer_feerates,
+        fee_policy,
+    )?;
+
+    Ok((
+        unvault_tx,
+        cancel_ladder,
+        emergency_ladder,
+        unvault_emergency_ladder,
+    ))
+}
+
+// Create the batched Unvault transaction spending this set of deposits, returning it along with
+// the derivation index of its outputs: the highest of the deposits one.
+#[allow(clippy::too_many_arguments)]
+fn batched_unvault_tx<C: secp256k1::Verification>(
+    deposit_txins: Vec<(OutPoint, Amount, ChildNumber)>,
+    deposit_descriptor: &DepositDescriptor,
+    unvault_descriptor: &UnvaultDescriptor,
+    cpfp_descriptor: &CpfpDescriptor,
+    lock_time: u32,
+    fee_policy: &FeePolicy,
+    secp: &secp256k1::Secp256k1<C>,
+) -> Result<(UnvaultTransaction, ChildNumber), TransactionCreationError> {
+    let max_deriv_index = deposit_txins
+        .iter()
+        .map(|(_, _, deriv_index)| *deriv_index)
+        .max()
+        .unwrap_or_else(|| ChildNumber::from(0));
+    let deposit_txins = deposit_txins
+        .into_iter()
+        .map(|(outpoint, amount, deriv_index)| {
+            let der_deposit_desc = deposit_descriptor.derive(deriv_index, secp);
+            DepositTxIn::new(outpoint, DepositTxOut::new(amount, &der_deposit_desc))
+        })
+        .collect();
+
+    let unvault_tx = UnvaultTransaction::new_batched(
+        deposit_txins,
+        &unvault_descriptor.derive(max_deriv_index, secp),
+        &cpfp_descriptor.derive(max_deriv_index, secp),
+        lock_time,
+        fee_policy,
+    )?;
+
+    Ok((unvault_tx, max_deriv_index))
+}
+
+/// Get the entire chain of pre-signed transaction out of a set of deposits and derivation indexes,
+/// with a single batched Unvault transaction spending all of them. The derivation index used
+/// for the Unvault and CPFP outputs, as well as for the deposit output of the Cancel transaction,
+/// is the highest of the deposits one. An Emergency transaction is created for each deposit, in
+/// the order of the set. No feebump input.
+///
+/// Panics if the set of deposits is empty.
+#[allow(clippy::too_many_arguments)]
+pub fn transaction_chain_batched<C: secp256k1::Verification>(
+    deposit_txins: Vec<(OutPoint, Amount, ChildNumber)>,
+    deposit_descriptor: &DepositDescriptor,
+    unvault_descriptor: &UnvaultDescriptor,
+    cpfp_descriptor: &CpfpDescriptor,
+    emer_address: EmergencyAddress,
+    lock_time: u32,
+    fee_policy: &FeePolicy,
+    secp: &secp256k1::Secp256k1<C>,
+) -> Result<
+    (
+        UnvaultTransaction,
+        CancelTransaction,
+        Vec<EmergencyTransaction>,
+        UnvaultEmergencyTransaction,
+    ),
+    Error,
+> {
+    let emergency_txs = deposit_txins
+        .iter()
+        .map(|(outpoint, amount, deriv_index)| {
+            let der_deposit_desc = deposit_descriptor.derive(*deriv_index, secp);
+            EmergencyTransaction::new(
+                DepositTxIn::new(*outpoint, DepositTxOut::new(*amount, &der_deposit_desc)),
+                None,
+                emer_address.clone(),
+                lock_time,
+                fee_policy,
+            )
+        })
+        .collect::<Result<Vec<EmergencyTransaction>, TransactionCreationError>>()?;
+
+    let (unvault_tx, deriv_index) = batched_unvault_tx(
+        deposit_txins,
+        deposit_descriptor,
+        unvault_descriptor,
+        cpfp_descriptor,
+        lock_time,
+        fee_policy,
+        secp,
+    )?;
+    let der_unvault_descriptor = unvault_descriptor.derive(deriv_index, secp);
+
+    let unvault_txin = unvault_tx.revault_unvault_txin(&der_unvault_descriptor);
+    let cancel_tx = CancelTransaction::new(
+        unvault_txin.clone(),
+        None,
+        &deposit_descriptor.derive(deriv_index, secp),
+        lock_time,
+        fee_policy,
+    )?;
+    let unvault_emergency_tx =
+        UnvaultEmergencyTransaction::new(unvault_txin, None, emer_address, lock_time, fee_policy)?;
+
+    Ok((unvault_tx, cancel_tx, emergency_txs, unvault_emergency_tx))
+}
+
+/// Get the entire chain of pre-signed transaction for this derivation index out of a deposit,
+/// with a partial Unvault transaction only unvaulting `unvault_amount` and sending the remainder
+/// to a new deposit at `change_derivation_index`. In addition to the usual chain, returns the
+/// Emergency transaction of the deposit change, which must be signed before the Unvault. No
+/// feebump input.
+#[allow(clippy::too_many_arguments)]
+pub fn transaction_chain_partial<C: secp256k1::Verification>(
+    deposit_outpoint: OutPoint,
+    deposit_amount: Amount,
+    unvault_amount: Amount,
+    deposit_descriptor: &DepositDescriptor,
+    unvault_descriptor: &UnvaultDescriptor,
+    cpfp_descriptor: &CpfpDescriptor,
+    derivation_index: ChildNumber,
+    change_derivation_index: ChildNumber,
+    emer_address: EmergencyAddress,
+    lock_time: u32,
+    fee_policy: &FeePolicy,
+    secp: &secp256k1::Secp256k1<C>,
+) -> Result<
+    (
+        UnvaultTransaction,
+        CancelTransaction,
+        EmergencyTransaction,
+        UnvaultEmergencyTransaction,
+        EmergencyTransaction,
+    ),
+    Error,
+> {
+    let (der_deposit_descriptor, der_unvault_descriptor, der_cpfp_descriptor) = (
+        deposit_descriptor.derive(derivation_index, secp),
+        unvault_descriptor.derive(derivation_index, secp),
+        cpfp_descriptor.derive(derivation_index, secp),
+    );
+    let der_change_descriptor = deposit_descriptor.derive(change_derivation_index, secp);
+
+    let deposit_txin = DepositTxIn::new(
+        deposit_outpoint,
+        DepositTxOut::new(deposit_amount, &der_deposit_descriptor),
+    );
+    let unvault_tx = UnvaultTransaction::new_partial(
+        deposit_txin.clone(),
+        unvault_amount,
+        &der_unvault_descriptor,
+        &der_cpfp_descriptor,
+        &der_change_descriptor,
+        lock_time,
+        fee_policy,
+    )?;
+
+    let unvault_txin = unvault_tx.revault_unvault_txin(&der_unvault_descriptor);
+    let cancel_tx = CancelTransaction::new(
+        unvault_txin.clone(),
+        None,
+        &der_deposit_descriptor,
+        lock_time,
+        fee_policy,
+    )?;
+    let emergency_tx = EmergencyTransaction::new(
+        deposit_txin,
+        None,
+        emer_address.clone(),
+        lock_time,
+        fee_policy,
+    )?;
+    let unvault_emergency_tx = UnvaultEmergencyTransaction::new(
+        unvault_txin,
+        None,
+        emer_address.clone(),
+        lock_time,
+        fee_policy,
+    )?;
+
+    let change_txin = unvault_tx
+        .deposit_change_txin(&der_change_descriptor)
+        .expect("Just created it with a deposit change");
+    let change_emergency_tx =
+        EmergencyTransaction::new(change_txin, None, emer_address, lock_time, fee_policy)?;
+
+    Ok((
+        unvault_tx,
+        cancel_tx,
+        emergency_tx,
+        unvault_emergency_tx,
+        change_emergency_tx,
+    ))
+}
+
 /// Get a spend transaction out of a list of deposits and derivation indexes.
 /// The derivation index used for the Spend CPFP is the highest of the deposits one.
 #[allow(clippy::too_many_arguments)]
@@ -651,6 +1064,7 @@ pub fn spend_tx_from_deposits<C: secp256k1::Verification>(
     unvault_descriptor: &UnvaultDescriptor,
     cpfp_descriptor: &CpfpDescriptor,
     lock_time: u32,
+    fee_policy: &FeePolicy,
     check_insane_fees: bool,
     secp: &secp256k1::Secp256k1<C>,
 ) -> Result<SpendTransaction, TransactionCreationError> {
@@ -667,8 +1081,14 @@ pub fn spend_tx_from_deposits<C: secp256k1::Verification>(
                 max_deriv_index = deriv_index;
             }
 
-            UnvaultTransaction::new(txin, &der_unvault_desc, &der_cpfp_desc, lock_time)
-                .map(|unvault_tx| unvault_tx.spend_unvault_txin(&der_unvault_desc))
+            UnvaultTransaction::new(
+                txin,
+                &der_unvault_desc,
+                &der_cpfp_desc,
+                lock_time,
+                fee_policy,
+            )
+            .map(|unvault_tx| unvault_tx.spend_unvault_txin(&der_unvault_desc))
         })
         .collect::<Result<Vec<UnvaultTxIn>, TransactionCreationError>>()?;
 
@@ -679,6 +1099,176 @@ pub fn spend_tx_from_deposits<C: secp256k1::Verification>(
         change_txo,
         &der_cpfp_descriptor,
         lock_time,
+        fee_policy,
+        check_insane_fees,
+    )
+}
+
+/// Plan a spend transaction paying to these `spend_txos` out of the available vaults, at this
+/// `feerate` (in sat/WU). The vaults are selected largest first, after deducting the cost of
+/// their Unvault transaction. Vaults too small to be unvaulted are ignored.
+///
+/// The remainder is sent back to a deposit at `change_index` if it is above the dust limit of
+/// the fee policy, otherwise it is given to miners. The fees paid by the Spend transaction are
+/// checked against the insane fees of the fee policy, and the derivation index used for the
+/// Spend CPFP is the highest of the selected vaults one.
+///
+/// Returns the Spend transaction along with the Unvault transactions it spends, in the order of
+/// its inputs. Selection stops before a vault would make the Spend transaction non-standard: it
+/// will error with [TransactionCreationError::TooLarge] if the vaults selected until then can't
+/// cover the outputs and the fees, or [TransactionCreationError::InsufficientFunds] if all the
+/// available vaults can't.
+#[allow(clippy::too_many_arguments)]
+pub fn plan_spend_tx<C: secp256k1::Verification>(
+    available_vaults: Vec<(OutPoint, Amount, ChildNumber)>,
+    spend_txos: Vec<SpendTxOut>,
+    feerate: u64,
+    change_index: ChildNumber,
+    deposit_descriptor: &DepositDescriptor,
+    unvault_descriptor: &UnvaultDescriptor,
+    cpfp_descriptor: &CpfpDescriptor,
+    lock_time: u32,
+    fee_policy: &FeePolicy,
+    secp: &secp256k1::Secp256k1<C>,
+) -> Result<(SpendTransaction, Vec<UnvaultTransaction>), TransactionCreationError> {
+    let mut candidates = Vec::with_capacity(available_vaults.len());
+    for (outpoint, amount, deriv_index) in available_vaults {
+        let der_deposit_desc = deposit_descriptor.derive(deriv_index, secp);
+        let der_unvault_desc = unvault_descriptor.derive(deriv_index, secp);
+        let der_cpfp_desc = cpfp_descriptor.derive(deriv_index, secp);
+
+        let txin = DepositTxIn::new(outpoint, DepositTxOut::new(amount, &der_deposit_desc));
+        match UnvaultTransaction::new(
+            txin,
+            &der_unvault_desc,
+            &der_cpfp_desc,
+            lock_time,
+            fee_policy,
+        ) {
+            Ok(unvault_tx) => {
+                let unvault_txin = unvault_tx.spend_unvault_txin(&der_unvault_desc);
+                candidates.push((unvault_tx, unvault_txin, deriv_index));
+            }
+            Err(TransactionCreationError::Dust) => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    candidates.sort_by_key(|(_, txin, _)| std::cmp::Reverse(txin.txout().txout().value));
+
+    let outputs_value = spend_txos
+        .iter()
+        .try_fold(0u64, |sum, txo| sum.checked_add(txo.txout().value))
+        .ok_or(TransactionCreationError::InsaneAmounts)?;
+    let der_change_descriptor = deposit_descriptor.derive(change_index, secp);
+    // The weight of the transaction doesn't depend on the derivation index of the CPFP output
+    let dummy_cpfp_descriptor = cpfp_descriptor.derive(change_index, secp);
+
+    // Select vaults until they pay for the outputs, the fees and the CPFP output without change
+    let mut unvault_txins = Vec::with_capacity(candidates.len());
+    let (mut inputs_value, mut inputs_sat_weight): (u64, u64) = (0, 0);
+    let mut max_deriv_index = ChildNumber::from(0);
+    let (mut enough, mut too_large) = (false, false);
+    for (_, txin, deriv_index) in candidates.iter() {
+        unvault_txins.push(txin.clone());
+        let sat_weight = inputs_sat_weight + txin.txout().max_sat_weight() as u64;
+        let weight = sat_weight
+            + SpendTransaction::witstrip_weight_for(
+                &unvault_txins,
+                &spend_txos,
+                None,
+                &dummy_cpfp_descriptor,
+                lock_time,
+            );
+        // All the Unvault outputs have the same weight, so a smaller vault wouldn't fit either
+        if weight > MAX_STANDARD_TX_WEIGHT as u64 {
+            unvault_txins.pop();
+            too_large = true;
+            break;
+        }
+
+        inputs_sat_weight = sat_weight;
+        inputs_value = inputs_value
+            .checked_add(txin.txout().txout().value)
+            .ok_or(TransactionCreationError::InsaneAmounts)?;
+        if *deriv_index > max_deriv_index {
+            max_deriv_index = *deriv_index;
+        }
+        if inputs_value >= spend::needed_value(feerate, weight, outputs_value)? {
+            enough = true;
+            break;
+        }
+    }
+    if too_large {
+        return Err(TransactionCreationError::TooLarge);
+    }
+    if !enough {
+        return Err(TransactionCreationError::InsufficientFunds);
+    }
+
+    let spend_tx = SpendTransaction::new_with_feerate(
+        unvault_txins,
+        spend_txos,
+        &der_change_descriptor,
+        &cpfp_descriptor.derive(max_deriv_index, secp),
+        feerate,
+        lock_time,
+        fee_policy,
+        true,
+    )?;
+    let mut unvault_txs: BTreeMap<OutPoint, UnvaultTransaction> = candidates
+        .into_iter()
+        .map(|(unvault_tx, unvault_txin, _)| (unvault_txin.outpoint(), unvault_tx))
+        .collect();
+    let unvault_txs = spend_tx
+        .tx()
+        .input
+        .iter()
+        .map(|txin| {
+            unvault_txs
+                .remove(&txin.previous_output)
+                .expect("Spend inputs are selected among the candidates")
+        })
+        .collect();
+
+    Ok((spend_tx, unvault_txs))
+}
+
+/// Get a spend transaction out of a set of deposits and derivation indexes, spending the output
+/// of the single batched Unvault transaction created by [transaction_chain_batched].
+/// The derivation index used for the Spend CPFP is the highest of the deposits one.
+///
+/// Panics if the set of deposits is empty.
+#[allow(clippy::too_many_arguments)]
+pub fn spend_tx_from_deposits_batched<C: secp256k1::Verification>(
+    deposit_txins: Vec<(OutPoint, Amount, ChildNumber)>,
+    spend_txos: Vec<SpendTxOut>,
+    change_txo: Option<DepositTxOut>,
+    deposit_descriptor: &DepositDescriptor,
+    unvault_descriptor: &UnvaultDescriptor,
+    cpfp_descriptor: &CpfpDescriptor,
+    lock_time: u32,
+    fee_policy: &FeePolicy,
+    check_insane_fees: bool,
+    secp: &secp256k1::Secp256k1<C>,
+) -> Result<SpendTransaction, TransactionCreationError> {
+    let (unvault_tx, deriv_index) = batched_unvault_tx(
+        deposit_txins,
+        deposit_descriptor,
+        unvault_descriptor,
+        cpfp_descriptor,
+        lock_time,
+        fee_policy,
+        secp,
+    )?;
+    let unvault_txin = unvault_tx.spend_unvault_txin(&unvault_descriptor.derive(deriv_index, secp));
+
+    SpendTransaction::new(
+        vec![unvault_txin],
+        spend_txos,
+        change_txo,
+        &cpfp_descriptor.derive(deriv_index, secp),
+        lock_time,
+        fee_policy,
         check_insane_fees,
     )
 }
@@ -688,10 +1278,18 @@ pub mod tests_helpers;
 
 #[cfg(test)]
 mod tests {
-    use super::tests_helpers::derive_transactions;
-    use crate::{error::*, scripts::*};
-
-    use miniscript::bitcoin::{blockdata::constants::COIN_VALUE, secp256k1, OutPoint};
+    use super::{
+        plan_spend_tx, tests_helpers::derive_transactions, tests_helpers::TestDeployment,
+        CpfpableTransaction, FeePolicy, RevaultTransaction, MAX_STANDARD_TX_WEIGHT,
+    };
+    use crate::{error::*, scripts::*, txouts::*};
+
+    use miniscript::{
+        bitcoin::{
+            blockdata::constants::COIN_VALUE, secp256k1, util::bip32, Amount, OutPoint, TxOut,
+        },
+        DescriptorTrait,
+    };
 
     use std::str::FromStr;
 
@@ -741,14 +1339,14 @@ mod tests {
                 234_631,
             ),
         ];
-        // Test the dust limit
+        // Test the dust limit: the revocation transactions may not create an output below it
         assert_eq!(
             derive_transactions(
                 2,
                 1,
                 csv,
                 deposit_prevout,
-                234_631,
+                292_381,
                 feebump_prevout,
                 feebump_value,
                 unvaults_spent.clone(),
@@ -774,13 +1372,13 @@ mod tests {
         )
         .expect_err("Unclean CSV");
 
-        // Absolute minimum
+        // Absolute minimum, for the largest CSV encoding
         derive_transactions(
             2,
             1,
             csv,
             deposit_prevout,
-            234_632,
+            292_607,
             feebump_prevout,
             feebump_value,
             unvaults_spent.clone(),
@@ -788,7 +1386,7 @@ mod tests {
             &secp,
         )
         .expect(&format!(
-            "Tx chain with 2 stakeholders, 1 manager, {} csv, 235_250 deposit",
+            "Tx chain with 2 stakeholders, 1 manager, {} csv, 292_607 deposit",
             csv
         ));
         // 1 BTC
@@ -861,6 +1459,167 @@ mod tests {
         ));
     }
 
+    #[test]
+    fn spend_planning() {
+        let secp = secp256k1::Secp256k1::new();
+        let deployment = TestDeployment::new(3, 2, false, 360_000_000, &secp);
+        let fee_policy = FeePolicy::default();
+        let deposit_txid = deployment.deposit_outpoint().txid;
+        let deposit_value = deployment.deposit_amount().as_sat();
+
+        // A Spend can be planned out of a set of available vaults, the largest ones being picked
+        // first. A vault too small to be unvaulted is never picked.
+        let available_vaults: Vec<(OutPoint, Amount, bip32::ChildNumber)> = (1..5)
+            .map(|i| {
+                (
+                    OutPoint {
+                        txid: deposit_txid,
+                        vout: 20 + i,
+                    },
+                    Amount::from_sat(i as u64 * deposit_value),
+                    bip32::ChildNumber::from(20 + i),
+                )
+            })
+            .chain(std::iter::once((
+                OutPoint {
+                    txid: deposit_txid,
+                    vout: 25,
+                },
+                Amount::from_sat(1_000),
+                bip32::ChildNumber::from(25),
+            )))
+            .collect();
+        let planned_feerate = 2;
+        let change_index = bip32::ChildNumber::from(30);
+        let (planned_spend, planned_unvaults) = plan_spend_tx(
+            available_vaults.clone(),
+            vec![SpendTxOut::new(TxOut {
+                value: 4 * deposit_value,
+                ..TxOut::default()
+            })],
+            planned_feerate,
+            change_index,
+            &deployment.deposit_descriptor,
+            &deployment.unvault_descriptor,
+            &deployment.cpfp_descriptor,
+            0,
+            &fee_policy,
+            &secp,
+        )
+        .unwrap();
+        // The largest vault can't pay for it alone
+        assert_eq!(planned_spend.tx().input.len(), 2);
+        assert_eq!(planned_unvaults.len(), 2);
+        for (i, unvault_tx) in planned_unvaults.iter().enumerate() {
+            assert_eq!(
+                unvault_tx.tx().input[0].previous_output,
+                available_vaults[3 - i].0
+            );
+            assert_eq!(
+                planned_spend.tx().input[i].previous_output.txid,
+                unvault_tx.txid()
+            );
+        }
+        // The target feerate is reached, exactly so if there is a change output
+        let planned_weight = planned_spend.max_weight();
+        assert!(planned_spend.fees() >= planned_weight * planned_feerate);
+        if let Some(change_txo) = planned_spend.tx().output.get(2) {
+            assert_eq!(planned_spend.fees(), planned_weight * planned_feerate);
+            assert!(change_txo.value >= fee_policy.dust_limit);
+            assert_eq!(
+                change_txo.script_pubkey,
+                deployment
+                    .deposit_descriptor
+                    .derive(change_index, &secp)
+                    .inner()
+                    .script_pubkey()
+            );
+        }
+
+        // With a small enough payment, there is always a change output
+        let (planned_spend, planned_unvaults) = plan_spend_tx(
+            available_vaults.clone(),
+            vec![SpendTxOut::new(TxOut {
+                value: 1_000,
+                ..TxOut::default()
+            })],
+            planned_feerate,
+            change_index,
+            &deployment.deposit_descriptor,
+            &deployment.unvault_descriptor,
+            &deployment.cpfp_descriptor,
+            0,
+            &fee_policy,
+            &secp,
+        )
+        .unwrap();
+        assert_eq!(planned_unvaults.len(), 1);
+        assert_eq!(planned_spend.tx().output.len(), 3);
+        assert_eq!(
+            planned_spend.fees(),
+            planned_spend.max_weight() * planned_feerate
+        );
+
+        // The vaults may not be worth enough
+        assert_eq!(
+            plan_spend_tx(
+                available_vaults,
+                vec![SpendTxOut::new(TxOut {
+                    value: 10 * deposit_value,
+                    ..TxOut::default()
+                })],
+                planned_feerate,
+                change_index,
+                &deployment.deposit_descriptor,
+                &deployment.unvault_descriptor,
+                &deployment.cpfp_descriptor,
+                0,
+                &fee_policy,
+                &secp,
+            ),
+            Err(TransactionCreationError::InsufficientFunds)
+        );
+
+        // Vaults are not selected past the standard weight
+        let split_input_weight = 4 * (32 + 4 + 1 + 4)
+            + UnvaultTxOut::new(
+                Amount::from_sat(0),
+                &deployment.der_unvault_descriptor(&secp),
+            )
+            .max_sat_weight() as u64;
+        let n_many_vaults = MAX_STANDARD_TX_WEIGHT / split_input_weight as u32 + 1;
+        let many_vaults: Vec<(OutPoint, Amount, bip32::ChildNumber)> = (0..n_many_vaults)
+            .map(|i| {
+                (
+                    OutPoint {
+                        txid: deposit_txid,
+                        vout: 100 + i,
+                    },
+                    Amount::from_sat(deposit_value),
+                    bip32::ChildNumber::from(100 + i),
+                )
+            })
+            .collect();
+        assert_eq!(
+            plan_spend_tx(
+                many_vaults,
+                vec![SpendTxOut::new(TxOut {
+                    value: n_many_vaults as u64 * deposit_value,
+                    ..TxOut::default()
+                })],
+                planned_feerate,
+                change_index,
+                &deployment.deposit_descriptor,
+                &deployment.unvault_descriptor,
+                &deployment.cpfp_descriptor,
+                0,
+                &fee_policy,
+                &secp,
+            ),
+            Err(TransactionCreationError::TooLarge)
+        );
+    }
+
     // Small sanity checks, see fuzzing targets for more.
     #[cfg(feature = "use-serde")]
     #[test]
@@ -2,7 +2,7 @@ use crate::{
     error::*,
     scripts::*,
     transactions::{
-        utils, CpfpableTransaction, RevaultTransaction, INSANE_FEES, MAX_STANDARD_TX_WEIGHT,
+        utils, CpfpableTransaction, FeePolicy, RevaultTransaction, MAX_STANDARD_TX_WEIGHT,
         TX_VERSION,
     },
     txins::*,
@@ -17,7 +17,7 @@ use miniscript::{
             Global as PsbtGlobal, Input as PsbtIn, Output as PsbtOut,
             PartiallySignedTransaction as Psbt,
         },
-        Amount, Network, OutPoint, SigHashType, Transaction,
+        Amount, Network, OutPoint, SigHashType, Transaction, TxIn, TxOut,
     },
     DescriptorTrait,
 };
@@ -33,6 +33,42 @@ use std::{
     convert::TryInto,
 };
 
+// The value of the CPFP output of a Spend transaction per weight unit of the transaction.
+// See https://github.com/revault/practical-revault/blob/master/transactions.md#spend_tx
+// for this arbirtrary value.
+pub(super) const SPEND_CPFP_VALUE_PER_WU: u64 = 16;
+
+// The weight of a transaction with these inputs and outputs, without witness.
+fn witstrip_weight(txins: Vec<TxIn>, txouts: Vec<TxOut>, lock_time: u32) -> u64 {
+    Transaction {
+        version: TX_VERSION,
+        lock_time,
+        input: txins,
+        output: txouts,
+    }
+    .get_weight()
+    .try_into()
+    .expect("Bug: an usize that doesn't fit in a u64?")
+}
+
+// The value a Spend transaction of this weight must spend to pay for its outputs, its fees at
+// this feerate (in sat/WU) and its CPFP output.
+pub(super) fn needed_value(
+    feerate: u64,
+    weight: u64,
+    outputs_value: u64,
+) -> Result<u64, TransactionCreationError> {
+    feerate
+        .checked_add(SPEND_CPFP_VALUE_PER_WU)
+        .and_then(|f| f.checked_mul(weight))
+        .and_then(|v| v.checked_add(outputs_value))
+        .ok_or(TransactionCreationError::InsaneFees)
+}
+
+/// A Spend transaction created by [SpendTransaction::split_batch], along with the indexes of the
+/// payments it pays for.
+pub type SplitSpend = (SpendTransaction, Vec<usize>);
+
 impl_revault_transaction!(
     SpendTransaction,
     doc = "The transaction spending the unvaulting transaction, paying to one or multiple \
@@ -42,8 +78,8 @@ impl SpendTransaction {
     /// A spend transaction can batch multiple unvault txouts, and may have any number of
     /// txouts (destination and change) in addition to the CPFP one..
     ///
-    /// The insane fees check is gated behind the `insane_fee_checks` parameter as the caller
-    /// may want to create a transaction without a change output.
+    /// The insane fees check against the fee policy is gated behind the `insane_fee_checks`
+    /// parameter as the caller may want to create a transaction without a change output.
     ///
     /// BIP174 Creator and Updater roles.
     pub fn new(
@@ -52,6 +88,7 @@ impl SpendTransaction {
         change_txout: Option<DepositTxOut>,
         cpfp_descriptor: &DerivedCpfpDescriptor,
         lock_time: u32,
+        fee_policy: &FeePolicy,
         insane_fee_check: bool,
     ) -> Result<SpendTransaction, TransactionCreationError> {
         // Check for duplicated inputs
@@ -171,13 +208,246 @@ impl SpendTransaction {
         let fees = value_in
             .checked_sub(value_out)
             .ok_or(TransactionCreationError::NegativeFees)?;
-        if insane_fee_check && fees > INSANE_FEES {
+        if insane_fee_check && fees > fee_policy.insane_fees {
             return Err(TransactionCreationError::InsaneFees);
         }
 
         Ok(SpendTransaction(psbt))
     }
 
+    /// Create a Spend transaction reaching this `feerate` (in sat/WU), sending the remainder to
+    /// a change output paying to the `change_descriptor`. The value of the CPFP output is paid
+    /// for by the inputs, on top of the fees.
+    ///
+    /// The change output is dropped to the fees if it would be below the dust limit of the fee
+    /// policy. Will error with [TransactionCreationError::InsufficientFunds] if the inputs can't
+    /// cover the outputs at this feerate.
+    ///
+    /// BIP174 Creator and Updater roles.
+    #[allow(clippy::too_many_arguments)]
+    pub fn new_with_feerate(
+        unvault_inputs: Vec<UnvaultTxIn>,
+        spend_txouts: Vec<SpendTxOut>,
+        change_descriptor: &DerivedDepositDescriptor,
+        cpfp_descriptor: &DerivedCpfpDescriptor,
+        feerate: u64,
+        lock_time: u32,
+        fee_policy: &FeePolicy,
+        insane_fee_check: bool,
+    ) -> Result<SpendTransaction, TransactionCreationError> {
+        let inputs_value = unvault_inputs
+            .iter()
+            .try_fold(0u64, |sum, txin| {
+                sum.checked_add(txin.txout().txout().value)
+            })
+            .ok_or(TransactionCreationError::InsaneAmounts)?;
+        let outputs_value = spend_txouts
+            .iter()
+            .try_fold(0u64, |sum, txo| sum.checked_add(txo.txout().value))
+            .ok_or(TransactionCreationError::InsaneAmounts)?;
+        let dummy_change_txo = DepositTxOut::new(Amount::from_sat(0), change_descriptor);
+        let weight = SpendTransaction::max_weight_for(
+            &unvault_inputs,
+            &spend_txouts,
+            Some(&dummy_change_txo),
+            cpfp_descriptor,
+            lock_time,
+        );
+        let change_value = inputs_value
+            .checked_sub(needed_value(feerate, weight, outputs_value)?)
+            .filter(|v| *v >= fee_policy.dust_limit && weight <= MAX_STANDARD_TX_WEIGHT as u64);
+
+        let change_txout = match change_value {
+            Some(value) => Some(DepositTxOut::new(
+                Amount::from_sat(value),
+                change_descriptor,
+            )),
+            None => {
+                let weight = SpendTransaction::max_weight_for(
+                    &unvault_inputs,
+                    &spend_txouts,
+                    None,
+                    cpfp_descriptor,
+                    lock_time,
+                );
+                if inputs_value < needed_value(feerate, weight, outputs_value)? {
+                    return Err(TransactionCreationError::InsufficientFunds);
+                }
+                None
+            }
+        };
+
+        SpendTransaction::new(
+            unvault_inputs,
+            spend_txouts,
+            change_txout,
+            cpfp_descriptor,
+            lock_time,
+            fee_policy,
+            insane_fee_check,
+        )
+    }
+
+    /// Split a batch of payments, along with the unvault inputs paying for them, into as many
+    /// Spend transactions as needed for each of them to be standard. Each Spend transaction
+    /// reaches this `feerate` (in sat/WU) and gets its own CPFP output and change output, as
+    /// created by [SpendTransaction::new_with_feerate]. The i-th Spend transaction sends its
+    /// change to the i-th of the `change_descriptors`.
+    ///
+    /// The payments are assigned to the Spend transactions in order, and the unvault inputs are
+    /// spent largest first. The unvault inputs not needed for the payments are sent to the
+    /// change of the Spend transactions they fit in, the others are returned unspent.
+    ///
+    /// Returns the Spend transactions along with the indexes of the `spend_txouts` paid by each
+    /// of them, and the unspent unvault inputs. Will error with
+    /// [TransactionCreationError::TooLarge] if a single payment can't be paid for by a standard
+    /// transaction, or [TransactionCreationError::MissingChangeDescriptor] if there are fewer
+    /// `change_descriptors` than Spend transactions needed.
+    #[allow(clippy::too_many_arguments)]
+    pub fn split_batch(
+        mut unvault_inputs: Vec<UnvaultTxIn>,
+        spend_txouts: Vec<SpendTxOut>,
+        change_descriptors: &[DerivedDepositDescriptor],
+        cpfp_descriptor: &DerivedCpfpDescriptor,
+        feerate: u64,
+        lock_time: u32,
+        fee_policy: &FeePolicy,
+    ) -> Result<(Vec<SplitSpend>, Vec<UnvaultTxIn>), TransactionCreationError> {
+        // Check for duplicated inputs, as they'd not necessarily end up in the same Spend
+        let uniq_txins: HashSet<OutPoint> = unvault_inputs.iter().map(|i| i.outpoint()).collect();
+        if uniq_txins.len() != unvault_inputs.len() {
+            return Err(TransactionCreationError::DuplicatedInput);
+        }
+        if spend_txouts.is_empty() {
+            return Ok((Vec::new(), unvault_inputs));
+        }
+        // Sorted in ascending order, we pop() from it to spend the largest first. Computing the
+        // satisfaction weight of an input is expensive, so we do it once for each of them.
+        unvault_inputs.sort_unstable_by_key(|txin| txin.txout().txout().value);
+        let mut unvault_inputs: Vec<(UnvaultTxIn, u64)> = unvault_inputs
+            .into_iter()
+            .map(|txin| {
+                let sat_weight = txin
+                    .txout()
+                    .max_sat_weight()
+                    .try_into()
+                    .expect("usize in u64");
+                (txin, sat_weight)
+            })
+            .collect();
+
+        // The weight of a Spend with these inputs and payments, accounting for a change output
+        let dummy_cpfp_txo = CpfpTxOut::new(Amount::from_sat(u64::MAX), cpfp_descriptor);
+        // The change outputs all have the same weight
+        let dummy_change_txo = DepositTxOut::new(
+            Amount::from_sat(0),
+            change_descriptors
+                .first()
+                .ok_or(TransactionCreationError::MissingChangeDescriptor)?,
+        );
+        let spend_weight = |txins: &[(UnvaultTxIn, u64)], payments: &[usize]| -> u64 {
+            let txouts = std::iter::once(dummy_cpfp_txo.txout().clone())
+                .chain(payments.iter().map(|i| spend_txouts[*i].txout().clone()))
+                .chain(std::iter::once(dummy_change_txo.txout().clone()))
+                .collect();
+            let sat_weight: u64 = txins.iter().map(|(_, sat_weight)| sat_weight).sum();
+            witstrip_weight(
+                txins.iter().map(|(txin, _)| txin.unsigned_txin()).collect(),
+                txouts,
+                lock_time,
+            ) + sat_weight
+        };
+
+        // The inputs and payments of the Spends, the last one being filled
+        let mut groups = Vec::new();
+        let mut group_txins: Vec<(UnvaultTxIn, u64)> = Vec::new();
+        let mut group_payments: Vec<usize> = Vec::new();
+        let (mut group_inputs_value, mut group_outputs_value): (u64, u64) = (0, 0);
+        for (payment, spend_txout) in spend_txouts.iter().enumerate() {
+            let payment_value = spend_txout.txout().value;
+            loop {
+                // Add inputs to the current Spend until they pay for this payment too
+                let n_txins = group_txins.len();
+                group_payments.push(payment);
+                group_outputs_value += payment_value;
+                let fits = loop {
+                    let weight = spend_weight(&group_txins, &group_payments);
+                    if weight > MAX_STANDARD_TX_WEIGHT as u64 {
+                        break false;
+                    }
+                    if group_inputs_value >= needed_value(feerate, weight, group_outputs_value)? {
+                        break true;
+                    }
+                    let txin = unvault_inputs
+                        .pop()
+                        .ok_or(TransactionCreationError::InsufficientFunds)?;
+                    group_inputs_value += txin.0.txout().txout().value;
+                    group_txins.push(txin);
+                };
+                if fits {
+                    break;
+                }
+
+                // It would be too large. Give back the inputs we added for this payment, and
+                // pay for it in a new Spend.
+                unvault_inputs.extend(group_txins.drain(n_txins..).rev());
+                group_payments.pop();
+                if group_payments.is_empty() {
+                    return Err(TransactionCreationError::TooLarge);
+                }
+                groups.push((
+                    std::mem::take(&mut group_txins),
+                    std::mem::take(&mut group_payments),
+                ));
+                group_inputs_value = 0;
+                group_outputs_value = 0;
+            }
+        }
+        if !group_payments.is_empty() {
+            groups.push((group_txins, group_payments));
+        }
+        // Each Spend sends its change to its own descriptor
+        if change_descriptors.len() < groups.len() {
+            return Err(TransactionCreationError::MissingChangeDescriptor);
+        }
+
+        // The inputs left are sent to the change of the Spends they fit in, starting with the
+        // last one. The others are left unspent.
+        let mut unspent_inputs = Vec::new();
+        'leftovers: while let Some(mut txin) = unvault_inputs.pop() {
+            for (txins, payments) in groups.iter_mut().rev() {
+                txins.push(txin);
+                if spend_weight(txins, payments) <= MAX_STANDARD_TX_WEIGHT as u64 {
+                    continue 'leftovers;
+                }
+                txin = txins.pop().expect("Just pushed it");
+            }
+            unspent_inputs.push(txin.0);
+        }
+
+        let spends = groups
+            .into_iter()
+            .zip(change_descriptors.iter())
+            .map(|((txins, payments), change_descriptor)| {
+                let txins = txins.into_iter().map(|(txin, _)| txin).collect();
+                let txouts = payments.iter().map(|i| spend_txouts[*i].clone()).collect();
+                SpendTransaction::new_with_feerate(
+                    txins,
+                    txouts,
+                    change_descriptor,
+                    cpfp_descriptor,
+                    feerate,
+                    lock_time,
+                    fee_policy,
+                    true,
+                )
+                .map(|spend_tx| (spend_tx, payments))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok((spends, unspent_inputs))
+    }
+
     /// Get the CPFP transaction output for a Spend transaction spending these `unvault_inputs`
     /// and creating these `spend_txouts`.
     ///
@@ -191,54 +461,81 @@ impl SpendTransaction {
         cpfp_descriptor: &DerivedCpfpDescriptor,
         lock_time: u32,
     ) -> CpfpTxOut {
-        let mut txos = Vec::with_capacity(spend_txouts.len() + 1);
-        let dummy_cpfp_txo = CpfpTxOut::new(Amount::from_sat(u64::MAX), &cpfp_descriptor);
+        let total_weight = SpendTransaction::max_weight_for(
+            &unvault_inputs,
+            &spend_txouts,
+            change_txout.as_ref(),
+            cpfp_descriptor,
+            lock_time,
+        );
+
+        let cpfp_value = SPEND_CPFP_VALUE_PER_WU * total_weight;
+        CpfpTxOut::new(Amount::from_sat(cpfp_value), &cpfp_descriptor)
+    }
+
+    // The weight of a Spend transaction with these inputs and outputs once all its inputs are
+    // satisfied, used to compute the value of its CPFP output.
+    pub(super) fn max_weight_for(
+        unvault_inputs: &[UnvaultTxIn],
+        spend_txouts: &[SpendTxOut],
+        change_txout: Option<&DepositTxOut>,
+        cpfp_descriptor: &DerivedCpfpDescriptor,
+        lock_time: u32,
+    ) -> u64 {
+        let sat_weight: u64 = unvault_inputs
+            .iter()
+            .map(|txin| txin.txout().max_sat_weight())
+            .sum::<usize>()
+            .try_into()
+            .expect("An usize doesn't fit in an u64?");
+        sat_weight
+            .checked_add(SpendTransaction::witstrip_weight_for(
+                unvault_inputs,
+                spend_txouts,
+                change_txout,
+                cpfp_descriptor,
+                lock_time,
+            ))
+            .expect("Weight computation bug: cannot overflow")
+    }
+
+    // The weight of a Spend transaction with these inputs and outputs without the witnesses, for
+    // the callers keeping track of the satisfaction weight of the inputs themselves.
+    pub(super) fn witstrip_weight_for(
+        unvault_inputs: &[UnvaultTxIn],
+        spend_txouts: &[SpendTxOut],
+        change_txout: Option<&DepositTxOut>,
+        cpfp_descriptor: &DerivedCpfpDescriptor,
+        lock_time: u32,
+    ) -> u64 {
+        let mut txos = Vec::with_capacity(spend_txouts.len() + 2);
+        let dummy_cpfp_txo = CpfpTxOut::new(Amount::from_sat(u64::MAX), cpfp_descriptor);
         txos.push(dummy_cpfp_txo.txout().clone());
         txos.extend(
             spend_txouts
-                .into_iter()
-                .map(|spend_txout| spend_txout.into_txout()),
+                .iter()
+                .map(|spend_txout| spend_txout.txout().clone()),
         );
 
         if let Some(change_txout) = change_txout {
-            txos.push(change_txout.into_txout());
+            txos.push(change_txout.txout().clone());
         }
 
-        let dummy_tx = Transaction {
-            version: TX_VERSION,
-            lock_time,
-            input: unvault_inputs
+        witstrip_weight(
+            unvault_inputs
                 .iter()
                 .map(|input| input.unsigned_txin())
                 .collect(),
-            output: txos,
-        };
-
-        let sat_weight: u64 = unvault_inputs
-            .iter()
-            .map(|txin| txin.txout().max_sat_weight())
-            .sum::<usize>()
-            .try_into()
-            .expect("An usize doesn't fit in an u64?");
-        let witstrip_weight: u64 = dummy_tx
-            .get_weight()
-            .try_into()
-            .expect("Bug: an usize that doesn't fit in a u64?");
-        let total_weight = sat_weight
-            .checked_add(witstrip_weight)
-            .expect("Weight computation bug: cannot overflow");
-
-        // See https://github.com/revault/practical-revault/blob/master/transactions.md#spend_tx
-        // for this arbirtrary value.
-        let cpfp_value = 16 * total_weight;
-        CpfpTxOut::new(Amount::from_sat(cpfp_value), &cpfp_descriptor)
+            txos,
+            lock_time,
+        )
     }
 
     // FIXME: feerate sanity checks
     /// Parse a Spend transaction from a PSBT
     pub fn from_raw_psbt(raw_psbt: &[u8]) -> Result<Self, TransactionSerialisationError> {
         let psbt = Decodable::consensus_decode(raw_psbt)?;
-        let psbt = utils::psbt_common_sanity_checks(psbt)?;
+        let psbt = utils::psbt_common_sanity_checks(psbt, false, None)?;
 
         if psbt.inputs.is_empty() {
             return Err(PsbtValidationError::InvalidInputCount(0).into());
@@ -252,7 +549,7 @@ impl SpendTransaction {
                 .as_ref()
                 .expect("Checked by the common parsing checks");
             if !txo.script_pubkey.is_v0_p2wsh() {
-                return Err(PsbtValidationError::InvalidInputField(input.clone()).into());
+                return Err(PsbtValidationError::InvalidInputField(Box::new(input.clone())).into());
             }
 
             if input.final_script_witness.is_some() {
@@ -260,35 +557,39 @@ impl SpendTransaction {
             }
 
             if input.sighash_type != Some(SigHashType::All) {
-                return Err(PsbtValidationError::InvalidSighashType(input.clone()).into());
+                return Err(
+                    PsbtValidationError::InvalidSighashType(Box::new(input.clone())).into(),
+                );
             }
 
             // The Unvault input must contain a valid witness script
             if let Some(ref ws) = input.witness_script {
                 if ws.to_v0_p2wsh() != txo.script_pubkey {
-                    return Err(PsbtValidationError::InvalidInWitnessScript(input.clone()).into());
+                    return Err(PsbtValidationError::InvalidInWitnessScript(Box::new(
+                        input.clone(),
+                    ))
+                    .into());
                 }
             } else {
-                return Err(PsbtValidationError::MissingInWitnessScript(input.clone()).into());
+                return Err(
+                    PsbtValidationError::MissingInWitnessScript(Box::new(input.clone())).into(),
+                );
             }
 
             // And since it has a witscript it must have derivation paths for it set
             if input.bip32_derivation.is_empty() {
-                return Err(PsbtValidationError::InvalidInputField(input.clone()).into());
+                return Err(PsbtValidationError::InvalidInputField(Box::new(input.clone())).into());
             }
 
             max_sat_weight += miniscript::descriptor::Wsh::new(
-                miniscript::Miniscript::parse(
-                    input
-                        .witness_script
-                        .as_ref()
-                        .ok_or_else(|| PsbtValidationError::InvalidInputField(input.clone()))?,
-                )
-                .map_err(|_| PsbtValidationError::InvalidInputField(input.clone()))?,
+                miniscript::Miniscript::parse(input.witness_script.as_ref().ok_or_else(|| {
+                    PsbtValidationError::InvalidInputField(Box::new(input.clone()))
+                })?)
+                .map_err(|_| PsbtValidationError::InvalidInputField(Box::new(input.clone())))?,
             )
-            .map_err(|_| PsbtValidationError::InvalidInputField(input.clone()))?
+            .map_err(|_| PsbtValidationError::InvalidInputField(Box::new(input.clone())))?
             .max_satisfaction_weight()
-            .map_err(|_| PsbtValidationError::InvalidInputField(input.clone()))?;
+            .map_err(|_| PsbtValidationError::InvalidInputField(Box::new(input.clone())))?;
         }
 
         let mut derivation_count: usize = 0;
@@ -364,3 +665,220 @@ impl CpfpableTransaction for SpendTransaction {
         weight
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transactions::{tests_helpers::TestDeployment, TRUC_TX_VERSION};
+
+    use miniscript::bitcoin::{consensus::encode, secp256k1, util::bip32};
+
+    #[test]
+    fn spend_is_never_truc() {
+        let secp = secp256k1::Secp256k1::new();
+        let deployment = TestDeployment::new(3, 2, false, 360_000_000, &secp);
+        let fee_policy = FeePolicy::default();
+        let (unvault_tx, _, _, _) = deployment.chain(&fee_policy, &secp);
+        let spend_tx = deployment.spend_tx(&unvault_tx, &fee_policy, &secp);
+
+        let mut truc_spend_psbt = spend_tx.psbt().clone();
+        truc_spend_psbt.global.unsigned_tx.version = TRUC_TX_VERSION;
+        assert!(matches!(
+            SpendTransaction::from_raw_psbt(&encode::serialize(&truc_spend_psbt)),
+            Err(TransactionSerialisationError::Validation(
+                PsbtValidationError::InvalidTransactionVersion(TRUC_TX_VERSION)
+            ))
+        ));
+    }
+
+    #[test]
+    fn spend_at_feerate() {
+        let secp = secp256k1::Secp256k1::new();
+        let deployment = TestDeployment::new(3, 2, false, 360_000_000, &secp);
+        let fee_policy = FeePolicy::default();
+        let (unvault_tx, _, _, _) = deployment.chain(&fee_policy, &secp);
+        let unvault_txin = unvault_tx.spend_unvault_txin(&deployment.der_unvault_descriptor(&secp));
+        let der_cpfp_descriptor = deployment.der_cpfp_descriptor(&secp);
+        let der_change_descriptor = deployment
+            .deposit_descriptor
+            .derive(bip32::ChildNumber::from(30), &secp);
+        let feerate = 2;
+
+        // A Spend may be created at a target feerate out of given inputs, computing the change
+        let feerate_spend = SpendTransaction::new_with_feerate(
+            vec![unvault_txin.clone()],
+            vec![SpendTxOut::new(TxOut {
+                value: 1_000,
+                ..TxOut::default()
+            })],
+            &der_change_descriptor,
+            &der_cpfp_descriptor,
+            feerate,
+            0,
+            &fee_policy,
+            true,
+        )
+        .unwrap();
+        assert_eq!(feerate_spend.tx().output.len(), 3);
+        assert_eq!(feerate_spend.fees(), feerate_spend.max_weight() * feerate);
+
+        // If the change would be dust, it is given to the fees instead
+        let change_value = feerate_spend.tx().output[2].value;
+        let feerate_spend = SpendTransaction::new_with_feerate(
+            vec![unvault_txin.clone()],
+            vec![SpendTxOut::new(TxOut {
+                value: 1_000 + change_value - fee_policy.dust_limit + 1,
+                ..TxOut::default()
+            })],
+            &der_change_descriptor,
+            &der_cpfp_descriptor,
+            feerate,
+            0,
+            &fee_policy,
+            true,
+        )
+        .unwrap();
+        assert_eq!(feerate_spend.tx().output.len(), 2);
+        assert!(feerate_spend.fees() > feerate_spend.max_weight() * feerate);
+
+        // The inputs must be worth enough
+        assert_eq!(
+            SpendTransaction::new_with_feerate(
+                vec![unvault_txin.clone()],
+                vec![SpendTxOut::new(TxOut {
+                    value: unvault_txin.txout().txout().value,
+                    ..TxOut::default()
+                })],
+                &der_change_descriptor,
+                &der_cpfp_descriptor,
+                feerate,
+                0,
+                &fee_policy,
+                true,
+            ),
+            Err(TransactionCreationError::InsufficientFunds)
+        );
+    }
+
+    #[test]
+    fn split_batch() {
+        let secp = secp256k1::Secp256k1::new();
+        let deployment = TestDeployment::new(3, 2, false, 360_000_000, &secp);
+        let fee_policy = FeePolicy::default();
+        let der_unvault_descriptor = deployment.der_unvault_descriptor(&secp);
+        let der_cpfp_descriptor = deployment.der_cpfp_descriptor(&secp);
+        let feerate = 2;
+
+        // A batch of payments too large for a single Spend is split into several standard ones
+        let input_weight = 4 * (32 + 4 + 1 + 4)
+            + UnvaultTxOut::new(Amount::from_sat(0), &der_unvault_descriptor).max_sat_weight()
+                as u64;
+        let n_inputs = MAX_STANDARD_TX_WEIGHT as u64 / input_weight + 20;
+        let inputs: Vec<UnvaultTxIn> = (0..n_inputs)
+            .map(|i| {
+                UnvaultTxIn::new(
+                    OutPoint {
+                        txid: deployment.deposit_outpoint().txid,
+                        vout: 100 + i as u32,
+                    },
+                    UnvaultTxOut::new(Amount::from_sat(1_000_000), &der_unvault_descriptor),
+                    deployment.unvault_descriptor.csv_value(),
+                )
+            })
+            .collect();
+        // Each payment needs 10 inputs, the last ones go to the change where they fit
+        let payments: Vec<SpendTxOut> = (0..n_inputs / 10 - 1)
+            .map(|_| {
+                SpendTxOut::new(TxOut {
+                    value: 9_900_000,
+                    ..TxOut::default()
+                })
+            })
+            .collect();
+        let change_descriptors: Vec<DerivedDepositDescriptor> = (0..payments.len())
+            .map(|i| {
+                deployment
+                    .deposit_descriptor
+                    .derive(bip32::ChildNumber::from(200 + i as u32), &secp)
+            })
+            .collect();
+        let (spends, unspent_inputs) = SpendTransaction::split_batch(
+            inputs.clone(),
+            payments.clone(),
+            &change_descriptors,
+            &der_cpfp_descriptor,
+            feerate,
+            0,
+            &fee_policy,
+        )
+        .unwrap();
+        assert!(spends.len() > 1);
+        let mut paid: Vec<usize> = Vec::new();
+        let mut n_spent_inputs = 0;
+        for (i, (spend, spend_payments)) in spends.iter().enumerate() {
+            assert!(spend.max_weight() <= MAX_STANDARD_TX_WEIGHT as u64);
+            assert!(spend.fees() >= spend.max_weight() * feerate);
+            // The CPFP output comes first, then the payments of this Spend and its own change
+            assert!(!spend_payments.is_empty());
+            for (j, payment) in spend_payments.iter().enumerate() {
+                assert_eq!(spend.tx().output[j + 1], *payments[*payment].txout());
+            }
+            if let Some(change_txo) = spend.tx().output.get(spend_payments.len() + 1) {
+                assert_eq!(
+                    change_txo.script_pubkey,
+                    change_descriptors[i].inner().script_pubkey()
+                );
+            }
+            paid.extend(spend_payments);
+            n_spent_inputs += spend.tx().input.len();
+        }
+        assert_eq!(paid, (0..payments.len()).collect::<Vec<usize>>());
+        // The inputs not needed for the payments are either sent to the change or left unspent
+        assert_eq!(
+            n_spent_inputs as u64 + unspent_inputs.len() as u64,
+            n_inputs
+        );
+
+        // A change descriptor is needed per Spend, not per payment
+        assert_eq!(
+            SpendTransaction::split_batch(
+                inputs.clone(),
+                payments.clone(),
+                &change_descriptors[..spends.len()],
+                &der_cpfp_descriptor,
+                feerate,
+                0,
+                &fee_policy,
+            ),
+            Ok((spends.clone(), unspent_inputs))
+        );
+        for n_change_descriptors in [0, spends.len() - 1].iter() {
+            assert_eq!(
+                SpendTransaction::split_batch(
+                    inputs.clone(),
+                    payments.clone(),
+                    &change_descriptors[..*n_change_descriptors],
+                    &der_cpfp_descriptor,
+                    feerate,
+                    0,
+                    &fee_policy,
+                ),
+                Err(TransactionCreationError::MissingChangeDescriptor)
+            );
+        }
+
+        // Without payments, all the inputs are left unspent
+        let (spends, unspent_inputs) = SpendTransaction::split_batch(
+            inputs,
+            Vec::new(),
+            &[],
+            &der_cpfp_descriptor,
+            feerate,
+            0,
+            &fee_policy,
+        )
+        .unwrap();
+        assert!(spends.is_empty());
+        assert_eq!(unspent_inputs.len() as u64, n_inputs);
+    }
+}
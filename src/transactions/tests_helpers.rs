@@ -1,7 +1,8 @@
 use super::{
     transaction_chain, CancelTransaction, CpfpTransaction, CpfpableTransaction, DepositTransaction,
-    EmergencyAddress, EmergencyTransaction, FeeBumpTransaction, RevaultTransaction,
-    SpendTransaction, UnvaultEmergencyTransaction, UnvaultTransaction, CPFP_MIN_CHANGE, DUST_LIMIT,
+    EmergencyAddress, EmergencyTransaction, FeeBumpTransaction, FeePolicy, LargestFirst,
+    RevaultTransaction, SpendTransaction, UnvaultEmergencyTransaction, UnvaultTransaction,
+    CPFP_MIN_CHANGE, DUST_LIMIT,
 };
 
 use crate::{error::*, scripts::*, txins::*, txouts::*};
@@ -19,7 +20,7 @@ use miniscript::{
     Descriptor, DescriptorTrait, MiniscriptKey,
 };
 
-fn get_random_privkey(rng: &mut fastrand::Rng) -> bip32::ExtendedPrivKey {
+pub fn get_random_privkey(rng: &mut fastrand::Rng) -> bip32::ExtendedPrivKey {
     let rand_bytes: Vec<u8> = repeat_with(|| rng.u8(..)).take(64).collect();
 
     bip32::ExtendedPrivKey::new_master(Network::Bitcoin, &rand_bytes)
@@ -28,7 +29,7 @@ fn get_random_privkey(rng: &mut fastrand::Rng) -> bip32::ExtendedPrivKey {
 
 // This generates the master private keys to derive directly from master, so it's
 // [None]<xpub_goes_here>m/* descriptor pubkeys
-fn get_participants_sets(
+pub fn get_participants_sets(
     n_stk: usize,
     n_man: usize,
     with_cosig_servers: bool,
@@ -96,7 +97,7 @@ fn get_participants_sets(
 }
 
 // Routine for ""signing"" a transaction
-fn satisfy_transaction_input(
+pub fn satisfy_transaction_input(
     secp: &secp256k1::Secp256k1<secp256k1::All>,
     tx: &mut impl RevaultTransaction,
     input_index: usize,
@@ -214,6 +215,274 @@ macro_rules! roundtrip {
     };
 }
 
+/// A deployment with a single confirmed deposit, for the tests of the modules building upon the
+/// transactions.
+pub struct TestDeployment {
+    pub managers_priv: Vec<bip32::ExtendedPrivKey>,
+    pub mancpfp_priv: Vec<bip32::ExtendedPrivKey>,
+    pub stakeholders_priv: Vec<bip32::ExtendedPrivKey>,
+    pub cosigners_priv: Vec<bip32::ExtendedPrivKey>,
+    pub deposit_descriptor: DepositDescriptor,
+    pub unvault_descriptor: UnvaultDescriptor,
+    pub cpfp_descriptor: CpfpDescriptor,
+    pub emergency_address: EmergencyAddress,
+    pub child_number: bip32::ChildNumber,
+    pub deposit_tx: DepositTransaction,
+    pub deposit_txin: DepositTxIn,
+}
+
+impl TestDeployment {
+    pub fn new(
+        n_stk: usize,
+        n_man: usize,
+        with_cosig_servers: bool,
+        deposit_value: u64,
+        secp: &secp256k1::Secp256k1<secp256k1::All>,
+    ) -> TestDeployment {
+        let child_number = bip32::ChildNumber::from(10);
+        let (
+            (managers_priv, managers),
+            (mancpfp_priv, mancpfp),
+            (stakeholders_priv, stakeholders),
+            (cosigners_priv, cosigners),
+        ) = get_participants_sets(n_stk, n_man, with_cosig_servers, secp);
+
+        let unvault_descriptor = UnvaultDescriptor::new(
+            stakeholders.clone(),
+            managers.clone(),
+            managers.len(),
+            cosigners.clone(),
+            cosigners.len(),
+            18,
+        )
+        .expect("Valid participants sets");
+        let cpfp_descriptor = CpfpDescriptor::new(mancpfp).expect("Valid participants sets");
+        let deposit_descriptor =
+            DepositDescriptor::new(stakeholders).expect("Valid participants sets");
+        let emergency_address = EmergencyAddress::from(Address::p2wsh(
+            &deposit_descriptor
+                .derive(bip32::ChildNumber::from(1_000), secp)
+                .inner()
+                .explicit_script(),
+            Network::Bitcoin,
+        ))
+        .expect("It's a P2WSH");
+
+        let der_deposit_descriptor = deposit_descriptor.derive(child_number, secp);
+        let deposit_tx = DepositTransaction(Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output: OutPoint::from_str(
+                    "39a8212c6a9b467680d43e47b61b8363fe1febb761f9f548eb4a432b2bc9bbec:0",
+                )
+                .unwrap(),
+                ..TxIn::default()
+            }],
+            output: vec![TxOut {
+                value: deposit_value,
+                script_pubkey: der_deposit_descriptor.inner().script_pubkey(),
+            }],
+        });
+        let deposit_txin = DepositTxIn::new(
+            OutPoint {
+                txid: deposit_tx.0.txid(),
+                vout: 0,
+            },
+            DepositTxOut::new(Amount::from_sat(deposit_value), &der_deposit_descriptor),
+        );
+
+        TestDeployment {
+            managers_priv,
+            mancpfp_priv,
+            stakeholders_priv,
+            cosigners_priv,
+            deposit_descriptor,
+            unvault_descriptor,
+            cpfp_descriptor,
+            emergency_address,
+            child_number,
+            deposit_tx,
+            deposit_txin,
+        }
+    }
+
+    pub fn deposit_outpoint(&self) -> OutPoint {
+        self.deposit_txin.outpoint()
+    }
+
+    pub fn deposit_amount(&self) -> Amount {
+        Amount::from_sat(self.deposit_txin.txout().txout().value)
+    }
+
+    pub fn der_deposit_descriptor(
+        &self,
+        secp: &secp256k1::Secp256k1<secp256k1::All>,
+    ) -> DerivedDepositDescriptor {
+        self.deposit_descriptor.derive(self.child_number, secp)
+    }
+
+    pub fn der_unvault_descriptor(
+        &self,
+        secp: &secp256k1::Secp256k1<secp256k1::All>,
+    ) -> DerivedUnvaultDescriptor {
+        self.unvault_descriptor.derive(self.child_number, secp)
+    }
+
+    pub fn der_cpfp_descriptor(
+        &self,
+        secp: &secp256k1::Secp256k1<secp256k1::All>,
+    ) -> DerivedCpfpDescriptor {
+        self.cpfp_descriptor.derive(self.child_number, secp)
+    }
+
+    /// The transaction chain of the deposit, unsigned
+    pub fn chain(
+        &self,
+        fee_policy: &FeePolicy,
+        secp: &secp256k1::Secp256k1<secp256k1::All>,
+    ) -> (
+        UnvaultTransaction,
+        CancelTransaction,
+        EmergencyTransaction,
+        UnvaultEmergencyTransaction,
+    ) {
+        transaction_chain(
+            self.deposit_outpoint(),
+            self.deposit_amount(),
+            &self.deposit_descriptor,
+            &self.unvault_descriptor,
+            &self.cpfp_descriptor,
+            self.child_number,
+            self.emergency_address.clone(),
+            0,
+            fee_policy,
+            secp,
+        )
+        .expect("Valid deposit")
+    }
+
+    /// The transaction chain of the deposit, signed by all the stakeholders and finalized
+    pub fn signed_chain(
+        &self,
+        fee_policy: &FeePolicy,
+        secp: &secp256k1::Secp256k1<secp256k1::All>,
+    ) -> (
+        UnvaultTransaction,
+        CancelTransaction,
+        EmergencyTransaction,
+        UnvaultEmergencyTransaction,
+    ) {
+        let (mut unvault_tx, mut cancel_tx, mut emer_tx, mut unemer_tx) =
+            self.chain(fee_policy, secp);
+
+        let stakeholders_priv = &self.stakeholders_priv;
+        let child_number = Some(self.child_number);
+        sign_and_finalize(
+            secp,
+            &mut unvault_tx,
+            SigHashType::All,
+            stakeholders_priv,
+            child_number,
+        );
+        let revocation_sighash = SigHashType::AllPlusAnyoneCanPay;
+        sign_and_finalize(
+            secp,
+            &mut cancel_tx,
+            revocation_sighash,
+            stakeholders_priv,
+            child_number,
+        );
+        sign_and_finalize(
+            secp,
+            &mut emer_tx,
+            revocation_sighash,
+            stakeholders_priv,
+            child_number,
+        );
+        sign_and_finalize(
+            secp,
+            &mut unemer_tx,
+            revocation_sighash,
+            stakeholders_priv,
+            child_number,
+        );
+
+        (unvault_tx, cancel_tx, emer_tx, unemer_tx)
+    }
+
+    /// A Spend of this Unvault paying all but the CPFP output and 10k sats of fees to a
+    /// dummy Script, unsigned.
+    pub fn spend_tx(
+        &self,
+        unvault_tx: &UnvaultTransaction,
+        fee_policy: &FeePolicy,
+        secp: &secp256k1::Secp256k1<secp256k1::All>,
+    ) -> SpendTransaction {
+        let spend_unvault_txin = unvault_tx.spend_unvault_txin(&self.der_unvault_descriptor(secp));
+        let der_cpfp_descriptor = self.der_cpfp_descriptor(secp);
+        let cpfp_value = SpendTransaction::cpfp_txout(
+            vec![spend_unvault_txin.clone()],
+            vec![SpendTxOut::new(TxOut::default())],
+            None,
+            &der_cpfp_descriptor,
+            0,
+        )
+        .txout()
+        .value;
+        let spend_txo = TxOut {
+            value: spend_unvault_txin.txout().txout().value - cpfp_value - 10_000,
+            ..TxOut::default()
+        };
+
+        SpendTransaction::new(
+            vec![spend_unvault_txin],
+            vec![SpendTxOut::new(spend_txo)],
+            None,
+            &der_cpfp_descriptor,
+            0,
+            fee_policy,
+            true,
+        )
+        .expect("Amounts ok")
+    }
+
+    /// Sign this Spend with all the managers and Cosigning Servers, and finalize it
+    pub fn sign_spend(
+        &self,
+        spend_tx: &mut SpendTransaction,
+        secp: &secp256k1::Secp256k1<secp256k1::All>,
+    ) {
+        let xprivs = self
+            .managers_priv
+            .iter()
+            .chain(self.cosigners_priv.iter())
+            .copied()
+            .collect::<Vec<bip32::ExtendedPrivKey>>();
+        sign_and_finalize(
+            secp,
+            spend_tx,
+            SigHashType::All,
+            &xprivs,
+            Some(self.child_number),
+        );
+    }
+}
+
+// Sign the first input of this transaction with all these keys, and finalize it
+fn sign_and_finalize(
+    secp: &secp256k1::Secp256k1<secp256k1::All>,
+    tx: &mut impl RevaultTransaction,
+    sighash_type: SigHashType,
+    xprivs: &Vec<bip32::ExtendedPrivKey>,
+    child_number: Option<bip32::ChildNumber>,
+) {
+    let sighash = tx.signature_hash(0, sighash_type).expect("Input exists");
+    satisfy_transaction_input(secp, tx, 0, &sighash, xprivs, child_number)
+        .expect("Valid signatures");
+    tx.finalize(secp).expect("All signatures are there");
+}
+
 /// Derive transactions for a given deployment configuration, asserting some invariants
 pub fn derive_transactions(
     n_stk: usize,
@@ -230,6 +499,7 @@ pub fn derive_transactions(
 ) -> Result<(), Error> {
     // Let's get the 10th key of each
     let child_number = bip32::ChildNumber::from(10);
+    let fee_policy = FeePolicy::default();
 
     // Keys, keys, keys everywhere !
     let (
@@ -245,6 +515,7 @@ pub fn derive_transactions(
         managers.clone(),
         managers.len(),
         cosigners.clone(),
+        cosigners.len(),
         csv,
     )?;
     assert_eq!(unvault_descriptor.csv_value(), csv);
@@ -263,10 +534,11 @@ pub fn derive_transactions(
     )?;
     desc_san_check(cpfp_descriptor.derive(child_number.into(), &secp).inner())?;
 
-    // We reuse the deposit descriptor for the emergency address
+    // We reuse the deposit descriptor for the emergency address, at an index never used by
+    // the vaults
     let emergency_address = EmergencyAddress::from(Address::p2wsh(
         &deposit_descriptor
-            .derive(child_number, secp)
+            .derive(bip32::ChildNumber::from(1_000), secp)
             .inner()
             .explicit_script(),
         Network::Bitcoin,
@@ -280,6 +552,9 @@ pub fn derive_transactions(
         unvault_descriptor.csv_value()
     );
     let der_cpfp_descriptor = cpfp_descriptor.derive(child_number, secp);
+    // The CPFP transactions send their change to a fresh address
+    let change_descriptor = cpfp_descriptor.derive(bip32::ChildNumber::from(11), secp);
+    let cpfp_change_txo = CpfpTxOut::new(Amount::from_sat(0), &change_descriptor);
 
     // The funding transaction does not matter (random txid from my mempool)
     let deposit_scriptpubkey = der_deposit_descriptor.inner().script_pubkey();
@@ -316,6 +591,7 @@ pub fn derive_transactions(
         child_number,
         emergency_address.clone(),
         0,
+        &fee_policy,
         secp,
     )?;
 
@@ -352,8 +628,13 @@ pub fn derive_transactions(
 
     // Create and sign the first (deposit) emergency transaction
     // We can sign the transaction without the feebump input
-    let mut emergency_tx_no_feebump =
-        EmergencyTransaction::new(deposit_txin.clone(), None, emergency_address.clone(), 0)?;
+    let mut emergency_tx_no_feebump = EmergencyTransaction::new(
+        deposit_txin.clone(),
+        None,
+        emergency_address.clone(),
+        0,
+        &fee_policy,
+    )?;
     assert_eq!(h_emer, emergency_tx_no_feebump);
     assert_eq!(
         emergency_tx_no_feebump.emergency_outpoint(),
@@ -414,6 +695,7 @@ pub fn derive_transactions(
         Some(feebump_txin),
         emergency_address.clone(),
         0,
+        &fee_policy,
     )?;
     assert_eq!(
         emergency_tx.emergency_outpoint(),
@@ -447,6 +729,8 @@ pub fn derive_transactions(
     roundtrip!(emergency_tx, EmergencyTransaction);
     emergency_tx.finalize(&secp)?;
     roundtrip!(emergency_tx, EmergencyTransaction);
+    EmergencyTransaction::from_raw_psbt(&emergency_tx.as_psbt_serialized(), &fee_policy)
+        .expect("Created with this fee policy");
 
     // Create but don't sign the unvaulting transaction until all revaulting transactions
     // are finalized
@@ -456,6 +740,7 @@ pub fn derive_transactions(
         &der_unvault_descriptor,
         &der_cpfp_descriptor,
         0,
+        &fee_policy,
     )?;
     roundtrip!(unvault_tx, UnvaultTransaction);
 
@@ -464,13 +749,20 @@ pub fn derive_transactions(
     // 548 is the witstrip weight of an unvault tx (1 segwit input, 2 P2WSH txouts), 6 is the
     // feerate is sat/WU, and 30_000 is the CPFP output value.
     assert_eq!(unvault_tx.fees(), (548 + deposit_txin_sat_cost as u64) * 6);
+    UnvaultTransaction::from_raw_psbt(&unvault_tx.as_psbt_serialized(), &fee_policy)
+        .expect("Created with this fee policy");
 
     // Create and sign the cancel transaction
     let rev_unvault_txin = unvault_tx.revault_unvault_txin(&der_unvault_descriptor);
     assert_eq!(rev_unvault_txin.txout().txout().value, unvault_value);
     // We can create it entirely without the feebump input
-    let mut cancel_tx_without_feebump =
-        CancelTransaction::new(rev_unvault_txin.clone(), None, &der_deposit_descriptor, 0)?;
+    let mut cancel_tx_without_feebump = CancelTransaction::new(
+        rev_unvault_txin.clone(),
+        None,
+        &der_deposit_descriptor,
+        0,
+        &fee_policy,
+    )?;
     roundtrip!(cancel_tx_without_feebump, CancelTransaction);
     assert_eq!(h_cancel, cancel_tx_without_feebump);
     assert_eq!(
@@ -516,6 +808,7 @@ pub fn derive_transactions(
         Some(feebump_txin),
         &der_deposit_descriptor,
         0,
+        &fee_policy,
     )?;
     assert_eq!(
         cancel_tx.deposit_txin(&der_deposit_descriptor).outpoint(),
@@ -554,6 +847,9 @@ pub fn derive_transactions(
     roundtrip!(cancel_tx, CancelTransaction);
     cancel_tx.finalize(&secp)?;
     roundtrip!(cancel_tx, CancelTransaction);
+    // The feebump input is not accounted for, and a finalized transaction can still be checked
+    CancelTransaction::from_raw_psbt(&cancel_tx.as_psbt_serialized(), &fee_policy)
+        .expect("Created with this fee policy");
 
     // We can create it without the feebump input
     let mut unemergency_tx_no_feebump = UnvaultEmergencyTransaction::new(
@@ -561,6 +857,7 @@ pub fn derive_transactions(
         None,
         emergency_address.clone(),
         0,
+        &fee_policy,
     )?;
     roundtrip!(unemergency_tx_no_feebump, UnvaultEmergencyTransaction);
     assert_eq!(h_unemer, unemergency_tx_no_feebump);
@@ -604,6 +901,7 @@ pub fn derive_transactions(
         Some(feebump_txin),
         emergency_address,
         0,
+        &fee_policy,
     )?;
     roundtrip!(unemergency_tx, UnvaultEmergencyTransaction);
     assert_eq!(
@@ -649,6 +947,8 @@ pub fn derive_transactions(
     roundtrip!(unemergency_tx, UnvaultEmergencyTransaction);
     unemergency_tx.finalize(&secp)?;
     roundtrip!(unemergency_tx, UnvaultEmergencyTransaction);
+    UnvaultEmergencyTransaction::from_raw_psbt(&unemergency_tx.as_psbt_serialized(), &fee_policy)
+        .expect("Created with this fee policy");
 
     // Now we can sign the unvault
     let unvault_tx_sighash = unvault_tx
@@ -692,12 +992,15 @@ pub fn derive_transactions(
     // Let's ask for a decent feerate
     let added_feerate = 6121;
     // We try to feebump two unvaults in 1 transaction
-    let cpfp_tx = CpfpTransaction::from_txins(
+    let (cpfp_tx, _) = CpfpTransaction::from_txins(
         cpfp_txins.clone(),
         tbc_weight,
         tbc_fees,
         added_feerate,
         listunspent.clone(),
+        &change_descriptor,
+        false,
+        &LargestFirst,
     )
     .unwrap();
 
@@ -713,7 +1016,7 @@ pub fn derive_transactions(
         assert!(
             (o.value == 0 && o.script_pubkey.is_op_return())
                 || (o.value >= CPFP_MIN_CHANGE
-                    && o.script_pubkey == cpfp_txins[0].txout().txout().script_pubkey)
+                    && o.script_pubkey == cpfp_change_txo.txout().script_pubkey)
         );
     }
 
@@ -781,6 +1084,7 @@ pub fn derive_transactions(
         change_txo,
         &der_cpfp_descriptor,
         0,
+        &fee_policy,
         true,
     )
     .expect("Amounts ok");
@@ -815,6 +1119,7 @@ pub fn derive_transactions(
         None,
         &der_cpfp_descriptor,
         0,
+        &fee_policy,
         true,
     )
     .expect_err("Creating a dust output");
@@ -829,6 +1134,7 @@ pub fn derive_transactions(
         )),
         &der_cpfp_descriptor,
         0,
+        &fee_policy,
         true,
     )
     .expect_err("Creating a dust output");
@@ -881,6 +1187,7 @@ pub fn derive_transactions(
         None,
         &der_cpfp_descriptor,
         0,
+        &fee_policy,
         true,
     )?;
     roundtrip!(spend_tx, SpendTransaction);
@@ -933,12 +1240,15 @@ pub fn derive_transactions(
     let tbc_weight = spend_tx.max_weight();
     let tbc_fees = Amount::from_sat(spend_tx.fees());
     let added_feerate = 6121;
-    let cpfp_tx = CpfpTransaction::from_txins(
+    let (cpfp_tx, _) = CpfpTransaction::from_txins(
         cpfp_txins,
         tbc_weight,
         tbc_fees,
         added_feerate,
         listunspent.clone(),
+        &change_descriptor,
+        false,
+        &LargestFirst,
     )
     .unwrap();
 
@@ -953,7 +1263,7 @@ pub fn derive_transactions(
         assert!(
             (o.value == 0 && o.script_pubkey.is_op_return())
                 || (o.value >= CPFP_MIN_CHANGE
-                    && o.script_pubkey == cpfp_txin.txout().txout().script_pubkey)
+                    && o.script_pubkey == cpfp_change_txo.txout().script_pubkey)
         );
     }
 
@@ -2,8 +2,8 @@ use crate::{
     error::*,
     scripts::*,
     transactions::{
-        utils, CpfpableTransaction, RevaultTransaction, DUST_LIMIT, INSANE_FEES,
-        MAX_STANDARD_TX_WEIGHT, TX_VERSION, UNVAULT_CPFP_VALUE, UNVAULT_TX_FEERATE,
+        utils, CpfpableTransaction, FeePolicy, RevaultTransaction, MAX_STANDARD_TX_WEIGHT,
+        TRUC_MAX_WEIGHT,
     },
     txins::*,
     txouts::*,
@@ -29,57 +29,88 @@ use {
     serde::ser::{Serialize, Serializer},
 };
 
-use std::{collections::BTreeMap, convert::TryInto};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    convert::TryInto,
+};
+
+// The shapes of Unvault transactions, each parsed by its own routine
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum UnvaultShape {
+    Single,
+    Batched,
+    Partial,
+}
 
 impl_revault_transaction!(
     UnvaultTransaction,
+    parse_psbt,
     doc = "The unvaulting transaction, spending a deposit and being eventually spent by a spend transaction (if not revaulted)."
 );
 impl UnvaultTransaction {
     // Internal DRY routine for creating the inner PSBT
     fn create_psbt(
-        deposit_txin: DepositTxIn,
+        deposit_txins: Vec<DepositTxIn>,
         unvault_txout: UnvaultTxOut,
         cpfp_txout: CpfpTxOut,
+        deposit_change_txout: Option<DepositTxOut>,
         lock_time: u32,
+        version: i32,
     ) -> Psbt {
+        // 1 Unvault, 1 CPFP, and the optional deposit change for a partial Unvault
+        let mut psbtouts = vec![
+            PsbtOut {
+                bip32_derivation: unvault_txout.bip32_derivation().clone(),
+                ..PsbtOut::default()
+            },
+            PsbtOut {
+                bip32_derivation: cpfp_txout.bip32_derivation().clone(),
+                ..PsbtOut::default()
+            },
+        ];
+        let mut txouts = vec![unvault_txout.into_txout(), cpfp_txout.into_txout()];
+        if let Some(deposit_change_txout) = deposit_change_txout {
+            psbtouts.push(PsbtOut {
+                bip32_derivation: deposit_change_txout.bip32_derivation().clone(),
+                ..PsbtOut::default()
+            });
+            txouts.push(deposit_change_txout.into_txout());
+        }
+
         Psbt {
-            // 1 Unvault, 1 CPFP
-            outputs: vec![
-                PsbtOut {
-                    bip32_derivation: unvault_txout.bip32_derivation().clone(),
-                    ..PsbtOut::default()
-                },
-                PsbtOut {
-                    bip32_derivation: cpfp_txout.bip32_derivation().clone(),
-                    ..PsbtOut::default()
-                },
-            ],
+            outputs: psbtouts,
             global: PsbtGlobal {
                 unsigned_tx: Transaction {
-                    version: TX_VERSION,
+                    version,
                     lock_time,
-                    input: vec![deposit_txin.unsigned_txin()],
-                    output: vec![unvault_txout.into_txout(), cpfp_txout.into_txout()],
+                    input: deposit_txins
+                        .iter()
+                        .map(|txin| txin.unsigned_txin())
+                        .collect(),
+                    output: txouts,
                 },
                 version: 0,
                 xpub: BTreeMap::new(),
                 proprietary: BTreeMap::new(),
                 unknown: BTreeMap::new(),
             },
-            inputs: vec![PsbtIn {
-                witness_script: Some(deposit_txin.txout().witness_script().clone()),
-                bip32_derivation: deposit_txin.txout().bip32_derivation().clone(),
-                sighash_type: Some(SigHashType::All),
-                witness_utxo: Some(deposit_txin.into_txout().into_txout()),
-                ..PsbtIn::default()
-            }],
+            inputs: deposit_txins
+                .into_iter()
+                .map(|deposit_txin| PsbtIn {
+                    witness_script: Some(deposit_txin.txout().witness_script().clone()),
+                    bip32_derivation: deposit_txin.txout().bip32_derivation().clone(),
+                    sighash_type: Some(SigHashType::All),
+                    witness_utxo: Some(deposit_txin.into_txout().into_txout()),
+                    ..PsbtIn::default()
+                })
+                .collect(),
         }
     }
 
-    /// An unvault transaction always spends one deposit output and contains one CPFP output in
-    /// addition to the unvault one.
-    /// It's always created using a fixed feerate and the CPFP output value is fixed as well.
+    /// An unvault transaction spends one deposit output (see [UnvaultTransaction::new_batched] to
+    /// spend several) and contains one CPFP output in addition to the unvault one.
+    /// It's always created using the fixed feerate and CPFP output value of the fee policy, as a
+    /// TRUC transaction if the policy requires it.
     ///
     /// BIP174 Creator and Updater roles.
     pub fn new(
@@ -87,56 +118,187 @@ impl UnvaultTransaction {
         unvault_descriptor: &DerivedUnvaultDescriptor,
         cpfp_descriptor: &DerivedCpfpDescriptor,
         lock_time: u32,
+        fee_policy: &FeePolicy,
+    ) -> Result<UnvaultTransaction, TransactionCreationError> {
+        UnvaultTransaction::new_batched(
+            vec![deposit_input],
+            unvault_descriptor,
+            cpfp_descriptor,
+            lock_time,
+            fee_policy,
+        )
+    }
+
+    /// A batched unvault transaction spends a set of deposit outputs at once, to a single unvault
+    /// output and a single CPFP output. The fees are paid at the fixed feerate of the fee policy
+    /// for the whole transaction, and the CPFP output gets the same value as for a single deposit.
+    /// The revocation transactions spend its unvault output as for any Unvault transaction.
+    /// A counterparty's batched Unvault is parsed using [UnvaultTransaction::from_raw_psbt_batched].
+    ///
+    /// Panics if no deposit input is given.
+    ///
+    /// BIP174 Creator and Updater roles.
+    pub fn new_batched(
+        deposit_inputs: Vec<DepositTxIn>,
+        unvault_descriptor: &DerivedUnvaultDescriptor,
+        cpfp_descriptor: &DerivedCpfpDescriptor,
+        lock_time: u32,
+        fee_policy: &FeePolicy,
+    ) -> Result<UnvaultTransaction, TransactionCreationError> {
+        assert!(!deposit_inputs.is_empty());
+        UnvaultTransaction::create(
+            deposit_inputs,
+            unvault_descriptor,
+            cpfp_descriptor,
+            None,
+            lock_time,
+            fee_policy,
+        )
+    }
+
+    /// A partial unvault transaction only unvaults `unvault_value` out of the deposit, and sends
+    /// the remainder back to a new deposit output. The fees are paid by the deposit change, which
+    /// must not be dust, and the CPFP output has its usual value. It must be derived at another
+    /// index than the deposit, in order not to reuse its address.
+    /// The Cancel transaction only revaults the unvaulted part. As for the deposit change, it only
+    /// exists once the deposit is spent, invalidating the deposit's Emergency transaction. Being a
+    /// new deposit, it must be covered by its own
+    /// [EmergencyTransaction](crate::transactions::EmergencyTransaction), created from
+    /// [UnvaultTransaction::deposit_change_txin], before the Unvault is signed.
+    /// A counterparty's partial Unvault is parsed using [UnvaultTransaction::from_raw_psbt_partial].
+    ///
+    /// BIP174 Creator and Updater roles.
+    pub fn new_partial(
+        deposit_input: DepositTxIn,
+        unvault_value: Amount,
+        unvault_descriptor: &DerivedUnvaultDescriptor,
+        cpfp_descriptor: &DerivedCpfpDescriptor,
+        deposit_descriptor: &DerivedDepositDescriptor,
+        lock_time: u32,
+        fee_policy: &FeePolicy,
     ) -> Result<UnvaultTransaction, TransactionCreationError> {
+        if deposit_descriptor.inner().script_pubkey() == deposit_input.txout().txout().script_pubkey
+        {
+            return Err(TransactionCreationError::DepositAddressReuse);
+        }
+
+        UnvaultTransaction::create(
+            vec![deposit_input],
+            unvault_descriptor,
+            cpfp_descriptor,
+            Some((unvault_value, deposit_descriptor)),
+            lock_time,
+            fee_policy,
+        )
+    }
+
+    // Create an Unvault transaction spending these deposits. If a partial unvault value is given,
+    // the remainder is sent to a deposit change output. Otherwise it's all unvaulted.
+    fn create(
+        deposit_inputs: Vec<DepositTxIn>,
+        unvault_descriptor: &DerivedUnvaultDescriptor,
+        cpfp_descriptor: &DerivedCpfpDescriptor,
+        partial: Option<(Amount, &DerivedDepositDescriptor)>,
+        lock_time: u32,
+        fee_policy: &FeePolicy,
+    ) -> Result<UnvaultTransaction, TransactionCreationError> {
+        let uniq_outpoints: BTreeSet<OutPoint> =
+            deposit_inputs.iter().map(|txin| txin.outpoint()).collect();
+        if uniq_outpoints.len() != deposit_inputs.len() {
+            return Err(TransactionCreationError::DuplicatedInput);
+        }
+
         // First, create a dummy transaction to get its weight without Witness
         let dummy_unvault_txout = UnvaultTxOut::new(Amount::from_sat(u64::MAX), unvault_descriptor);
         let dummy_cpfp_txout = CpfpTxOut::new(Amount::from_sat(u64::MAX), cpfp_descriptor);
+        let dummy_change_txout =
+            partial.map(|(_, desc)| DepositTxOut::new(Amount::from_sat(u64::MAX), desc));
         let dummy_tx = UnvaultTransaction::create_psbt(
-            deposit_input.clone(),
+            deposit_inputs.clone(),
             dummy_unvault_txout,
             dummy_cpfp_txout,
+            dummy_change_txout,
             lock_time,
+            fee_policy.tx_version(),
         )
         .global
         .unsigned_tx;
 
         // The weight of the transaction once signed will be the size of the witness-stripped
-        // transaction plus the size of the single input's witness.
-        let total_weight = dummy_tx
-            .get_weight()
-            .checked_add(deposit_input.txout().max_sat_weight())
+        // transaction plus the size of the inputs' witnesses.
+        let total_weight = deposit_inputs
+            .iter()
+            .try_fold(dummy_tx.get_weight(), |weight, txin| {
+                weight.checked_add(txin.txout().max_sat_weight())
+            })
             .expect("Properly-computed weights cannot overflow");
         let total_weight: u64 = total_weight.try_into().expect("usize in u64");
-        let fees = UNVAULT_TX_FEERATE
+        if total_weight > MAX_STANDARD_TX_WEIGHT as u64 {
+            return Err(TransactionCreationError::TooLarge);
+        }
+        let fees = fee_policy
+            .unvault_feerate
             .checked_mul(total_weight)
-            .expect("Properly-computed weights cannot overflow");
+            .ok_or(TransactionCreationError::InsaneFees)?;
         // Nobody wants to pay 3k€ fees if we had a bug.
-        if fees > INSANE_FEES {
+        if fees > fee_policy.insane_fees {
             return Err(TransactionCreationError::InsaneFees);
         }
 
-        assert!(
-            total_weight <= MAX_STANDARD_TX_WEIGHT as u64,
-            "A single input and two outputs"
-        );
+        if fee_policy.truc && total_weight > TRUC_MAX_WEIGHT {
+            return Err(TransactionCreationError::TrucViolation);
+        }
 
-        // The unvault output value is then equal to the deposit value minus the fees and the CPFP.
-        let deposit_value = deposit_input.txout().txout().value;
-        if fees + UNVAULT_CPFP_VALUE + DUST_LIMIT > deposit_value {
+        let deposit_value = deposit_inputs
+            .iter()
+            .try_fold(0u64, |sum, txin| {
+                sum.checked_add(txin.txout().txout().value)
+            })
+            .ok_or(TransactionCreationError::InsaneAmounts)?;
+        if deposit_value > max_money(Network::Bitcoin) {
+            return Err(TransactionCreationError::InsaneAmounts);
+        }
+        // The remaining value is either unvaulted, or sent back to a deposit for a partial Unvault.
+        // Either way, it must not be dust.
+        let partial_value = partial.map(|(value, _)| value.as_sat()).unwrap_or(0);
+        if partial.is_some() && partial_value < fee_policy.dust_limit {
             return Err(TransactionCreationError::Dust);
         }
-        let unvault_value = deposit_value - fees - UNVAULT_CPFP_VALUE; // Arithmetic checked above
-        if unvault_value > max_money(Network::Bitcoin) {
-            return Err(TransactionCreationError::InsaneAmounts);
+        let min_value = fees
+            .checked_add(fee_policy.unvault_cpfp_value)
+            .and_then(|v| v.checked_add(fee_policy.dust_limit))
+            .and_then(|v| v.checked_add(partial_value))
+            .ok_or(TransactionCreationError::InsaneAmounts)?;
+        if min_value > deposit_value {
+            return Err(TransactionCreationError::Dust);
         }
+        // Arithmetic checked above
+        let remaining_value = deposit_value - fees - fee_policy.unvault_cpfp_value - partial_value;
 
-        let unvault_txout = UnvaultTxOut::new(Amount::from_sat(unvault_value), unvault_descriptor);
-        let cpfp_txout = CpfpTxOut::new(Amount::from_sat(UNVAULT_CPFP_VALUE), cpfp_descriptor);
+        let cpfp_txout = CpfpTxOut::new(
+            Amount::from_sat(fee_policy.unvault_cpfp_value),
+            cpfp_descriptor,
+        );
+        let (unvault_txout, deposit_change_txout) = match partial {
+            Some((unvault_value, deposit_descriptor)) => (
+                UnvaultTxOut::new(unvault_value, unvault_descriptor),
+                Some(DepositTxOut::new(
+                    Amount::from_sat(remaining_value),
+                    deposit_descriptor,
+                )),
+            ),
+            None => (
+                UnvaultTxOut::new(Amount::from_sat(remaining_value), unvault_descriptor),
+                None,
+            ),
+        };
         Ok(UnvaultTransaction(UnvaultTransaction::create_psbt(
-            deposit_input,
+            deposit_inputs,
             unvault_txout,
             cpfp_txout,
+            deposit_change_txout,
             lock_time,
+            fee_policy.tx_version(),
         )))
     }
 
@@ -181,66 +343,242 @@ impl UnvaultTransaction {
         self.unvault_txin(unvault_descriptor, RBF_SEQUENCE)
     }
 
-    /// Parse an Unvault transaction from a PSBT
-    pub fn from_raw_psbt(raw_psbt: &[u8]) -> Result<Self, TransactionSerialisationError> {
+    /// Get the deposit change txo of a partial Unvault, to be referenced by its Emergency and
+    /// Unvault transactions. None if this Unvault doesn't pay to this deposit descriptor.
+    pub fn deposit_change_txin(
+        &self,
+        deposit_descriptor: &DerivedDepositDescriptor,
+    ) -> Option<DepositTxIn> {
+        let spk = deposit_descriptor.inner().script_pubkey();
+        let tx = &self.psbt().global.unsigned_tx;
+        let index = tx.output.iter().position(|txo| txo.script_pubkey == spk)?;
+
+        let prev_txout =
+            DepositTxOut::new(Amount::from_sat(tx.output[index].value), deposit_descriptor);
+        Some(DepositTxIn::new(
+            OutPoint {
+                txid: tx.txid(),
+                vout: index.try_into().expect("There are three outputs"),
+            },
+            prev_txout,
+        ))
+    }
+
+    // Parse an Unvault transaction spending a single deposit and without deposit change from a
+    // PSBT, only checking its structure. This is what the deserialization routines use, as they
+    // don't know about the fee policy.
+    fn parse_psbt(raw_psbt: &[u8]) -> Result<Self, TransactionSerialisationError> {
+        UnvaultTransaction::parse_structure(raw_psbt, UnvaultShape::Single)
+    }
+
+    // Parse an Unvault transaction of this shape from a PSBT, only checking its structure.
+    fn parse_structure(
+        raw_psbt: &[u8],
+        shape: UnvaultShape,
+    ) -> Result<Self, TransactionSerialisationError> {
         let psbt = Decodable::consensus_decode(raw_psbt)?;
-        let psbt = utils::psbt_common_sanity_checks(psbt)?;
+        let psbt = utils::psbt_common_sanity_checks(psbt, false, Some(TRUC_MAX_WEIGHT))?;
 
-        // Unvault + CPFP txos
+        // Unvault + CPFP txos, and a deposit change txo for a partial Unvault
         let output_count = psbt.global.unsigned_tx.output.len();
-        if output_count != 2 {
+        let expected_output_count = if shape == UnvaultShape::Partial { 3 } else { 2 };
+        if output_count != expected_output_count {
             return Err(PsbtValidationError::InvalidOutputCount(output_count).into());
         }
 
         for output in psbt.outputs.iter() {
             if output.bip32_derivation.is_empty() {
-                return Err(PsbtValidationError::InvalidOutputField(output.clone()).into());
+                return Err(
+                    PsbtValidationError::InvalidOutputField(Box::new(output.clone())).into(),
+                );
+            }
+        }
+        // The deposit change is a deposit txo, it must be P2WSH
+        if let Some(deposit_change) = psbt.global.unsigned_tx.output.get(2) {
+            if !deposit_change.script_pubkey.is_v0_p2wsh() {
+                return Err(PsbtValidationError::InvalidOutputField(Box::new(
+                    psbt.outputs[2].clone(),
+                ))
+                .into());
             }
         }
 
+        // One deposit input, or several for a batched Unvault
         let input_count = psbt.global.unsigned_tx.input.len();
-        // We for now have 1 unvault == 1 deposit
-        if input_count != 1 {
+        if input_count < 1 || (input_count > 1 && shape != UnvaultShape::Batched) {
             return Err(PsbtValidationError::InvalidInputCount(input_count).into());
         }
-        let input = &psbt.inputs[0];
-        if input.final_script_witness.is_none() {
+        for input in psbt.inputs.iter() {
+            let spk = &input
+                .witness_utxo
+                .as_ref()
+                .expect("Check in sanity checks")
+                .script_pubkey;
+            if !spk.is_v0_p2wsh() {
+                return Err(
+                    PsbtValidationError::InvalidPrevoutType(Box::new(input.clone())).into(),
+                );
+            }
+
+            if input.final_script_witness.is_some() {
+                continue;
+            }
+
             if input.sighash_type != Some(SigHashType::All) {
-                return Err(PsbtValidationError::InvalidSighashType(input.clone()).into());
+                return Err(
+                    PsbtValidationError::InvalidSighashType(Box::new(input.clone())).into(),
+                );
             }
 
             if input.bip32_derivation.is_empty() {
-                return Err(PsbtValidationError::InvalidInputField(input.clone()).into());
+                return Err(PsbtValidationError::InvalidInputField(Box::new(input.clone())).into());
             }
 
             if let Some(ref ws) = input.witness_script {
-                if ws.to_v0_p2wsh()
-                    != input
-                        .witness_utxo
-                        .as_ref()
-                        .expect("Check in sanity checks")
-                        .script_pubkey
-                {
-                    return Err(PsbtValidationError::InvalidInWitnessScript(input.clone()).into());
+                if &ws.to_v0_p2wsh() != spk {
+                    return Err(PsbtValidationError::InvalidInWitnessScript(Box::new(
+                        input.clone(),
+                    ))
+                    .into());
                 }
             } else {
-                return Err(PsbtValidationError::MissingInWitnessScript(input.clone()).into());
+                return Err(
+                    PsbtValidationError::MissingInWitnessScript(Box::new(input.clone())).into(),
+                );
             }
         }
 
-        // NOTE: the Unvault transaction cannot get larger than MAX_STANDARD_TX_WEIGHT
+        // A batched Unvault must not get out of standardness bounds once finalized
+        let weight = fees_weight(&psbt)?;
+        if weight > MAX_STANDARD_TX_WEIGHT as u64 {
+            return Err(PsbtValidationError::TransactionTooLarge.into());
+        }
 
         Ok(UnvaultTransaction(psbt))
     }
 
-    /// Add a signature for the (single) input spending the Deposit transaction
+    /// Parse an Unvault transaction spending a single deposit from a PSBT, checking it was
+    /// created under this fee policy.
+    pub fn from_raw_psbt(
+        raw_psbt: &[u8],
+        fee_policy: &FeePolicy,
+    ) -> Result<Self, TransactionSerialisationError> {
+        UnvaultTransaction::parse_with_fee_policy(raw_psbt, fee_policy, UnvaultShape::Single)
+    }
+
+    /// Parse an Unvault transaction spending one or more deposits from a PSBT, as created by
+    /// [UnvaultTransaction::new_batched], checking it was created under this fee policy.
+    pub fn from_raw_psbt_batched(
+        raw_psbt: &[u8],
+        fee_policy: &FeePolicy,
+    ) -> Result<Self, TransactionSerialisationError> {
+        UnvaultTransaction::parse_with_fee_policy(raw_psbt, fee_policy, UnvaultShape::Batched)
+    }
+
+    /// Parse a partial Unvault transaction from a PSBT, as created by
+    /// [UnvaultTransaction::new_partial], checking it was created under this fee policy and that
+    /// its deposit change pays to a new address of this deposit descriptor.
+    pub fn from_raw_psbt_partial<C: secp256k1::Verification>(
+        raw_psbt: &[u8],
+        fee_policy: &FeePolicy,
+        deposit_descriptor: &DepositDescriptor,
+        secp: &secp256k1::Secp256k1<C>,
+    ) -> Result<Self, TransactionSerialisationError> {
+        let unvault_tx =
+            UnvaultTransaction::parse_with_fee_policy(raw_psbt, fee_policy, UnvaultShape::Partial)?;
+        let psbt = unvault_tx.psbt();
+        let tx = &psbt.global.unsigned_tx;
+
+        // The deposit change must be derived from our deposit descriptor at a single-step
+        // unhardened path, and not at the index of the deposit it spends.
+        let change_psbtout = &psbt.outputs[2];
+        let der_path = &change_psbtout
+            .bip32_derivation
+            .values()
+            .next()
+            .expect("Checked in the structure checks")
+            .1;
+        let change_index = match der_path.as_ref() {
+            [index] if index.is_normal() => *index,
+            _ => {
+                return Err(PsbtValidationError::InvalidOutputField(Box::new(
+                    change_psbtout.clone(),
+                ))
+                .into())
+            }
+        };
+        let change_spk = deposit_descriptor
+            .derive(change_index, secp)
+            .inner()
+            .script_pubkey();
+        let deposit_spk = &psbt.inputs[0]
+            .witness_utxo
+            .as_ref()
+            .expect("Checked in the sanity checks")
+            .script_pubkey;
+        if tx.output[2].script_pubkey != change_spk || &change_spk == deposit_spk {
+            return Err(
+                PsbtValidationError::InvalidOutputField(Box::new(change_psbtout.clone())).into(),
+            );
+        }
+
+        Ok(unvault_tx)
+    }
+
+    // Parse an Unvault transaction of this shape from a PSBT, checking it was created under this
+    // fee policy.
+    fn parse_with_fee_policy(
+        raw_psbt: &[u8],
+        fee_policy: &FeePolicy,
+        shape: UnvaultShape,
+    ) -> Result<Self, TransactionSerialisationError> {
+        let unvault_tx = UnvaultTransaction::parse_structure(raw_psbt, shape)?;
+        let psbt = unvault_tx.psbt();
+        let tx = &psbt.global.unsigned_tx;
+
+        if tx.version != fee_policy.tx_version() {
+            return Err(PsbtValidationError::InvalidTransactionVersion(tx.version).into());
+        }
+
+        // We always create the Unvault output first, and then the CPFP one
+        let (unvault_value, cpfp_value) = (tx.output[0].value, tx.output[1].value);
+        if cpfp_value != fee_policy.unvault_cpfp_value {
+            return Err(PsbtValidationError::InvalidCpfpValue(cpfp_value).into());
+        }
+        if unvault_value < fee_policy.dust_limit {
+            return Err(PsbtValidationError::DustOutput(unvault_value).into());
+        }
+        if let Some(deposit_change) = tx.output.get(2) {
+            if deposit_change.value < fee_policy.dust_limit {
+                return Err(PsbtValidationError::DustOutput(deposit_change.value).into());
+            }
+        }
+
+        let fees = unvault_tx.fees();
+        if fees > fee_policy.insane_fees {
+            return Err(PsbtValidationError::InsaneFees(fees).into());
+        }
+        let weight = fees_weight(psbt)?;
+        let expected_fees = fee_policy
+            .unvault_feerate
+            .checked_mul(weight)
+            .ok_or(PsbtValidationError::InsaneAmounts)?;
+        if fees != expected_fees {
+            return Err(PsbtValidationError::InvalidFees(fees, expected_fees).into());
+        }
+
+        Ok(unvault_tx)
+    }
+
+    /// Add a signature for the (first) input spending a Deposit transaction. The signatures for
+    /// the other inputs of a batched Unvault transaction are added using
+    /// [RevaultTransaction::add_signature].
     pub fn add_sig<C: secp256k1::Verification>(
         &mut self,
         pubkey: secp256k1::PublicKey,
         signature: secp256k1::Signature,
         secp: &secp256k1::Secp256k1<C>,
     ) -> Result<Option<Vec<u8>>, InputSatisfactionError> {
-        // We are only ever created with a single input
         let input_index = 0;
         RevaultTransaction::add_signature(self, input_index, pubkey, signature, secp)
     }
@@ -251,37 +589,480 @@ impl CpfpableTransaction for UnvaultTransaction {
         let psbt = self.psbt();
         let tx = &psbt.global.unsigned_tx;
 
-        // We are only ever created with exactly one input.
-        let txin = &psbt.inputs[0];
-        let txin_weight: u64 = if self.is_finalized() {
-            txin.final_script_witness
-                .as_ref()
-                .expect("Always set if final")
-                .iter()
-                .map(|e| e.len())
-                .sum::<usize>()
-                .try_into()
-                .expect("Bug: witness size >u64::MAX")
-        } else {
-            // FIXME: this panic can probably be triggered...
-            miniscript::descriptor::Wsh::new(
-                miniscript::Miniscript::parse(
-                    txin.witness_script
+        // One input per deposit spent
+        let is_finalized = self.is_finalized();
+        let txins_weight: u64 = psbt
+            .inputs
+            .iter()
+            .map(|txin| -> u64 {
+                if is_finalized {
+                    txin.final_script_witness
                         .as_ref()
-                        .expect("Unvault txins always have a witness Script"),
-                )
-                .expect("UnvaultTxIn witness_script is created from a Miniscript"),
-            )
-            .expect("")
-            .max_satisfaction_weight()
-            .expect("It's a sane Script, derived from a Miniscript")
-            .try_into()
-            .expect("Can't be >u64::MAX")
-        };
+                        .expect("Always set if final")
+                        .iter()
+                        .map(|e| e.len())
+                        .sum::<usize>()
+                        .try_into()
+                        .expect("Bug: witness size >u64::MAX")
+                } else {
+                    // FIXME: this panic can probably be triggered...
+                    miniscript::descriptor::Wsh::new(
+                        miniscript::Miniscript::parse(
+                            txin.witness_script
+                                .as_ref()
+                                .expect("Unvault txins always have a witness Script"),
+                        )
+                        .expect("UnvaultTxIn witness_script is created from a Miniscript"),
+                    )
+                    .expect("")
+                    .max_satisfaction_weight()
+                    .expect("It's a sane Script, derived from a Miniscript")
+                    .try_into()
+                    .expect("Can't be >u64::MAX")
+                }
+            })
+            .sum();
 
         let weight: u64 = tx.get_weight().try_into().expect("Can't be >u64::MAX");
-        let weight = weight + txin_weight;
+        let weight = weight + txins_weight;
         assert!(weight > 0, "We never create an empty tx");
         weight
     }
 }
+
+// The weight of the Unvault transaction used to compute its fees: the witness-stripped
+// transaction plus the maximum satisfaction weight of each deposit input.
+fn fees_weight(psbt: &Psbt) -> Result<u64, PsbtValidationError> {
+    let witstrip_weight: u64 = psbt
+        .global
+        .unsigned_tx
+        .get_weight()
+        .try_into()
+        .expect("usize in u64");
+
+    psbt.inputs
+        .iter()
+        .try_fold(witstrip_weight, |weight, input| {
+            weight
+                .checked_add(utils::input_max_sat_weight(input)?)
+                .ok_or(PsbtValidationError::InsaneAmounts)
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transactions::{
+        spend_tx_from_deposits_batched,
+        tests_helpers::{satisfy_transaction_input, TestDeployment},
+        transaction_chain_batched, transaction_chain_partial, TRUC_TX_VERSION,
+    };
+
+    use miniscript::bitcoin::{consensus::encode, util::bip32, TxOut};
+    use std::str::FromStr;
+
+    #[test]
+    fn unvault_fee_policy() {
+        let secp = secp256k1::Secp256k1::new();
+        let deployment = TestDeployment::new(3, 2, false, 360_000_000, &secp);
+        let fee_policy = FeePolicy::default();
+        let (unvault_tx, _, _, _) = deployment.chain(&fee_policy, &secp);
+
+        // It was created under our fee policy, but not under another one
+        assert_eq!(
+            UnvaultTransaction::from_raw_psbt(&unvault_tx.as_psbt_serialized(), &fee_policy),
+            Ok(unvault_tx.clone())
+        );
+        let other_policy = FeePolicy {
+            unvault_cpfp_value: fee_policy.unvault_cpfp_value + 1,
+            ..fee_policy.clone()
+        };
+        assert_eq!(
+            UnvaultTransaction::from_raw_psbt(&unvault_tx.as_psbt_serialized(), &other_policy),
+            Err(TransactionSerialisationError::Validation(
+                PsbtValidationError::InvalidCpfpValue(fee_policy.unvault_cpfp_value)
+            ))
+        );
+    }
+
+    #[test]
+    fn truc_unvault() {
+        let secp = secp256k1::Secp256k1::new();
+        let deployment = TestDeployment::new(3, 2, false, 360_000_000, &secp);
+        let fee_policy = FeePolicy::default();
+        let truc_policy = FeePolicy {
+            truc: true,
+            ..fee_policy.clone()
+        };
+
+        // The Unvault can be created as a TRUC transaction, if the fee policy says so
+        let (truc_unvault, _, _, _) = deployment.chain(&truc_policy, &secp);
+        assert_eq!(truc_unvault.tx().version, TRUC_TX_VERSION);
+        assert_eq!(
+            UnvaultTransaction::from_raw_psbt(&truc_unvault.as_psbt_serialized(), &truc_policy),
+            Ok(truc_unvault.clone())
+        );
+        assert!(matches!(
+            UnvaultTransaction::from_raw_psbt(&truc_unvault.as_psbt_serialized(), &fee_policy),
+            Err(TransactionSerialisationError::Validation(
+                PsbtValidationError::InvalidTransactionVersion(TRUC_TX_VERSION)
+            ))
+        ));
+    }
+
+    #[test]
+    fn batched_unvault() {
+        let secp = secp256k1::Secp256k1::new();
+        let deployment = TestDeployment::new(3, 2, false, 360_000_000, &secp);
+        let fee_policy = FeePolicy::default();
+        let deposit_value = deployment.deposit_amount().as_sat();
+
+        // Several deposits can be unvaulted at once by a batched Unvault transaction, with a
+        // single Unvault output to be revaulted and a single CPFP output
+        let batch: Vec<(OutPoint, Amount, bip32::ChildNumber)> = (0..3)
+            .map(|i| {
+                (
+                    OutPoint {
+                        txid: deployment.deposit_outpoint().txid,
+                        vout: 10 + i,
+                    },
+                    deployment.deposit_amount(),
+                    bip32::ChildNumber::from(10 + i),
+                )
+            })
+            .collect();
+        let (mut batched_unvault, batched_cancel, batched_emers, batched_unemer) =
+            transaction_chain_batched(
+                batch.clone(),
+                &deployment.deposit_descriptor,
+                &deployment.unvault_descriptor,
+                &deployment.cpfp_descriptor,
+                deployment.emergency_address.clone(),
+                0,
+                &fee_policy,
+                &secp,
+            )
+            .unwrap();
+        assert_eq!(batched_unvault.tx().input.len(), 3);
+        assert_eq!(batched_unvault.tx().output.len(), 2);
+        assert_eq!(
+            batched_unvault.fees(),
+            batched_unvault.max_weight() * fee_policy.unvault_feerate
+        );
+        assert_eq!(
+            batched_unvault.tx().output[0].value,
+            3 * deposit_value - batched_unvault.fees() - fee_policy.unvault_cpfp_value
+        );
+        assert_eq!(
+            UnvaultTransaction::from_raw_psbt_batched(
+                &batched_unvault.as_psbt_serialized(),
+                &fee_policy
+            ),
+            Ok(batched_unvault.clone())
+        );
+        // Only if a batched Unvault is expected, which the deserialization routines don't
+        for err in [
+            UnvaultTransaction::from_raw_psbt(&batched_unvault.as_psbt_serialized(), &fee_policy),
+            UnvaultTransaction::from_psbt_serialized(&batched_unvault.as_psbt_serialized()),
+            UnvaultTransaction::from_str(&batched_unvault.as_psbt_string()),
+        ]
+        .iter()
+        {
+            assert!(matches!(
+                err,
+                Err(TransactionSerialisationError::Validation(
+                    PsbtValidationError::InvalidInputCount(3)
+                ))
+            ));
+        }
+
+        // The Unvault and CPFP outputs are derived at the highest index of the batch
+        let last_child = bip32::ChildNumber::from(12);
+        let der_last_unvault_descriptor = deployment.unvault_descriptor.derive(last_child, &secp);
+        let batched_unvault_txin =
+            batched_unvault.revault_unvault_txin(&der_last_unvault_descriptor);
+        assert_eq!(
+            batched_cancel.tx().input[0].previous_output,
+            batched_unvault_txin.outpoint()
+        );
+        assert_eq!(
+            batched_cancel.tx().output[0].script_pubkey,
+            deployment
+                .deposit_descriptor
+                .derive(last_child, &secp)
+                .inner()
+                .script_pubkey()
+        );
+        assert_eq!(
+            batched_unemer.tx().input[0].previous_output,
+            batched_unvault_txin.outpoint()
+        );
+        // Each deposit still gets its own Emergency transaction
+        assert_eq!(batched_emers.len(), 3);
+        for (emer, (outpoint, _, _)) in batched_emers.iter().zip(batch.iter()) {
+            assert_eq!(emer.tx().input[0].previous_output, *outpoint);
+        }
+
+        // A single Spend input for the whole batch
+        let batched_spend = spend_tx_from_deposits_batched(
+            batch.clone(),
+            vec![SpendTxOut::new(TxOut {
+                value: batched_unvault.tx().output[0].value / 2,
+                ..TxOut::default()
+            })],
+            None,
+            &deployment.deposit_descriptor,
+            &deployment.unvault_descriptor,
+            &deployment.cpfp_descriptor,
+            0,
+            &fee_policy,
+            false,
+            &secp,
+        )
+        .unwrap();
+        assert_eq!(batched_spend.tx().input.len(), 1);
+        assert_eq!(
+            batched_spend.tx().input[0].previous_output,
+            batched_unvault
+                .spend_unvault_txin(&der_last_unvault_descriptor)
+                .outpoint()
+        );
+
+        // A deposit can't be spent twice
+        assert_eq!(
+            UnvaultTransaction::new_batched(
+                vec![
+                    deployment.deposit_txin.clone(),
+                    deployment.deposit_txin.clone()
+                ],
+                &deployment.der_unvault_descriptor(&secp),
+                &deployment.der_cpfp_descriptor(&secp),
+                0,
+                &fee_policy,
+            ),
+            Err(TransactionCreationError::DuplicatedInput)
+        );
+
+        // Each deposit input of a batched Unvault is checked at parsing
+        let mut invalid_psbt = batched_unvault.psbt().clone();
+        invalid_psbt.inputs[2].witness_script = None;
+        assert!(matches!(
+            UnvaultTransaction::from_raw_psbt_batched(
+                &encode::serialize(&invalid_psbt),
+                &fee_policy
+            ),
+            Err(TransactionSerialisationError::Validation(
+                PsbtValidationError::MissingInWitnessScript(_)
+            ))
+        ));
+
+        // All its inputs are signed by the stakeholders, each at their deposit's derivation index
+        for (i, (_, _, child)) in batch.iter().enumerate() {
+            let sighash = batched_unvault
+                .signature_hash(i, SigHashType::All)
+                .expect("Input exists");
+            satisfy_transaction_input(
+                &secp,
+                &mut batched_unvault,
+                i,
+                &sighash,
+                &deployment.stakeholders_priv,
+                Some(*child),
+            )
+            .unwrap();
+        }
+        batched_unvault.finalize(&secp).unwrap();
+        assert!(batched_unvault.is_valid(&secp));
+        assert_eq!(
+            UnvaultTransaction::from_raw_psbt_batched(
+                &batched_unvault.as_psbt_serialized(),
+                &fee_policy
+            ),
+            Ok(batched_unvault)
+        );
+    }
+
+    #[test]
+    fn partial_unvault() {
+        let secp = secp256k1::Secp256k1::new();
+        let deployment = TestDeployment::new(3, 2, false, 360_000_000, &secp);
+        let fee_policy = FeePolicy::default();
+        let deposit_value = deployment.deposit_amount().as_sat();
+        let (unvault_tx, _, _, _) = deployment.chain(&fee_policy, &secp);
+        let der_unvault_descriptor = deployment.der_unvault_descriptor(&secp);
+        let der_cpfp_descriptor = deployment.der_cpfp_descriptor(&secp);
+
+        // Only part of a deposit can be unvaulted, the remainder being sent back to a new deposit
+        let change_child = bip32::ChildNumber::from(13);
+        let der_change_descriptor = deployment.deposit_descriptor.derive(change_child, &secp);
+        let partial_value = deposit_value / 2;
+        let (partial_unvault, partial_cancel, partial_emer, partial_unemer, change_emer) =
+            transaction_chain_partial(
+                deployment.deposit_outpoint(),
+                deployment.deposit_amount(),
+                Amount::from_sat(partial_value),
+                &deployment.deposit_descriptor,
+                &deployment.unvault_descriptor,
+                &deployment.cpfp_descriptor,
+                deployment.child_number,
+                change_child,
+                deployment.emergency_address.clone(),
+                0,
+                &fee_policy,
+                &secp,
+            )
+            .unwrap();
+        assert_eq!(partial_unvault.tx().output.len(), 3);
+        assert_eq!(partial_unvault.tx().output[0].value, partial_value);
+        assert_eq!(
+            partial_unvault.fees(),
+            partial_unvault.max_weight() * fee_policy.unvault_feerate
+        );
+        let change_txin = partial_unvault
+            .deposit_change_txin(&der_change_descriptor)
+            .expect("A partial Unvault");
+        assert_eq!(
+            change_txin.txout().txout().value,
+            deposit_value - partial_value - fee_policy.unvault_cpfp_value - partial_unvault.fees()
+        );
+        assert!(unvault_tx
+            .deposit_change_txin(&der_change_descriptor)
+            .is_none());
+        assert_eq!(
+            UnvaultTransaction::from_raw_psbt_partial(
+                &partial_unvault.as_psbt_serialized(),
+                &fee_policy,
+                &deployment.deposit_descriptor,
+                &secp,
+            ),
+            Ok(partial_unvault.clone())
+        );
+        // Only if a partial Unvault is expected, which the deserialization routines don't
+        for err in [
+            UnvaultTransaction::from_raw_psbt(&partial_unvault.as_psbt_serialized(), &fee_policy),
+            UnvaultTransaction::from_raw_psbt_batched(
+                &partial_unvault.as_psbt_serialized(),
+                &fee_policy,
+            ),
+            UnvaultTransaction::from_psbt_serialized(&partial_unvault.as_psbt_serialized()),
+            UnvaultTransaction::from_str(&partial_unvault.as_psbt_string()),
+        ]
+        .iter()
+        {
+            assert!(matches!(
+                err,
+                Err(TransactionSerialisationError::Validation(
+                    PsbtValidationError::InvalidOutputCount(3)
+                ))
+            ));
+        }
+        // Nor is a regular Unvault a partial one
+        assert!(matches!(
+            UnvaultTransaction::from_raw_psbt_partial(
+                &unvault_tx.as_psbt_serialized(),
+                &fee_policy,
+                &deployment.deposit_descriptor,
+                &secp,
+            ),
+            Err(TransactionSerialisationError::Validation(
+                PsbtValidationError::InvalidOutputCount(2)
+            ))
+        ));
+
+        // The deposit change must pay to our deposit descriptor
+        let mut psbt = partial_unvault.psbt().clone();
+        psbt.global.unsigned_tx.output[2].script_pubkey =
+            der_unvault_descriptor.inner().script_pubkey();
+        assert!(matches!(
+            UnvaultTransaction::from_raw_psbt_partial(
+                &encode::serialize(&psbt),
+                &fee_policy,
+                &deployment.deposit_descriptor,
+                &secp,
+            ),
+            Err(TransactionSerialisationError::Validation(
+                PsbtValidationError::InvalidOutputField(..)
+            ))
+        ));
+        // At an unhardened index
+        let mut psbt = partial_unvault.psbt().clone();
+        for (_, (_, der_path)) in psbt.outputs[2].bip32_derivation.iter_mut() {
+            *der_path = vec![bip32::ChildNumber::from_hardened_idx(13).unwrap()].into();
+        }
+        assert!(matches!(
+            UnvaultTransaction::from_raw_psbt_partial(
+                &encode::serialize(&psbt),
+                &fee_policy,
+                &deployment.deposit_descriptor,
+                &secp,
+            ),
+            Err(TransactionSerialisationError::Validation(
+                PsbtValidationError::InvalidOutputField(..)
+            ))
+        ));
+
+        // The Cancel and UnvaultEmergency only cover the unvaulted part
+        let partial_unvault_txin = partial_unvault.revault_unvault_txin(&der_unvault_descriptor);
+        assert_eq!(partial_unvault_txin.txout().txout().value, partial_value);
+        assert_eq!(
+            partial_cancel.tx().input[0].previous_output,
+            partial_unvault_txin.outpoint()
+        );
+        assert_eq!(
+            partial_unemer.tx().input[0].previous_output,
+            partial_unvault_txin.outpoint()
+        );
+        // The original deposit is covered as usual, and the deposit change by its own Emergency
+        assert_eq!(
+            partial_emer.tx().input[0].previous_output,
+            deployment.deposit_outpoint()
+        );
+        assert_eq!(
+            change_emer.tx().input[0].previous_output,
+            change_txin.outpoint()
+        );
+        assert_eq!(
+            change_emer.tx().output[0].script_pubkey,
+            deployment.emergency_address.address().script_pubkey()
+        );
+
+        // The deposit change may not reuse the address of the deposit
+        assert_eq!(
+            UnvaultTransaction::new_partial(
+                deployment.deposit_txin.clone(),
+                Amount::from_sat(partial_value),
+                &der_unvault_descriptor,
+                &der_cpfp_descriptor,
+                &deployment.der_deposit_descriptor(&secp),
+                0,
+                &fee_policy,
+            ),
+            Err(TransactionCreationError::DepositAddressReuse)
+        );
+        // Neither the unvaulted part nor the deposit change may be dust
+        assert_eq!(
+            UnvaultTransaction::new_partial(
+                deployment.deposit_txin.clone(),
+                Amount::from_sat(fee_policy.dust_limit - 1),
+                &der_unvault_descriptor,
+                &der_cpfp_descriptor,
+                &der_change_descriptor,
+                0,
+                &fee_policy,
+            ),
+            Err(TransactionCreationError::Dust)
+        );
+        assert_eq!(
+            UnvaultTransaction::new_partial(
+                deployment.deposit_txin.clone(),
+                Amount::from_sat(deposit_value - fee_policy.unvault_cpfp_value),
+                &der_unvault_descriptor,
+                &der_cpfp_descriptor,
+                &der_change_descriptor,
+                0,
+                &fee_policy,
+            ),
+            Err(TransactionCreationError::Dust)
+        );
+    }
+}
@@ -2,7 +2,8 @@ use crate::{
     error::*,
     scripts::*,
     transactions::{
-        utils, RevaultTransaction, EMER_TX_FEERATE, INSANE_FEES, MAX_STANDARD_TX_WEIGHT, TX_VERSION,
+        utils, CpfpableTransaction, FeePolicy, RevaultTransaction, RevocationTransaction,
+        MAX_STANDARD_TX_WEIGHT, TRUC_MAX_WEIGHT,
     },
     txins::*,
     txouts::*,
@@ -29,6 +30,7 @@ use std::{collections::BTreeMap, convert::TryInto};
 
 impl_revault_transaction!(
     UnvaultEmergencyTransaction,
+    parse_psbt,
     doc = "The transaction spending an unvault output to The Emergency Script."
 );
 impl UnvaultEmergencyTransaction {
@@ -37,7 +39,9 @@ impl UnvaultEmergencyTransaction {
         unvault_txin: UnvaultTxIn,
         feebump_txin: Option<FeeBumpTxIn>,
         emergency_txo: EmergencyTxOut,
+        anchor_txo: Option<AnchorTxOut>,
         lock_time: u32,
+        version: i32,
     ) -> Psbt {
         let mut txins = vec![unvault_txin.unsigned_txin()];
         let mut psbtins = vec![PsbtIn {
@@ -56,13 +60,21 @@ impl UnvaultEmergencyTransaction {
             });
         }
 
+        // Emergency txout, and the optional anchor txout
+        let mut psbtouts = vec![PsbtOut::default()];
+        let mut txouts = vec![emergency_txo.into_txout()];
+        if let Some(anchor_txo) = anchor_txo {
+            psbtouts.push(PsbtOut::default());
+            txouts.push(anchor_txo.into_txout());
+        }
+
         Psbt {
             global: PsbtGlobal {
                 unsigned_tx: Transaction {
-                    version: TX_VERSION,
+                    version,
                     lock_time,
                     input: txins,
-                    output: vec![emergency_txo.into_txout()],
+                    output: txouts,
                 },
                 version: 0,
                 xpub: BTreeMap::new(),
@@ -70,8 +82,7 @@ impl UnvaultEmergencyTransaction {
                 unknown: BTreeMap::new(),
             },
             inputs: psbtins,
-            // Deposit txout
-            outputs: vec![PsbtOut::default()],
+            outputs: psbtouts,
         }
     }
 
@@ -84,6 +95,51 @@ impl UnvaultEmergencyTransaction {
         feebump_input: Option<FeeBumpTxIn>,
         emer_address: EmergencyAddress,
         lock_time: u32,
+        fee_policy: &FeePolicy,
+    ) -> Result<UnvaultEmergencyTransaction, TransactionCreationError> {
+        UnvaultEmergencyTransaction::with_feerate(
+            unvault_input,
+            feebump_input,
+            None,
+            emer_address,
+            lock_time,
+            fee_policy.emer_feerate,
+            fee_policy,
+        )
+    }
+
+    /// Create an UnvaultEmergency transaction with a keyless anchor output in addition to its main output,
+    /// so that it can be fee-bumped through CPFP instead of by adding a fee-bumping input. The
+    /// anchor output value is paid for by the revocation input, on top of the fees.
+    ///
+    /// BIP174 Creator and Updater roles.
+    pub fn new_with_anchor(
+        unvault_input: UnvaultTxIn,
+        emer_address: EmergencyAddress,
+        lock_time: u32,
+        fee_policy: &FeePolicy,
+    ) -> Result<UnvaultEmergencyTransaction, TransactionCreationError> {
+        UnvaultEmergencyTransaction::with_feerate(
+            unvault_input,
+            None,
+            Some(AnchorTxOut::default()),
+            emer_address,
+            lock_time,
+            fee_policy.emer_feerate,
+            fee_policy,
+        )
+    }
+
+    // Create an UnvaultEmergency transaction at this feerate (in sat/WU), used for the fee ladder
+    // and the anchored variant.
+    pub(super) fn with_feerate(
+        unvault_input: UnvaultTxIn,
+        feebump_input: Option<FeeBumpTxIn>,
+        anchor_txo: Option<AnchorTxOut>,
+        emer_address: EmergencyAddress,
+        lock_time: u32,
+        feerate: u64,
+        fee_policy: &FeePolicy,
     ) -> Result<UnvaultEmergencyTransaction, TransactionCreationError> {
         if let Some(ref txin) = feebump_input {
             if txin.txout().txout().value > max_money(Network::Bitcoin) {
@@ -98,7 +154,9 @@ impl UnvaultEmergencyTransaction {
             unvault_input.clone(),
             None,
             emer_txo,
+            anchor_txo.clone(),
             lock_time,
+            fee_policy.tx_version(),
         )
         .global
         .unsigned_tx;
@@ -110,46 +168,60 @@ impl UnvaultEmergencyTransaction {
             .checked_add(unvault_input.txout().max_sat_weight())
             .expect("Weight computation bug");
         let total_weight: u64 = total_weight.try_into().expect("usize in u64");
-        let fees = EMER_TX_FEERATE
+        let fees = feerate
             .checked_mul(total_weight)
-            .expect("Weight computation bug");
-        // Without the feebump input, it should not be reachable.
-        debug_assert!(fees < INSANE_FEES);
+            .ok_or(TransactionCreationError::InsaneFees)?;
 
         assert!(
             total_weight <= MAX_STANDARD_TX_WEIGHT as u64,
-            "At most 2 inputs and a single output"
+            "At most 2 inputs and 2 outputs"
         );
 
         // Now, get the emergency output value out of it.
         let deposit_value = unvault_input.txout().txout().value;
+        // The anchor output, if any, is paid for by the revocation input too.
+        let anchor_value = anchor_txo
+            .as_ref()
+            .map(|txo| txo.txout().value)
+            .unwrap_or(0);
         let emer_value = deposit_value
             .checked_sub(fees)
-            .expect("We would never create a dust unvault txo");
+            .and_then(|v| v.checked_sub(anchor_value))
+            .ok_or(TransactionCreationError::Dust)?;
+        if emer_value < fee_policy.dust_limit {
+            return Err(TransactionCreationError::Dust);
+        }
         assert!(
             emer_value < max_money(Network::Bitcoin),
             "Checked in UnvaultTransaction constructor already"
         );
         let emer_txo = EmergencyTxOut::new(emer_address, Amount::from_sat(emer_value));
 
-        Ok(UnvaultEmergencyTransaction(
-            UnvaultEmergencyTransaction::create_psbt(
-                unvault_input,
-                feebump_input,
-                emer_txo,
-                lock_time,
-            ),
-        ))
+        let psbt = UnvaultEmergencyTransaction::create_psbt(
+            unvault_input,
+            feebump_input,
+            emer_txo,
+            anchor_txo,
+            lock_time,
+            fee_policy.tx_version(),
+        );
+        if fee_policy.truc && utils::psbt_max_weight(&psbt) > TRUC_MAX_WEIGHT {
+            return Err(TransactionCreationError::TrucViolation);
+        }
+
+        Ok(UnvaultEmergencyTransaction(psbt))
     }
 
-    /// Parse an UnvaultEmergency transaction from a PSBT
-    pub fn from_raw_psbt(raw_psbt: &[u8]) -> Result<Self, TransactionSerialisationError> {
+    // Parse an UnvaultEmergency transaction from a PSBT, only checking its structure. This is what the
+    // deserialization routines use, as they don't know about the fee policy.
+    fn parse_psbt(raw_psbt: &[u8]) -> Result<Self, TransactionSerialisationError> {
         let psbt = Decodable::consensus_decode(raw_psbt)?;
-        let psbt = utils::psbt_common_sanity_checks(psbt)?;
+        let psbt = utils::psbt_common_sanity_checks(psbt, false, Some(TRUC_MAX_WEIGHT))?;
 
-        // Emergency txo
+        // Emergency txo, and an optional anchor txo
+        let has_anchor = utils::check_revocationtx_anchor(&psbt)?;
         let output_count = psbt.global.unsigned_tx.output.len();
-        if output_count != 1 {
+        if output_count != 1 + has_anchor as usize {
             return Err(PsbtValidationError::InvalidOutputCount(output_count).into());
         }
 
@@ -169,6 +241,23 @@ impl UnvaultEmergencyTransaction {
         Ok(UnvaultEmergencyTransaction(psbt))
     }
 
+    /// Parse an UnvaultEmergency transaction from a PSBT, checking it was created under this fee policy.
+    pub fn from_raw_psbt(
+        raw_psbt: &[u8],
+        fee_policy: &FeePolicy,
+    ) -> Result<Self, TransactionSerialisationError> {
+        let tx = UnvaultEmergencyTransaction::parse_psbt(raw_psbt)?;
+        if tx.tx().version != fee_policy.tx_version() {
+            return Err(PsbtValidationError::InvalidTransactionVersion(tx.tx().version).into());
+        }
+        let value = tx.tx().output[0].value;
+        if value < fee_policy.dust_limit {
+            return Err(PsbtValidationError::DustOutput(value).into());
+        }
+        utils::check_revocationtx_fees(tx.psbt(), fee_policy.emer_feerate)?;
+        Ok(tx)
+    }
+
     /// Add a signature for the input spending the Unvault transaction
     pub fn add_emer_sig<C: secp256k1::Verification>(
         &mut self,
@@ -190,3 +279,65 @@ impl UnvaultEmergencyTransaction {
         }
     }
 }
+
+impl CpfpableTransaction for UnvaultEmergencyTransaction {
+    fn max_weight(&self) -> u64 {
+        utils::psbt_max_weight(self.psbt())
+    }
+}
+
+impl RevocationTransaction for UnvaultEmergencyTransaction {
+    fn add_feebump_input(
+        &mut self,
+        feebump_input: FeeBumpTxIn,
+    ) -> Result<u64, TransactionCreationError> {
+        utils::add_feebump_input(&mut self.0, feebump_input)?;
+        Ok(self.max_feerate())
+    }
+
+    fn remove_feebump_input(&mut self) -> Option<FeeBumpTxIn> {
+        utils::remove_feebump_input(&mut self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transactions::{tests_helpers::TestDeployment, ANCHOR_VALUE};
+
+    use miniscript::bitcoin::consensus::encode;
+
+    #[test]
+    fn anchored_unvault_emergency() {
+        let secp = secp256k1::Secp256k1::new();
+        let deployment = TestDeployment::new(3, 2, false, 360_000_000, &secp);
+        let fee_policy = FeePolicy::default();
+        let (unvault_tx, _, _, _) = deployment.chain(&fee_policy, &secp);
+        let anchored_unemer = UnvaultEmergencyTransaction::new_with_anchor(
+            unvault_tx.revault_unvault_txin(&deployment.der_unvault_descriptor(&secp)),
+            deployment.emergency_address.clone(),
+            0,
+            &fee_policy,
+        )
+        .unwrap();
+        assert_eq!(
+            UnvaultEmergencyTransaction::from_raw_psbt(
+                &anchored_unemer.as_psbt_serialized(),
+                &fee_policy
+            ),
+            Ok(anchored_unemer.clone())
+        );
+        assert_eq!(anchored_unemer.tx().output[1].value, ANCHOR_VALUE);
+
+        // Anyone may spend the anchor output, it can't be given more than its value
+        let mut psbt = anchored_unemer.psbt().clone();
+        psbt.global.unsigned_tx.output[0].value -= 10_000;
+        psbt.global.unsigned_tx.output[1].value += 10_000;
+        assert!(matches!(
+            UnvaultEmergencyTransaction::from_raw_psbt(&encode::serialize(&psbt), &fee_policy),
+            Err(TransactionSerialisationError::Validation(
+                PsbtValidationError::InvalidAnchorValue(value)
+            )) if value == ANCHOR_VALUE + 10_000
+        ));
+    }
+}
@@ -1,17 +1,34 @@
-use crate::{error::*, transactions::TX_VERSION};
+use crate::{
+    error::*,
+    scripts::anchor_script_pubkey,
+    transactions::{ANCHOR_VALUE, TRUC_MAX_WEIGHT, TRUC_TX_VERSION, TX_VERSION},
+    txins::{FeeBumpTxIn, RevaultTxIn},
+    txouts::{FeeBumpTxOut, RevaultTxOut},
+};
 
 use miniscript::bitcoin::{
-    blockdata::constants::max_money,
+    blockdata::{constants::max_money, opcodes, script},
+    consensus::encode,
+    secp256k1,
     util::psbt::{Input as PsbtIn, PartiallySignedTransaction as Psbt},
-    Network, OutPoint, SigHashType,
+    Network, OutPoint, Script, SigHashType,
 };
+use miniscript::DescriptorTrait;
+
+use std::{collections::HashSet, convert::TryInto};
 
-use std::collections::HashSet;
+// The maximum size of the witness satisfying a P2WPKH: the elements count, a length-prefixed
+// signature and a length-prefixed compressed public key.
+pub const P2WPKH_MAX_SAT_WEIGHT: u64 = 1 + 1 + 73 + 1 + 33;
 
 /// Boilerplate for defining a Revault transaction as a newtype over a Psbt and implementing
-/// RevaultTransaction for it.
+/// RevaultTransaction for it. The deserialization routines parse it using `$parser`, which
+/// defaults to `from_raw_psbt`.
 macro_rules! impl_revault_transaction {
     ( $transaction_name:ident, $doc_comment:meta ) => {
+        impl_revault_transaction!($transaction_name, from_raw_psbt, $doc_comment);
+    };
+    ( $transaction_name:ident, $parser:ident, $doc_comment:meta ) => {
         use crate::transactions::inner_mut;
         use std::{fmt, str};
 
@@ -35,7 +52,7 @@ macro_rules! impl_revault_transaction {
             fn from_psbt_serialized(
                 raw_psbt: &[u8],
             ) -> Result<Self, TransactionSerialisationError> {
-                $transaction_name::from_raw_psbt(raw_psbt)
+                $transaction_name::$parser(raw_psbt)
             }
         }
 
@@ -85,11 +102,21 @@ macro_rules! impl_revault_transaction {
     };
 }
 
-/// Sanity check a PSBT representing a RevaultTransaction, the part common to all transactions
-pub fn psbt_common_sanity_checks(psbt: Psbt) -> Result<Psbt, PsbtValidationError> {
+/// Sanity check a PSBT representing a RevaultTransaction, the part common to all transactions.
+/// The keyless anchor outputs of the revocation transactions may only be spent by a CPFP
+/// transaction, which must set `spends_anchors`. Only the transactions which may be created as
+/// TRUC transactions may have a version of 3, they set `truc_max_weight` to the size they are
+/// restricted to as such.
+pub fn psbt_common_sanity_checks(
+    psbt: Psbt,
+    spends_anchors: bool,
+    truc_max_weight: Option<u64>,
+) -> Result<Psbt, PsbtValidationError> {
     let inner_tx = &psbt.global.unsigned_tx;
 
-    if inner_tx.version != TX_VERSION {
+    if inner_tx.version != TX_VERSION
+        && (inner_tx.version != TRUC_TX_VERSION || truc_max_weight.is_none())
+    {
         return Err(PsbtValidationError::InvalidTransactionVersion(
             inner_tx.version,
         ));
@@ -126,15 +153,21 @@ pub fn psbt_common_sanity_checks(psbt: Psbt) -> Result<Psbt, PsbtValidationError
     for input in psbt.inputs.iter() {
         // We restrict to native segwit, also for the external fee-bumping wallet.
         if input.witness_utxo.is_none() {
-            return Err(PsbtValidationError::MissingWitnessUtxo(input.clone()));
+            return Err(PsbtValidationError::MissingWitnessUtxo(Box::new(
+                input.clone(),
+            )));
         }
 
         if input.non_witness_utxo.is_some() {
-            return Err(PsbtValidationError::InvalidInputField(input.clone()));
+            return Err(PsbtValidationError::InvalidInputField(Box::new(
+                input.clone(),
+            )));
         }
 
         if input.redeem_script.is_some() {
-            return Err(PsbtValidationError::InvalidInputField(input.clone()));
+            return Err(PsbtValidationError::InvalidInputField(Box::new(
+                input.clone(),
+            )));
         }
 
         // Make sure it does not mix finalized and non-finalized inputs or final scripts
@@ -154,8 +187,9 @@ pub fn psbt_common_sanity_checks(psbt: Psbt) -> Result<Psbt, PsbtValidationError
         // If the witness script is provided, it must be a sane Miniscript
         if let Some(ref script) = input.witness_script {
             let _: miniscript::Miniscript<_, miniscript::Segwitv0> =
-                miniscript::Miniscript::parse(script)
-                    .map_err(|_| PsbtValidationError::InvalidInWitnessScript(input.clone()))?;
+                miniscript::Miniscript::parse(script).map_err(|_| {
+                    PsbtValidationError::InvalidInWitnessScript(Box::new(input.clone()))
+                })?;
         }
 
         // We'll then check it doesn't create more than it spends
@@ -172,7 +206,7 @@ pub fn psbt_common_sanity_checks(psbt: Psbt) -> Result<Psbt, PsbtValidationError
             .ok_or(PsbtValidationError::InsaneAmounts)?;
 
         // The previous output must either be P2WSH, in which case the witness script must
-        // correspond to the ScriptPubKey, or P2WPKH.
+        // correspond to the ScriptPubKey, P2WPKH, or a keyless anchor for a CPFP.
         let spk = &input.witness_utxo.as_ref().unwrap().script_pubkey;
         if spk.is_v0_p2wsh() {
             // It's blanked when finalized
@@ -180,15 +214,30 @@ pub fn psbt_common_sanity_checks(psbt: Psbt) -> Result<Psbt, PsbtValidationError
                 continue;
             }
 
-            let ws = input
-                .witness_script
-                .as_ref()
-                .ok_or_else(|| PsbtValidationError::MissingInWitnessScript(input.clone()))?;
+            let ws = input.witness_script.as_ref().ok_or_else(|| {
+                PsbtValidationError::MissingInWitnessScript(Box::new(input.clone()))
+            })?;
             if &ws.to_v0_p2wsh() != spk {
-                return Err(PsbtValidationError::InvalidInWitnessScript(input.clone()));
+                return Err(PsbtValidationError::InvalidInWitnessScript(Box::new(
+                    input.clone(),
+                )));
+            }
+        } else if spk == &anchor_script_pubkey() {
+            if !spends_anchors {
+                return Err(PsbtValidationError::InvalidPrevoutType(Box::new(
+                    input.clone(),
+                )));
+            }
+            // A keyless anchor is spent with an empty witness
+            if input.witness_script.is_some() {
+                return Err(PsbtValidationError::InvalidInputField(Box::new(
+                    input.clone(),
+                )));
             }
         } else if !spk.is_v0_p2wpkh() {
-            return Err(PsbtValidationError::InvalidInputField(input.clone()));
+            return Err(PsbtValidationError::InvalidInputField(Box::new(
+                input.clone(),
+            )));
         }
     }
 
@@ -211,9 +260,92 @@ pub fn psbt_common_sanity_checks(psbt: Psbt) -> Result<Psbt, PsbtValidationError
         return Err(PsbtValidationError::InsaneAmounts);
     }
 
+    // TRUC transactions are restricted to a smaller size
+    if let (TRUC_TX_VERSION, Some(max_weight)) = (psbt.global.unsigned_tx.version, truc_max_weight)
+    {
+        let weight = psbt_max_weight_checked(&psbt)?;
+        if weight > max_weight {
+            return Err(PsbtValidationError::TrucTooLarge(weight));
+        }
+    }
+
     Ok(psbt)
 }
 
+// Miniscript can neither satisfy nor interpret the spending of a keyless anchor. As the signature
+// hashes of the other inputs don't commit to the Script of the coins they don't spend, present
+// Miniscript with the spending of the P2WSH of an always-true Script in place of it.
+fn mask_anchor_inputs(psbt: &Psbt) -> Psbt {
+    let anchor_spk = anchor_script_pubkey();
+    let mask_script = script::Builder::new()
+        .push_opcode(opcodes::OP_TRUE)
+        .into_script();
+
+    let mut masked_psbt = psbt.clone();
+    for input in masked_psbt.inputs.iter_mut() {
+        let utxo = input
+            .witness_utxo
+            .as_mut()
+            .expect("We always set witness_utxo");
+        if utxo.script_pubkey != anchor_spk {
+            continue;
+        }
+
+        utxo.script_pubkey = mask_script.to_v0_p2wsh();
+        if input.final_script_witness.is_some() {
+            input.final_script_witness = Some(vec![mask_script.to_bytes()]);
+        } else {
+            input.witness_script = Some(mask_script.clone());
+        }
+    }
+
+    masked_psbt
+}
+
+/// Finalize all the inputs of this PSBT using Miniscript, including the keyless anchor ones which
+/// are spent with an empty witness.
+pub fn finalize_psbt(
+    psbt: &mut Psbt,
+    secp: &secp256k1::Secp256k1<impl secp256k1::Verification>,
+) -> Result<(), miniscript::psbt::Error> {
+    let mut masked_psbt = mask_anchor_inputs(psbt);
+    miniscript::psbt::finalize(&mut masked_psbt, secp)?;
+
+    let anchor_spk = anchor_script_pubkey();
+    for (masked_input, input) in masked_psbt.inputs.into_iter().zip(psbt.inputs.iter_mut()) {
+        let is_anchor = input
+            .witness_utxo
+            .as_ref()
+            .map(|utxo| utxo.script_pubkey == anchor_spk)
+            == Some(true);
+        if is_anchor {
+            input.final_script_witness = Some(vec![]);
+        } else {
+            *input = masked_input;
+        }
+    }
+
+    Ok(())
+}
+
+/// Check the finalized inputs of this PSBT using Miniscript's interpreter, including the keyless
+/// anchor ones.
+pub fn interpreter_check(
+    psbt: &Psbt,
+    secp: &secp256k1::Secp256k1<impl secp256k1::Verification>,
+) -> Result<(), miniscript::psbt::Error> {
+    miniscript::psbt::interpreter_check(&mask_anchor_inputs(psbt), secp)
+}
+
+/// Get the version of the transactions to create, depending on whether they must be TRUC.
+pub fn tx_version(truc: bool) -> i32 {
+    if truc {
+        TRUC_TX_VERSION
+    } else {
+        TX_VERSION
+    }
+}
+
 /// If one of these inputs is a P2WSH, return it.
 pub fn find_revocationtx_input(inputs: &[PsbtIn]) -> Option<&PsbtIn> {
     inputs.iter().find(|i| {
@@ -234,6 +366,74 @@ pub fn find_feebumping_input(inputs: &[PsbtIn]) -> Option<&PsbtIn> {
     })
 }
 
+/// Add a fee-bumping input to a revocation transaction, leaving the other inputs untouched.
+/// A revocation transaction with an anchor output is fee-bumped through CPFP instead.
+pub fn add_feebump_input(
+    psbt: &mut Psbt,
+    feebump_input: FeeBumpTxIn,
+) -> Result<(), TransactionCreationError> {
+    if find_feebumping_input(&psbt.inputs).is_some() {
+        return Err(TransactionCreationError::AlreadyFeeBumped);
+    }
+    let anchor_spk = anchor_script_pubkey();
+    if psbt
+        .global
+        .unsigned_tx
+        .output
+        .iter()
+        .any(|txo| txo.script_pubkey == anchor_spk)
+    {
+        return Err(TransactionCreationError::AnchoredRevocation);
+    }
+    if psbt
+        .global
+        .unsigned_tx
+        .input
+        .iter()
+        .any(|txin| txin.previous_output == feebump_input.outpoint())
+    {
+        return Err(TransactionCreationError::DuplicatedInput);
+    }
+    if feebump_input.txout().txout().value > max_money(Network::Bitcoin) {
+        return Err(TransactionCreationError::InsaneAmounts);
+    }
+
+    psbt.global
+        .unsigned_tx
+        .input
+        .push(feebump_input.unsigned_txin());
+    psbt.inputs.push(PsbtIn {
+        sighash_type: Some(SigHashType::All),
+        witness_utxo: Some(feebump_input.into_txout().into_txout()),
+        ..PsbtIn::default()
+    });
+    if psbt.global.unsigned_tx.version == TRUC_TX_VERSION && psbt_max_weight(psbt) > TRUC_MAX_WEIGHT
+    {
+        remove_feebump_input(psbt);
+        return Err(TransactionCreationError::TrucViolation);
+    }
+
+    Ok(())
+}
+
+/// Remove the fee-bumping input of a revocation transaction, if any, leaving the other inputs
+/// untouched.
+pub fn remove_feebump_input(psbt: &mut Psbt) -> Option<FeeBumpTxIn> {
+    let index = psbt.inputs.iter().position(|psbtin| {
+        psbtin
+            .witness_utxo
+            .as_ref()
+            .map(|txo| txo.script_pubkey.is_v0_p2wpkh())
+            == Some(true)
+    })?;
+
+    let txin = psbt.global.unsigned_tx.input.remove(index);
+    let psbtin = psbt.inputs.remove(index);
+    let prev_txout = FeeBumpTxOut::new(psbtin.witness_utxo.expect("Checked above"))
+        .expect("Checked above it's a P2WPKH");
+    Some(FeeBumpTxIn::new(txin.previous_output, prev_txout))
+}
+
 /// Sanity check an "internal" PSBT input of a revocation transaction
 pub fn check_revocationtx_input(input: &PsbtIn) -> Result<(), PsbtValidationError> {
     assert!(input
@@ -250,12 +450,16 @@ pub fn check_revocationtx_input(input: &PsbtIn) -> Result<(), PsbtValidationErro
 
     // The revocation input must indicate that it wants to be signed with ACP
     if input.sighash_type != Some(SigHashType::AllPlusAnyoneCanPay) {
-        return Err(PsbtValidationError::InvalidSighashType(input.clone()));
+        return Err(PsbtValidationError::InvalidSighashType(Box::new(
+            input.clone(),
+        )));
     }
 
     // It must have derivation paths set since it must have a witscript
     if input.bip32_derivation.is_empty() {
-        return Err(PsbtValidationError::InvalidInputField(input.clone()));
+        return Err(PsbtValidationError::InvalidInputField(Box::new(
+            input.clone(),
+        )));
     }
 
     Ok(())
@@ -270,7 +474,9 @@ pub fn check_feebump_input(input: &PsbtIn) -> Result<(), PsbtValidationError> {
 
     // The feebump input must indicate that it wants to be signed with ALL
     if input.sighash_type != Some(SigHashType::All) {
-        return Err(PsbtValidationError::InvalidSighashType(input.clone()));
+        return Err(PsbtValidationError::InvalidSighashType(Box::new(
+            input.clone(),
+        )));
     }
 
     // The feebump input must be P2WPKH
@@ -280,17 +486,47 @@ pub fn check_feebump_input(input: &PsbtIn) -> Result<(), PsbtValidationError> {
         .map(|u| u.script_pubkey.is_v0_p2wpkh())
         != Some(true)
     {
-        return Err(PsbtValidationError::InvalidPrevoutType(input.clone()));
+        return Err(PsbtValidationError::InvalidPrevoutType(Box::new(
+            input.clone(),
+        )));
     }
 
     // And therefore must not have a witness script
     if input.witness_script.is_some() {
-        return Err(PsbtValidationError::InvalidInputField(input.clone()));
+        return Err(PsbtValidationError::InvalidInputField(Box::new(
+            input.clone(),
+        )));
     }
 
     Ok(())
 }
 
+/// Check the optional keyless anchor output of a revocation transaction, and return whether it
+/// has one. It must come after the revocation output and have the value the constructors give it,
+/// as anyone may spend it. An anchored revocation transaction is fee-bumped through CPFP only: it
+/// can't have a fee-bumping input.
+pub fn check_revocationtx_anchor(psbt: &Psbt) -> Result<bool, PsbtValidationError> {
+    let tx = &psbt.global.unsigned_tx;
+    let anchor_spk = anchor_script_pubkey();
+
+    match tx.output.iter().position(|o| o.script_pubkey == anchor_spk) {
+        None => Ok(false),
+        Some(index) => {
+            if index != 1 || tx.output.len() != 2 {
+                return Err(PsbtValidationError::InvalidOutputCount(tx.output.len()));
+            }
+            if tx.input.len() != 1 {
+                return Err(PsbtValidationError::InvalidInputCount(tx.input.len()));
+            }
+            if tx.output[1].value != ANCHOR_VALUE {
+                return Err(PsbtValidationError::InvalidAnchorValue(tx.output[1].value));
+            }
+
+            Ok(true)
+        }
+    }
+}
+
 /// Return the position of the first P2WSH input of a Psbt
 pub fn p2wsh_input_index(psbt: &Psbt) -> Option<usize> {
     psbt.inputs.iter().position(|i| {
@@ -301,6 +537,51 @@ pub fn p2wsh_input_index(psbt: &Psbt) -> Option<usize> {
     })
 }
 
+/// Get the weight of the transaction once all its inputs are satisfied. For inputs that were not
+/// finalized yet, the maximum weight of their satisfaction is used.
+pub fn psbt_max_weight(psbt: &Psbt) -> u64 {
+    psbt_max_weight_checked(psbt).expect("Checked at creation or parsing")
+}
+
+// Same as psbt_max_weight, but errors if the weight of the satisfaction of an input can't be
+// computed.
+fn psbt_max_weight_checked(psbt: &Psbt) -> Result<u64, PsbtValidationError> {
+    let mut weight: u64 = psbt
+        .global
+        .unsigned_tx
+        .get_weight()
+        .try_into()
+        .expect("usize in u64");
+
+    for input in psbt.inputs.iter() {
+        let sat_weight = if let Some(ref witness) = input.final_script_witness {
+            encode::serialize(witness)
+                .len()
+                .try_into()
+                .expect("usize in u64")
+        } else {
+            let spk = &input
+                .witness_utxo
+                .as_ref()
+                .ok_or_else(|| PsbtValidationError::MissingWitnessUtxo(Box::new(input.clone())))?
+                .script_pubkey;
+            if spk.is_v0_p2wsh() {
+                input_max_sat_weight(input)?
+            } else if spk.is_v0_p2wpkh() {
+                P2WPKH_MAX_SAT_WEIGHT
+            } else {
+                // A keyless anchor, spent with an empty witness
+                1
+            }
+        };
+        weight = weight
+            .checked_add(sat_weight)
+            .ok_or(PsbtValidationError::InsaneAmounts)?;
+    }
+
+    Ok(weight)
+}
+
 /// Returns the absolute fees paid by a PSBT.
 ///
 /// Returns None if:
@@ -319,3 +600,89 @@ pub fn psbt_fees(psbt: &Psbt) -> Option<u64> {
 
     value_in.checked_sub(value_out)
 }
+
+/// Get the witness Script of a P2WSH PSBT input, whether it was finalized or not.
+pub fn input_witness_script(input: &PsbtIn) -> Option<Script> {
+    if let Some(ref ws) = input.witness_script {
+        return Some(ws.clone());
+    }
+
+    // Once finalized, the witness Script is the last element of the witness stack
+    input
+        .final_script_witness
+        .as_ref()
+        .and_then(|witness| witness.last())
+        .map(|ws| Script::from(ws.clone()))
+}
+
+/// Get the maximum weight of the witness satisfying this P2WSH PSBT input, as used by the
+/// transaction constructors to compute the fees.
+pub fn input_max_sat_weight(input: &PsbtIn) -> Result<u64, PsbtValidationError> {
+    let ws = input_witness_script(input)
+        .ok_or_else(|| PsbtValidationError::MissingInWitnessScript(Box::new(input.clone())))?;
+    let ms: miniscript::Miniscript<_, miniscript::Segwitv0> = miniscript::Miniscript::parse(&ws)
+        .map_err(|_| PsbtValidationError::InvalidInWitnessScript(Box::new(input.clone())))?;
+    let sat_weight = miniscript::descriptor::Wsh::new(ms)
+        .and_then(|wsh| wsh.max_satisfaction_weight())
+        .map_err(|_| PsbtValidationError::InvalidInWitnessScript(Box::new(input.clone())))?;
+
+    Ok(sat_weight.try_into().expect("usize in u64"))
+}
+
+// Get the fees paid by a revocation transaction and its weight, both without accounting for
+// the fee-bumping input as it pays for itself.
+fn revocationtx_base_fees(psbt: &Psbt) -> Result<(u64, u64), PsbtValidationError> {
+    let index = p2wsh_input_index(psbt).ok_or(PsbtValidationError::MissingRevocationInput)?;
+    let input = &psbt.inputs[index];
+
+    // The weight of the transaction without the feebump input
+    let mut tx = psbt.global.unsigned_tx.clone();
+    tx.input = vec![tx.input[index].clone()];
+    let witstrip_weight: u64 = tx.get_weight().try_into().expect("usize in u64");
+    let weight = witstrip_weight
+        .checked_add(input_max_sat_weight(input)?)
+        .ok_or(PsbtValidationError::InsaneAmounts)?;
+
+    let value_in = input
+        .witness_utxo
+        .as_ref()
+        .expect("Checked in the common checks")
+        .value;
+    let value_out = tx
+        .output
+        .iter()
+        .try_fold(0u64, |acc, o| acc.checked_add(o.value))
+        .ok_or(PsbtValidationError::InsaneAmounts)?;
+    let fees = value_in
+        .checked_sub(value_out)
+        .ok_or(PsbtValidationError::InsaneAmounts)?;
+
+    Ok((fees, weight))
+}
+
+/// Check the fees of a revocation transaction were computed at this feerate (in sat/WU).
+///
+/// As in the constructors, the fee-bumping input is not accounted for as it pays for itself.
+pub fn check_revocationtx_fees(psbt: &Psbt, feerate: u64) -> Result<(), PsbtValidationError> {
+    let (fees, weight) = revocationtx_base_fees(psbt)?;
+    let expected_fees = feerate
+        .checked_mul(weight)
+        .ok_or(PsbtValidationError::InsaneAmounts)?;
+
+    if fees != expected_fees {
+        return Err(PsbtValidationError::InvalidFees(fees, expected_fees));
+    }
+
+    Ok(())
+}
+
+/// Get the feerate (in sat/WU) a revocation transaction was created with, not accounting for
+/// the fee-bumping input.
+pub fn revocationtx_feerate(psbt: &Psbt) -> Result<u64, PsbtValidationError> {
+    let (fees, weight) = revocationtx_base_fees(psbt)?;
+    let feerate = fees / weight;
+    // Make sure it was created by a constructor, and not only approximately at this feerate
+    check_revocationtx_fees(psbt, feerate)?;
+
+    Ok(feerate)
+}
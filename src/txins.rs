@@ -3,7 +3,7 @@
 //! Wrappers around bitcoin's OutPoint and previous TxOut to statically check Revault
 //! transaction creation and ease PSBT management.
 
-use crate::txouts::{CpfpTxOut, DepositTxOut, FeeBumpTxOut, UnvaultTxOut};
+use crate::txouts::{AnchorTxOut, CpfpTxOut, DepositTxOut, FeeBumpTxOut, UnvaultTxOut};
 
 use miniscript::bitcoin::{OutPoint, TxIn};
 
@@ -25,13 +25,14 @@ pub trait RevaultTxIn<T>: fmt::Debug + Clone + PartialEq {
 }
 
 macro_rules! implem_revault_txin {
-    ( $struct_name:ident, $txout_struct_name:ident, $doc_comment:meta ) => {
+    ( $struct_name:ident, $txout_struct_name:ident, $doc_comment:meta $(, $field:ident: $field_type:ty)* ) => {
         #[$doc_comment]
         #[derive(Debug, Clone, PartialEq)]
         pub struct $struct_name {
             outpoint: OutPoint,
             prev_txout: $txout_struct_name,
             sequence: u32,
+            $($field: $field_type,)*
         }
 
         impl RevaultTxIn<$txout_struct_name> for $struct_name {
@@ -118,15 +119,47 @@ implem_revault_txin!(
     CpfpTxIn,
     CpfpTxOut,
     doc = "The [Unvault CPFP txo](crate::txouts::CpfpTxOut) spent to accelerate the confirmation of the \
-            [Unvault](crate::transactions::UnvaultTransaction)."
+            [Unvault](crate::transactions::UnvaultTransaction).",
+    confirmed: bool
 );
 impl CpfpTxIn {
-    /// Instanciate a TxIn referencing a CPFP txout which signals for RBF.
+    /// Instanciate a TxIn referencing an unconfirmed CPFP txout which signals for RBF.
     pub fn new(outpoint: OutPoint, prev_txout: CpfpTxOut) -> CpfpTxIn {
         CpfpTxIn {
             outpoint,
             prev_txout,
             sequence: RBF_SEQUENCE,
+            confirmed: false,
+        }
+    }
+
+    /// Instanciate a TxIn referencing a confirmed CPFP txout which signals for RBF.
+    pub fn new_confirmed(outpoint: OutPoint, prev_txout: CpfpTxOut) -> CpfpTxIn {
+        CpfpTxIn {
+            confirmed: true,
+            ..CpfpTxIn::new(outpoint, prev_txout)
+        }
+    }
+
+    /// Whether the transaction creating the txout this txin refers to is confirmed.
+    pub fn is_confirmed(&self) -> bool {
+        self.confirmed
+    }
+}
+
+implem_revault_txin!(
+    AnchorTxIn,
+    AnchorTxOut,
+    doc = "The [anchor txo](crate::txouts::AnchorTxOut) spent to accelerate the confirmation of a \
+            revocation transaction."
+);
+impl AnchorTxIn {
+    /// Instanciate a TxIn referencing an anchor txout which signals for RBF.
+    pub fn new(outpoint: OutPoint, prev_txout: AnchorTxOut) -> AnchorTxIn {
+        AnchorTxIn {
+            outpoint,
+            prev_txout,
+            sequence: RBF_SEQUENCE,
         }
     }
 }
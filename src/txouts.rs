@@ -6,8 +6,10 @@
 use crate::{
     error::TxoutCreationError,
     scripts::{
-        DerivedCpfpDescriptor, DerivedDepositDescriptor, DerivedUnvaultDescriptor, EmergencyAddress,
+        anchor_script_pubkey, DerivedCpfpDescriptor, DerivedDepositDescriptor,
+        DerivedUnvaultDescriptor, EmergencyAddress,
     },
+    transactions::ANCHOR_VALUE,
 };
 
 use miniscript::{
@@ -225,6 +227,42 @@ impl RevaultTxOut for FeeBumpTxOut {
     }
 }
 
+/// The keyless "Pay To Anchor" output attached to a revocation transaction so that it can be
+/// fee-bumped through CPFP.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AnchorTxOut(TxOut);
+impl AnchorTxOut {
+    /// Create a new AnchorTxOut of the given value
+    pub fn new(value: Amount) -> AnchorTxOut {
+        AnchorTxOut(TxOut {
+            script_pubkey: anchor_script_pubkey(),
+            value: value.as_sat(),
+        })
+    }
+
+    /// Get the size, in weight units, of the (empty) witness spending this output.
+    pub fn max_sat_weight(&self) -> usize {
+        // The witness items count
+        1
+    }
+}
+
+impl Default for AnchorTxOut {
+    fn default() -> Self {
+        AnchorTxOut::new(Amount::from_sat(ANCHOR_VALUE))
+    }
+}
+
+impl RevaultTxOut for AnchorTxOut {
+    fn txout(&self) -> &TxOut {
+        &self.0
+    }
+
+    fn into_txout(self) -> TxOut {
+        self.0
+    }
+}
+
 /// A [Spend](crate::transactions::SpendTransaction) output can be either a change one (DepositTxOut)
 /// or a payee-controlled one (ExternalTxOut).
 #[derive(Debug, Clone, PartialEq)]
@@ -0,0 +1,754 @@
+//! # Revault vaults
+//!
+//! The lifecycle of a vault, from the deposit to the transaction definitively spending it.
+//! The state of a vault is deduced from the signatures gathered for its pre-signed transactions
+//! and from the transactions of its chain that were seen on the network.
+
+use crate::{
+    error::VaultError,
+    transactions::{
+        CancelTransaction, EmergencyTransaction, RevaultTransaction, SpendTransaction,
+        UnvaultEmergencyTransaction, UnvaultTransaction, TRUC_TX_VERSION,
+    },
+};
+
+use miniscript::bitcoin::{secp256k1, util::bip32, OutPoint, Transaction, Txid};
+
+use std::{collections::HashMap, fmt};
+
+/// The state of a vault
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum VaultState {
+    /// The deposit transaction isn't confirmed yet
+    Unconfirmed,
+    /// The deposit transaction is confirmed
+    Funded,
+    /// The revocation transactions are being signed
+    Securing,
+    /// The revocation transactions are fully signed
+    Secured,
+    /// The Unvault transaction is being signed
+    Activating,
+    /// The Unvault transaction is fully signed
+    Active,
+    /// The Unvault transaction was broadcast
+    Unvaulting,
+    /// The Unvault transaction is confirmed
+    Unvaulted,
+    /// The Cancel transaction was broadcast
+    Canceling,
+    /// The Cancel transaction is confirmed
+    Canceled,
+    /// The Emergency transaction was broadcast
+    EmergencyVaulting,
+    /// The Emergency transaction is confirmed
+    EmergencyVaulted,
+    /// The UnvaultEmergency transaction was broadcast
+    UnvaultEmergencyVaulting,
+    /// The UnvaultEmergency transaction is confirmed
+    UnvaultEmergencyVaulted,
+    /// The Spend transaction was broadcast
+    Spending,
+    /// The Spend transaction is confirmed
+    Spent,
+}
+
+impl VaultState {
+    /// The states a vault in this state may transition to, not accounting for reorgs.
+    pub fn next_states(&self) -> &'static [VaultState] {
+        use VaultState::*;
+
+        match self {
+            Unconfirmed => &[Funded],
+            Funded => &[Securing, Secured],
+            Securing => &[Secured],
+            Secured => &[Activating, Active, EmergencyVaulting],
+            Activating => &[Active, EmergencyVaulting],
+            Active => &[Unvaulting, EmergencyVaulting],
+            Unvaulting => &[Unvaulted, Canceling, UnvaultEmergencyVaulting],
+            Unvaulted => &[Spending, Canceling, UnvaultEmergencyVaulting],
+            Spending => &[Spent, Canceling, UnvaultEmergencyVaulting],
+            Canceling => &[Canceled, UnvaultEmergencyVaulting],
+            EmergencyVaulting => &[EmergencyVaulted],
+            UnvaultEmergencyVaulting => &[UnvaultEmergencyVaulted],
+            Canceled | EmergencyVaulted | UnvaultEmergencyVaulted | Spent => &[],
+        }
+    }
+
+    /// Whether the coins definitively left the vault.
+    pub fn is_final(&self) -> bool {
+        self.next_states().is_empty()
+    }
+}
+
+impl fmt::Display for VaultState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let state = match self {
+            Self::Unconfirmed => "unconfirmed",
+            Self::Funded => "funded",
+            Self::Securing => "securing",
+            Self::Secured => "secured",
+            Self::Activating => "activating",
+            Self::Active => "active",
+            Self::Unvaulting => "unvaulting",
+            Self::Unvaulted => "unvaulted",
+            Self::Canceling => "canceling",
+            Self::Canceled => "canceled",
+            Self::EmergencyVaulting => "emergencyvaulting",
+            Self::EmergencyVaulted => "emergencyvaulted",
+            Self::UnvaultEmergencyVaulting => "unvaultemergencyvaulting",
+            Self::UnvaultEmergencyVaulted => "unvaultemergencyvaulted",
+            Self::Spending => "spending",
+            Self::Spent => "spent",
+        };
+        write!(f, "{}", state)
+    }
+}
+
+/// Something that happened on the network to a transaction of the vault chain.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VaultEvent {
+    /// This transaction was seen in the mempool (`height` is `None`) or confirmed in the block
+    /// at `height`.
+    Seen {
+        /// The transaction id
+        txid: Txid,
+        /// The height of the block the transaction was confirmed in, if any
+        height: Option<u32>,
+    },
+    /// The chain was reorganized, the blocks above `height` aren't part of it anymore. The
+    /// transactions they contained are assumed back to the mempool.
+    Reorg {
+        /// The height of the last block common to both chains
+        height: u32,
+    },
+}
+
+/// A vault: a deposit and its chain of pre-signed transactions.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Vault {
+    deposit_outpoint: OutPoint,
+    derivation_index: bip32::ChildNumber,
+    unvault_tx: UnvaultTransaction,
+    cancel_tx: CancelTransaction,
+    emergency_tx: EmergencyTransaction,
+    unvault_emergency_tx: UnvaultEmergencyTransaction,
+    spend_tx: Option<SpendTransaction>,
+    // The known replacements of the revocation transactions, and the txid they replace
+    replacements: HashMap<Txid, Txid>,
+    // The transactions of the chain we saw, and the height at which they were confirmed
+    seen: HashMap<Txid, Option<u32>>,
+}
+
+impl Vault {
+    /// Create a vault for the deposit at `deposit_outpoint`, from its transaction chain as
+    /// created by [transaction_chain](crate::transactions::transaction_chain).
+    pub fn new(
+        deposit_outpoint: OutPoint,
+        derivation_index: bip32::ChildNumber,
+        unvault_tx: UnvaultTransaction,
+        cancel_tx: CancelTransaction,
+        emergency_tx: EmergencyTransaction,
+        unvault_emergency_tx: UnvaultEmergencyTransaction,
+    ) -> Vault {
+        Vault {
+            deposit_outpoint,
+            derivation_index,
+            unvault_tx,
+            cancel_tx,
+            emergency_tx,
+            unvault_emergency_tx,
+            spend_tx: None,
+            replacements: HashMap::new(),
+            seen: HashMap::new(),
+        }
+    }
+
+    /// Get the outpoint of the deposit
+    pub fn deposit_outpoint(&self) -> OutPoint {
+        self.deposit_outpoint
+    }
+
+    /// Get the derivation index of the vault descriptors
+    pub fn derivation_index(&self) -> bip32::ChildNumber {
+        self.derivation_index
+    }
+
+    /// Get a reference to the Unvault transaction
+    pub fn unvault_tx(&self) -> &UnvaultTransaction {
+        &self.unvault_tx
+    }
+
+    /// Get a mutable reference to the Unvault transaction, to add signatures
+    pub fn unvault_tx_mut(&mut self) -> &mut UnvaultTransaction {
+        &mut self.unvault_tx
+    }
+
+    /// Get a reference to the Cancel transaction
+    pub fn cancel_tx(&self) -> &CancelTransaction {
+        &self.cancel_tx
+    }
+
+    /// Get a mutable reference to the Cancel transaction, to add signatures
+    pub fn cancel_tx_mut(&mut self) -> &mut CancelTransaction {
+        &mut self.cancel_tx
+    }
+
+    /// Get a reference to the Emergency transaction
+    pub fn emergency_tx(&self) -> &EmergencyTransaction {
+        &self.emergency_tx
+    }
+
+    /// Get a mutable reference to the Emergency transaction, to add signatures
+    pub fn emergency_tx_mut(&mut self) -> &mut EmergencyTransaction {
+        &mut self.emergency_tx
+    }
+
+    /// Get a reference to the UnvaultEmergency transaction
+    pub fn unvault_emergency_tx(&self) -> &UnvaultEmergencyTransaction {
+        &self.unvault_emergency_tx
+    }
+
+    /// Get a mutable reference to the UnvaultEmergency transaction, to add signatures
+    pub fn unvault_emergency_tx_mut(&mut self) -> &mut UnvaultEmergencyTransaction {
+        &mut self.unvault_emergency_tx
+    }
+
+    /// Get a reference to the Spend transaction, if one was set
+    pub fn spend_tx(&self) -> Option<&SpendTransaction> {
+        self.spend_tx.as_ref()
+    }
+
+    /// Set the Spend transaction of this vault. Errors if it does not spend the Unvault output.
+    pub fn set_spend_tx(&mut self, spend_tx: SpendTransaction) -> Result<(), VaultError> {
+        let unvault_txid = self.unvault_tx.txid();
+        if !spend_tx
+            .tx()
+            .input
+            .iter()
+            .any(|txin| txin.previous_output.txid == unvault_txid)
+        {
+            return Err(VaultError::UnrelatedSpend(spend_tx.txid()));
+        }
+
+        self.spend_tx = Some(spend_tx);
+        Ok(())
+    }
+
+    /// Register a replacement of the Cancel transaction, such as a fee-bumped version of it or
+    /// another entry of its fee ladder, for the vault to recognize it once it is seen on the
+    /// network. Errors if it does not spend the Unvault output.
+    pub fn add_cancel_replacement(
+        &mut self,
+        cancel_tx: &CancelTransaction,
+    ) -> Result<(), VaultError> {
+        self.add_replacement(self.cancel_tx.txid(), self.unvault_outpoint(), cancel_tx)
+    }
+
+    /// Register a replacement of the Emergency transaction, such as a fee-bumped version of it
+    /// or another entry of its fee ladder, for the vault to recognize it once it is seen on the
+    /// network. Errors if it does not spend the deposit output.
+    pub fn add_emergency_replacement(
+        &mut self,
+        emergency_tx: &EmergencyTransaction,
+    ) -> Result<(), VaultError> {
+        self.add_replacement(
+            self.emergency_tx.txid(),
+            self.deposit_outpoint,
+            emergency_tx,
+        )
+    }
+
+    /// Register a replacement of the UnvaultEmergency transaction, such as a fee-bumped version
+    /// of it or another entry of its fee ladder, for the vault to recognize it once it is seen
+    /// on the network. Errors if it does not spend the Unvault output.
+    pub fn add_unvault_emergency_replacement(
+        &mut self,
+        unvault_emergency_tx: &UnvaultEmergencyTransaction,
+    ) -> Result<(), VaultError> {
+        self.add_replacement(
+            self.unvault_emergency_tx.txid(),
+            self.unvault_outpoint(),
+            unvault_emergency_tx,
+        )
+    }
+
+    /// Update the vault with an event from the network. Events about transactions unrelated to
+    /// this vault are ignored.
+    pub fn apply_event(&mut self, event: VaultEvent) {
+        match event {
+            VaultEvent::Seen { txid, height } => {
+                if self.chain_txids().contains(&txid) || self.replacements.contains_key(&txid) {
+                    self.seen.insert(txid, height);
+                }
+            }
+            VaultEvent::Reorg { height } => {
+                for confirmation in self.seen.values_mut() {
+                    if matches!(confirmation, Some(h) if *h > height) {
+                        *confirmation = None;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Get the current state of the vault.
+    pub fn state<C: secp256k1::Verification>(&self, secp: &secp256k1::Secp256k1<C>) -> VaultState {
+        // The transactions taking the coins out of the vault (or the Unvault), by order of
+        // precedence. A confirmed one always wins over an unconfirmed (conflicting) one.
+        let mut outcomes = vec![
+            (
+                self.emergency_tx.txid(),
+                VaultState::EmergencyVaulting,
+                VaultState::EmergencyVaulted,
+            ),
+            (
+                self.unvault_emergency_tx.txid(),
+                VaultState::UnvaultEmergencyVaulting,
+                VaultState::UnvaultEmergencyVaulted,
+            ),
+            (
+                self.cancel_tx.txid(),
+                VaultState::Canceling,
+                VaultState::Canceled,
+            ),
+        ];
+        if let Some(ref spend_tx) = self.spend_tx {
+            outcomes.push((spend_tx.txid(), VaultState::Spending, VaultState::Spent));
+        }
+        for (txid, _, confirmed) in outcomes.iter() {
+            if let Some(Some(_)) = self.seen_with_replacements(txid) {
+                return *confirmed;
+            }
+        }
+        for (txid, pending, _) in outcomes.iter() {
+            if self.seen_with_replacements(txid).is_some() {
+                return *pending;
+            }
+        }
+
+        match self.seen.get(&self.unvault_tx.txid()) {
+            Some(Some(_)) => return VaultState::Unvaulted,
+            Some(None) => return VaultState::Unvaulting,
+            None => {}
+        }
+
+        if !matches!(self.seen.get(&self.deposit_outpoint.txid), Some(Some(_))) {
+            return VaultState::Unconfirmed;
+        }
+
+        let secured = is_signed(&self.emergency_tx, secp)
+            && is_signed(&self.cancel_tx, secp)
+            && is_signed(&self.unvault_emergency_tx, secp);
+        if !secured {
+            if has_signatures(&self.emergency_tx)
+                || has_signatures(&self.cancel_tx)
+                || has_signatures(&self.unvault_emergency_tx)
+            {
+                return VaultState::Securing;
+            }
+            return VaultState::Funded;
+        }
+
+        if is_signed(&self.unvault_tx, secp) {
+            VaultState::Active
+        } else if has_signatures(&self.unvault_tx) {
+            VaultState::Activating
+        } else {
+            VaultState::Secured
+        }
+    }
+
+    /// Get the fully-signed transactions that can be broadcast to move the vault to one of its
+    /// next states, along with the state they'd move it to.
+    ///
+    /// A TRUC Unvault transaction may only have a single unconfirmed child, which is left to its
+    /// CPFP transaction: its revocation transactions are only returned once it's confirmed. This
+    /// doesn't shorten the time to revoke, as the timelock of the Spend only starts then.
+    pub fn next_transactions<C: secp256k1::Verification>(
+        &self,
+        secp: &secp256k1::Secp256k1<C>,
+    ) -> Vec<(VaultState, Transaction)> {
+        let current_state = self.state(secp);
+        let unconfirmed_truc_unvault = current_state == VaultState::Unvaulting
+            && self.unvault_tx.tx().version == TRUC_TX_VERSION;
+
+        current_state
+            .next_states()
+            .iter()
+            .filter_map(|state| {
+                let tx = match state {
+                    VaultState::Canceling | VaultState::UnvaultEmergencyVaulting
+                        if unconfirmed_truc_unvault =>
+                    {
+                        None
+                    }
+                    VaultState::Unvaulting => finalized_tx(&self.unvault_tx, secp),
+                    VaultState::Canceling => finalized_tx(&self.cancel_tx, secp),
+                    VaultState::EmergencyVaulting => finalized_tx(&self.emergency_tx, secp),
+                    VaultState::UnvaultEmergencyVaulting => {
+                        finalized_tx(&self.unvault_emergency_tx, secp)
+                    }
+                    VaultState::Spending => self
+                        .spend_tx
+                        .as_ref()
+                        .and_then(|spend_tx| finalized_tx(spend_tx, secp)),
+                    _ => None,
+                }?;
+                Some((*state, tx))
+            })
+            .collect()
+    }
+
+    // The Unvault output, spent by the Cancel and UnvaultEmergency transactions
+    fn unvault_outpoint(&self) -> OutPoint {
+        OutPoint {
+            txid: self.unvault_tx.txid(),
+            vout: 0,
+        }
+    }
+
+    // Register a replacement of the transaction of the chain with this txid, spending this
+    // outpoint
+    fn add_replacement(
+        &mut self,
+        txid: Txid,
+        outpoint: OutPoint,
+        replacement: &impl RevaultTransaction,
+    ) -> Result<(), VaultError> {
+        let replacement_txid = replacement.txid();
+        if !replacement
+            .tx()
+            .input
+            .iter()
+            .any(|txin| txin.previous_output == outpoint)
+        {
+            return Err(VaultError::UnrelatedReplacement(replacement_txid));
+        }
+
+        if replacement_txid != txid {
+            self.replacements.insert(replacement_txid, txid);
+        }
+        Ok(())
+    }
+
+    // Whether the transaction of the chain with this txid, or any of its replacements, was seen
+    // and the height of the block it was confirmed in, if any
+    fn seen_with_replacements(&self, txid: &Txid) -> Option<Option<u32>> {
+        self.replacements
+            .iter()
+            .filter(|(_, replaced)| *replaced == txid)
+            .map(|(replacement, _)| replacement)
+            .chain(std::iter::once(txid))
+            .filter_map(|txid| self.seen.get(txid).copied())
+            .max()
+    }
+
+    // The txids of all the transactions of the chain, including the deposit
+    fn chain_txids(&self) -> Vec<Txid> {
+        let mut txids = vec![
+            self.deposit_outpoint.txid,
+            self.unvault_tx.txid(),
+            self.cancel_tx.txid(),
+            self.emergency_tx.txid(),
+            self.unvault_emergency_tx.txid(),
+        ];
+        if let Some(ref spend_tx) = self.spend_tx {
+            txids.push(spend_tx.txid());
+        }
+        txids
+    }
+}
+
+// Whether this transaction is fully signed
+fn is_signed<C: secp256k1::Verification>(
+    tx: &impl RevaultTransaction,
+    secp: &secp256k1::Secp256k1<C>,
+) -> bool {
+    tx.is_finalized() || tx.is_finalizable(secp)
+}
+
+// Whether any input of this transaction got a signature
+fn has_signatures(tx: &impl RevaultTransaction) -> bool {
+    tx.psbt()
+        .inputs
+        .iter()
+        .any(|input| !input.partial_sigs.is_empty())
+}
+
+// Get the finalized transaction, if it's fully signed
+fn finalized_tx<C: secp256k1::Verification>(
+    tx: &impl RevaultTransaction,
+    secp: &secp256k1::Secp256k1<C>,
+) -> Option<Transaction> {
+    if tx.is_finalized() {
+        return Some(tx.clone().into_tx());
+    }
+
+    let mut tx = tx.clone();
+    tx.finalize(secp).ok()?;
+    Some(tx.into_tx())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transactions::{
+        tests_helpers::{satisfy_transaction_input, TestDeployment},
+        CancelLadder, FeePolicy,
+    };
+
+    use miniscript::bitcoin::SigHashType;
+
+    #[test]
+    fn vault_lifecycle() {
+        let secp = secp256k1::Secp256k1::new();
+        let deployment = TestDeployment::new(3, 2, false, 360_000_000, &secp);
+        let fee_policy = FeePolicy::default();
+        let (unvault_tx, cancel_tx, emer_tx, unemer_tx) = deployment.chain(&fee_policy, &secp);
+        let (signed_unvault_tx, signed_cancel_tx, signed_emer_tx, signed_unemer_tx) =
+            deployment.signed_chain(&fee_policy, &secp);
+        let mut spend_tx = deployment.spend_tx(&signed_unvault_tx, &fee_policy, &secp);
+        deployment.sign_spend(&mut spend_tx, &secp);
+        let other_deployment = TestDeployment::new(3, 2, false, 360_000_000, &secp);
+        let (other_unvault_tx, _, _, _) = other_deployment.chain(&fee_policy, &secp);
+        let other_spend_tx = other_deployment.spend_tx(&other_unvault_tx, &fee_policy, &secp);
+        let deposit_outpoint = deployment.deposit_outpoint();
+
+        // The state of a vault follows the signatures of its chain and the transactions seen
+        let mut vault = Vault::new(
+            deposit_outpoint,
+            deployment.child_number,
+            unvault_tx,
+            cancel_tx,
+            emer_tx,
+            unemer_tx,
+        );
+        assert_eq!(vault.state(&secp), VaultState::Unconfirmed);
+        vault.apply_event(VaultEvent::Seen {
+            txid: deposit_outpoint.txid,
+            height: None,
+        });
+        assert_eq!(vault.state(&secp), VaultState::Unconfirmed);
+        vault.apply_event(VaultEvent::Seen {
+            txid: deposit_outpoint.txid,
+            height: Some(100),
+        });
+        assert_eq!(vault.state(&secp), VaultState::Funded);
+        assert!(vault.next_transactions(&secp).is_empty());
+        let vault_emer_sighash = vault
+            .emergency_tx()
+            .signature_hash(0, SigHashType::AllPlusAnyoneCanPay)
+            .expect("Input exists");
+        satisfy_transaction_input(
+            &secp,
+            vault.emergency_tx_mut(),
+            0,
+            &vault_emer_sighash,
+            &deployment.stakeholders_priv[..1].to_vec(),
+            Some(deployment.child_number),
+        )
+        .unwrap();
+        assert_eq!(vault.state(&secp), VaultState::Securing);
+        *vault.emergency_tx_mut() = signed_emer_tx.clone();
+        *vault.cancel_tx_mut() = signed_cancel_tx.clone();
+        *vault.unvault_emergency_tx_mut() = signed_unemer_tx;
+        assert_eq!(vault.state(&secp), VaultState::Secured);
+        assert_eq!(
+            vault.next_transactions(&secp),
+            vec![(
+                VaultState::EmergencyVaulting,
+                signed_emer_tx.clone().into_tx()
+            )]
+        );
+        let vault_unvault_sighash = vault
+            .unvault_tx()
+            .signature_hash(0, SigHashType::All)
+            .expect("Input exists");
+        satisfy_transaction_input(
+            &secp,
+            vault.unvault_tx_mut(),
+            0,
+            &vault_unvault_sighash,
+            &deployment.stakeholders_priv[..1].to_vec(),
+            Some(deployment.child_number),
+        )
+        .unwrap();
+        assert_eq!(vault.state(&secp), VaultState::Activating);
+        *vault.unvault_tx_mut() = signed_unvault_tx.clone();
+        assert_eq!(vault.state(&secp), VaultState::Active);
+        assert_eq!(
+            vault.next_transactions(&secp),
+            vec![
+                (VaultState::Unvaulting, signed_unvault_tx.clone().into_tx()),
+                (VaultState::EmergencyVaulting, signed_emer_tx.into_tx())
+            ]
+        );
+
+        // Only a Spend of the vault's Unvault output may be set
+        assert_eq!(
+            vault.set_spend_tx(other_spend_tx.clone()),
+            Err(VaultError::UnrelatedSpend(other_spend_tx.txid()))
+        );
+        vault.set_spend_tx(spend_tx.clone()).unwrap();
+        vault.apply_event(VaultEvent::Seen {
+            txid: signed_unvault_tx.txid(),
+            height: None,
+        });
+        assert_eq!(vault.state(&secp), VaultState::Unvaulting);
+        assert_eq!(
+            vault
+                .next_transactions(&secp)
+                .into_iter()
+                .map(|(state, _)| state)
+                .collect::<Vec<VaultState>>(),
+            vec![VaultState::Canceling, VaultState::UnvaultEmergencyVaulting]
+        );
+        vault.apply_event(VaultEvent::Seen {
+            txid: signed_unvault_tx.txid(),
+            height: Some(101),
+        });
+        assert_eq!(vault.state(&secp), VaultState::Unvaulted);
+        assert_eq!(
+            vault.next_transactions(&secp)[0],
+            (VaultState::Spending, spend_tx.clone().into_tx())
+        );
+
+        // Transactions unrelated to the vault are ignored
+        vault.apply_event(VaultEvent::Seen {
+            txid: other_spend_tx.txid(),
+            height: Some(101),
+        });
+        assert_eq!(vault.state(&secp), VaultState::Unvaulted);
+        vault.apply_event(VaultEvent::Seen {
+            txid: spend_tx.txid(),
+            height: Some(102),
+        });
+        assert_eq!(vault.state(&secp), VaultState::Spent);
+        assert!(vault.state(&secp).is_final());
+        assert!(vault.next_transactions(&secp).is_empty());
+
+        // After a reorg, a confirmed conflicting Cancel wins over the unconfirmed Spend
+        vault.apply_event(VaultEvent::Reorg { height: 101 });
+        assert_eq!(vault.state(&secp), VaultState::Spending);
+        vault.apply_event(VaultEvent::Seen {
+            txid: signed_cancel_tx.txid(),
+            height: Some(102),
+        });
+        assert_eq!(vault.state(&secp), VaultState::Canceled);
+        vault.apply_event(VaultEvent::Reorg { height: 99 });
+        assert_eq!(vault.state(&secp), VaultState::Canceling);
+    }
+
+    #[test]
+    fn revocation_replacements() {
+        let secp = secp256k1::Secp256k1::new();
+        let deployment = TestDeployment::new(3, 2, false, 360_000_000, &secp);
+        let fee_policy = FeePolicy::default();
+        let (unvault_tx, cancel_tx, emer_tx, unemer_tx) =
+            deployment.signed_chain(&fee_policy, &secp);
+        let other_deployment = TestDeployment::new(3, 2, false, 360_000_000, &secp);
+        let (_, other_cancel_tx, other_emer_tx, _) = other_deployment.chain(&fee_policy, &secp);
+        let deposit_outpoint = deployment.deposit_outpoint();
+        let mut vault = Vault::new(
+            deposit_outpoint,
+            deployment.child_number,
+            unvault_tx.clone(),
+            cancel_tx,
+            emer_tx,
+            unemer_tx,
+        );
+        vault.apply_event(VaultEvent::Seen {
+            txid: deposit_outpoint.txid,
+            height: Some(100),
+        });
+        vault.apply_event(VaultEvent::Seen {
+            txid: unvault_tx.txid(),
+            height: Some(101),
+        });
+        assert_eq!(vault.state(&secp), VaultState::Unvaulted);
+
+        // Only the replacements spending the same output may be registered
+        assert_eq!(
+            vault.add_cancel_replacement(&other_cancel_tx),
+            Err(VaultError::UnrelatedReplacement(other_cancel_tx.txid()))
+        );
+        assert_eq!(
+            vault.add_emergency_replacement(&other_emer_tx),
+            Err(VaultError::UnrelatedReplacement(other_emer_tx.txid()))
+        );
+        let cancel_ladder = CancelLadder::new(
+            unvault_tx.revault_unvault_txin(&deployment.der_unvault_descriptor(&secp)),
+            &deployment.der_deposit_descriptor(&secp),
+            0,
+            &[fee_policy.cancel_feerate * 2, fee_policy.cancel_feerate * 4],
+            &fee_policy,
+        )
+        .unwrap();
+        for (_, cancel_tx) in cancel_ladder.entries() {
+            vault.add_cancel_replacement(cancel_tx).unwrap();
+        }
+
+        // Whichever entry of the ladder gets mined, the vault is canceled
+        let bumped_cancel_txid = cancel_ladder.highest().txid();
+        vault.apply_event(VaultEvent::Seen {
+            txid: bumped_cancel_txid,
+            height: None,
+        });
+        assert_eq!(vault.state(&secp), VaultState::Canceling);
+        vault.apply_event(VaultEvent::Seen {
+            txid: bumped_cancel_txid,
+            height: Some(102),
+        });
+        assert_eq!(vault.state(&secp), VaultState::Canceled);
+        vault.apply_event(VaultEvent::Reorg { height: 101 });
+        assert_eq!(vault.state(&secp), VaultState::Canceling);
+    }
+
+    #[test]
+    fn truc_vault_revocations() {
+        let secp = secp256k1::Secp256k1::new();
+        let deployment = TestDeployment::new(3, 2, false, 360_000_000, &secp);
+        let fee_policy = FeePolicy {
+            truc: true,
+            ..FeePolicy::default()
+        };
+        let (unvault_tx, cancel_tx, emer_tx, unemer_tx) =
+            deployment.signed_chain(&fee_policy, &secp);
+        let deposit_outpoint = deployment.deposit_outpoint();
+        let mut vault = Vault::new(
+            deposit_outpoint,
+            deployment.child_number,
+            unvault_tx.clone(),
+            cancel_tx.clone(),
+            emer_tx,
+            unemer_tx.clone(),
+        );
+        vault.apply_event(VaultEvent::Seen {
+            txid: deposit_outpoint.txid,
+            height: Some(100),
+        });
+        assert_eq!(vault.state(&secp), VaultState::Active);
+
+        // While the TRUC Unvault is unconfirmed, its only child may be its CPFP transaction
+        vault.apply_event(VaultEvent::Seen {
+            txid: unvault_tx.txid(),
+            height: None,
+        });
+        assert_eq!(vault.state(&secp), VaultState::Unvaulting);
+        assert!(vault.next_transactions(&secp).is_empty());
+
+        // Once it's confirmed it can be revoked
+        vault.apply_event(VaultEvent::Seen {
+            txid: unvault_tx.txid(),
+            height: Some(101),
+        });
+        assert_eq!(
+            vault.next_transactions(&secp),
+            vec![
+                (VaultState::Canceling, cancel_tx.into_tx()),
+                (VaultState::UnvaultEmergencyVaulting, unemer_tx.into_tx())
+            ]
+        );
+    }
+}